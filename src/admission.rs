@@ -0,0 +1,265 @@
+//! Admission control, which bounds the inferences in flight per model so that bursts wait in a
+//! bounded queue of the server, rather than growing the queue of triton until clients time out.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::AppError;
+use crate::model_routes::ModelRoutes;
+use crate::request_metrics::{record_queue_depth, record_queue_wait};
+use crate::triton::pool::TritonPool;
+
+/// The default limits of the models, which routes can override.
+#[derive(Clone, Copy, Debug)]
+pub struct AdmissionOptions {
+    /// Inferences in flight per model, unlimited if not set.
+    pub max_concurrency: Option<usize>,
+    /// Requests waiting for a model once its inferences in flight reach the limit.
+    pub max_queue: usize,
+    /// Time a request waits in the queue before it is rejected.
+    pub max_queue_wait: Duration,
+}
+
+/// The queue of a model, which is FIFO since the semaphore is fair.
+struct ModelQueue {
+    model: String,
+    semaphore: Arc<Semaphore>,
+    max_concurrency: usize,
+    max_queue: usize,
+    waiting: AtomicUsize,
+}
+
+/// A request waiting in the queue of a model, which leaves it when dropped, including when the
+/// client disconnects.
+struct Waiting<'a>(&'a ModelQueue);
+
+impl<'a> Waiting<'a> {
+    /// Enter the queue, unless it is full.
+    fn enter(queue: &'a ModelQueue) -> Option<Self> {
+        let waiting = queue
+            .waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |waiting| {
+                (waiting < queue.max_queue).then_some(waiting + 1)
+            })
+            .ok()?;
+        record_queue_depth(&queue.model, waiting + 1);
+        Some(Self(queue))
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let waiting = self.0.waiting.fetch_sub(1, Ordering::SeqCst) - 1;
+        record_queue_depth(&self.0.model, waiting);
+    }
+}
+
+/// A slot for an inference of a model, released when dropped.
+pub(crate) struct AdmissionPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// The queues of the models whose inferences in flight are limited.
+#[derive(Clone)]
+pub(crate) struct Admission {
+    options: AdmissionOptions,
+    model_routes: ModelRoutes,
+    /// The default pool, which tells the models without route which exist.
+    triton: TritonPool,
+    queues: Arc<Mutex<HashMap<String, Arc<ModelQueue>>>>,
+}
+
+impl Admission {
+    pub(crate) fn new(
+        options: AdmissionOptions,
+        model_routes: ModelRoutes,
+        triton: TritonPool,
+    ) -> Self {
+        Self {
+            options,
+            model_routes,
+            triton,
+            queues: Default::default(),
+        }
+    }
+
+    /// Wait for the slots of the `inferences` of a request for the model requested by the client,
+    /// at most all the slots of the model. The request is rejected with 429 when the queue of the
+    /// model is full, and with 503 once it waited for `max_queue_wait`. Models without limit are
+    /// admitted right away, without permit, as are the models which are not known yet so that
+    /// arbitrary model names do not create queues.
+    pub(crate) async fn admit(
+        &self,
+        model: &str,
+        inferences: usize,
+    ) -> Result<Option<AdmissionPermit>, AppError> {
+        let Some(queue) = self.queue(model) else {
+            return Ok(None);
+        };
+        let permits = inferences.max(1).min(queue.max_concurrency) as u32;
+        if let Ok(permit) = queue.semaphore.clone().try_acquire_many_owned(permits) {
+            return Ok(Some(AdmissionPermit { _permit: permit }));
+        }
+
+        let Some(_waiting) = Waiting::enter(&queue) else {
+            return Err(AppError::RateLimited(format!(
+                "Too many requests are waiting for the model {}. Please try again later.",
+                queue.model
+            )));
+        };
+        let start = Instant::now();
+        let permit = tokio::time::timeout(
+            self.options.max_queue_wait,
+            queue.semaphore.clone().acquire_many_owned(permits),
+        )
+        .await;
+        record_queue_wait(&queue.model, start.elapsed());
+        match permit {
+            Ok(permit) => Ok(Some(AdmissionPermit {
+                _permit: permit.expect("semaphore is never closed"),
+            })),
+            Err(_) => Err(AppError::Unavailable(format!(
+                "The model {} is overloaded, the request waited {}s in the queue. Please try \
+                 again later.",
+                queue.model,
+                self.options.max_queue_wait.as_secs()
+            ))),
+        }
+    }
+
+    /// The queue of the route of the model, if it is known and its inferences in flight are
+    /// limited.
+    fn queue(&self, model: &str) -> Option<Arc<ModelQueue>> {
+        let route = self.model_routes.resolve(model);
+        let max_concurrency = route.max_concurrency.or(self.options.max_concurrency)?;
+        if !self.model_routes.is_known(model, &self.triton) {
+            return None;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(route.id.clone()).or_insert_with(|| {
+            Arc::new(ModelQueue {
+                model: route.id.clone(),
+                semaphore: Arc::new(Semaphore::new(max_concurrency)),
+                max_concurrency,
+                max_queue: route.max_queue.unwrap_or(self.options.max_queue),
+                waiting: AtomicUsize::new(0),
+            })
+        });
+        Some(queue.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::LoadBalancing;
+    use crate::triton::pool::PoolOptions;
+
+    fn triton() -> TritonPool {
+        let options = PoolOptions {
+            load_balancing: LoadBalancing::RoundRobin,
+            max_failures: 1,
+            health_check_interval: Duration::from_secs(1),
+            connect_timeout: Duration::from_secs(1),
+            request_timeout: None,
+            keepalive_interval: Duration::from_secs(1),
+            keepalive_timeout: Duration::from_secs(1),
+        };
+        TritonPool::new(&["http://localhost:8001".to_string()], options).unwrap()
+    }
+
+    fn admission(max_concurrency: Option<usize>, max_queue: usize) -> Admission {
+        let options = AdmissionOptions {
+            max_concurrency,
+            max_queue,
+            max_queue_wait: Duration::from_millis(200),
+        };
+        let aliases = HashMap::from([
+            ("llama".to_string(), "llama".to_string()),
+            ("mistral".to_string(), "mistral".to_string()),
+        ]);
+        let model_routes = ModelRoutes::new(&[], aliases, |_| unreachable!()).unwrap();
+        Admission::new(options, model_routes, triton())
+    }
+
+    #[tokio::test]
+    pub async fn test_admit() {
+        let admission = admission(Some(1), 1);
+        let permit = admission.admit("llama", 1).await.unwrap();
+        assert!(permit.is_some());
+        // Models have their own slots.
+        assert!(admission.admit("mistral", 1).await.is_ok());
+
+        // The queued request gets the slot once released, while the queue is full meanwhile.
+        let queued = tokio::spawn({
+            let admission = admission.clone();
+            async move {
+                admission
+                    .admit("llama", 1)
+                    .await
+                    .map(|permit| permit.is_some())
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(
+            admission.admit("llama", 1).await,
+            Err(AppError::RateLimited(_))
+        ));
+        drop(permit);
+        assert!(queued.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    pub async fn test_inferences() {
+        let admission = admission(Some(4), 1);
+        let _permit = admission.admit("llama", 3).await.unwrap();
+        assert_eq!(
+            1,
+            admission
+                .queue("llama")
+                .unwrap()
+                .semaphore
+                .available_permits()
+        );
+        assert!(matches!(
+            admission.admit("llama", 2).await,
+            Err(AppError::Unavailable(_))
+        ));
+
+        // Requests with more inferences than slots take all of them.
+        let _permit = admission.admit("mistral", 8).await.unwrap();
+        let queue = admission.queue("mistral").unwrap();
+        assert_eq!(0, queue.semaphore.available_permits());
+    }
+
+    #[tokio::test]
+    pub async fn test_max_queue_wait() {
+        let admission = admission(Some(1), 1);
+        let _permit = admission.admit("llama", 1).await.unwrap();
+        assert!(matches!(
+            admission.admit("llama", 1).await,
+            Err(AppError::Unavailable(_))
+        ));
+        // The request left the queue.
+        let queue = admission.queue("llama").unwrap();
+        assert_eq!(0, queue.waiting.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    pub async fn test_unlimited() {
+        let admission = admission(None, 0);
+        assert!(admission.queue("llama").is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_unknown_model() {
+        let admission = admission(Some(1), 0);
+        assert!(admission.admit("gpt-4", 1).await.unwrap().is_none());
+        assert!(admission.queues.lock().unwrap().is_empty());
+    }
+}
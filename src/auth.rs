@@ -0,0 +1,316 @@
+//! Authentication of the clients with API keys, each of which may be restricted to some models.
+use std::fs::File;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::error::AppError;
+use crate::model_routes::ModelRoutes;
+use crate::rate_limit::RateLimits;
+
+/// An API key, as configured in the key file.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Name identifying the owner of the key in the logs and traces.
+    pub name: String,
+    /// The secret sent by the client as bearer token.
+    pub key: String,
+    /// Names or globs of the models the key may use, every model by default.
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// RFC 3339 time after which the key is rejected.
+    #[serde(default, with = "humantime_serde")]
+    pub expires_at: Option<SystemTime>,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Limit of the requests per minute, `--requests-per-minute` by default.
+    pub requests_per_minute: Option<u32>,
+    /// Limit of the generated tokens per minute, `--tokens-per-minute` by default.
+    pub tokens_per_minute: Option<u32>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl ApiKeyConfig {
+    /// Load the keys from a JSON file holding an array of keys.
+    pub fn load(path: &str) -> anyhow::Result<Vec<Self>> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open API key file {}", path))?;
+        serde_json::from_reader(file)
+            .with_context(|| format!("failed to parse API key file {}", path))
+    }
+}
+
+/// The API key of a request, which is anonymous when the server has no key.
+pub(crate) struct ApiKey {
+    pub(crate) name: String,
+    pub(crate) rate_limits: RateLimits,
+    /// SHA-256 digest of the secret, so that keys of any length are compared in the same time.
+    digest: [u8; 32],
+    /// Empty when the key may use every model.
+    models: Vec<Pattern>,
+    expires_at: Option<SystemTime>,
+    enabled: bool,
+}
+
+impl ApiKey {
+    fn new(config: &ApiKeyConfig, default_limits: RateLimits) -> anyhow::Result<Self> {
+        let models = config
+            .models
+            .iter()
+            .map(|model| {
+                Pattern::new(model.trim())
+                    .with_context(|| format!("invalid model {} of API key {}", model, config.name))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let rate_limits = RateLimits::new(config.requests_per_minute, config.tokens_per_minute)
+            .with_context(|| format!("invalid rate limits of API key {}", config.name))?;
+        Ok(Self {
+            name: config.name.clone(),
+            rate_limits: rate_limits.or(default_limits),
+            digest: Sha256::digest(&config.key).into(),
+            models,
+            expires_at: config.expires_at,
+            enabled: config.enabled,
+        })
+    }
+
+    fn anonymous(rate_limits: RateLimits) -> Self {
+        Self {
+            name: "anonymous".to_string(),
+            rate_limits,
+            digest: Sha256::digest("").into(),
+            models: vec![],
+            expires_at: None,
+            enabled: true,
+        }
+    }
+
+    /// Check that the key may use the model requested by the client, by its name or by the id of
+    /// its route.
+    pub(crate) fn authorize(
+        &self,
+        model: &str,
+        model_routes: &ModelRoutes,
+    ) -> Result<(), AppError> {
+        if self.allows(model, model_routes) {
+            Ok(())
+        } else {
+            Err(AppError::PermissionDenied(format!(
+                "The API key {} is not allowed to use the model {}.",
+                self.name, model
+            )))
+        }
+    }
+
+    pub(crate) fn allows(&self, model: &str, model_routes: &ModelRoutes) -> bool {
+        let route = model_routes.resolve(model);
+        self.models.is_empty()
+            || self
+                .models
+                .iter()
+                .any(|pattern| pattern.matches(model) || pattern.matches(&route.id))
+    }
+}
+
+/// The API keys accepted by the server, which can be reloaded from the key file.
+#[derive(Clone)]
+pub struct ApiKeys {
+    /// The `--api-key`, which may use every model.
+    api_key: Option<String>,
+    file: Option<String>,
+    keys: Arc<RwLock<Vec<Arc<ApiKey>>>>,
+    /// The key of the requests when the server has no key, whose rate limits are shared by all
+    /// the clients.
+    anonymous: Arc<ApiKey>,
+    default_limits: RateLimits,
+}
+
+impl ApiKeys {
+    /// Load the `--api-key` key, named `default`, and the keys of the key file. The default limits
+    /// apply to the keys which do not set theirs.
+    pub fn new(
+        api_key: Option<String>,
+        file: Option<String>,
+        default_limits: RateLimits,
+    ) -> anyhow::Result<Self> {
+        let keys = Self {
+            api_key,
+            file,
+            keys: Default::default(),
+            anonymous: Arc::new(ApiKey::anonymous(default_limits)),
+            default_limits,
+        };
+        keys.reload()?;
+        Ok(keys)
+    }
+
+    /// Load the keys again from the key file, keeping the previous keys if the file is invalid.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let mut configs = match &self.file {
+            Some(path) => ApiKeyConfig::load(path)?,
+            None => vec![],
+        };
+        if let Some(key) = &self.api_key {
+            configs.push(ApiKeyConfig {
+                name: "default".to_string(),
+                key: key.clone(),
+                models: vec![],
+                expires_at: None,
+                enabled: true,
+                requests_per_minute: None,
+                tokens_per_minute: None,
+            });
+        }
+        let keys = configs
+            .iter()
+            .map(|config| ApiKey::new(config, self.default_limits).map(Arc::new))
+            .collect::<anyhow::Result<_>>()?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Reload the key file on SIGHUP.
+    pub fn reload_on_hangup(&self) -> anyhow::Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = signal(SignalKind::hangup())?;
+            let keys = self.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    match keys.reload() {
+                        Ok(()) => tracing::info!("reloaded API keys"),
+                        Err(err) => tracing::error!("failed to reload API keys: {:?}", err),
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// The key of the requests when the server has no key.
+    pub(crate) fn anonymous(&self) -> Arc<ApiKey> {
+        self.anonymous.clone()
+    }
+
+    /// Whether the clients must send an API key.
+    pub(crate) fn required(&self) -> bool {
+        self.api_key.is_some() || self.file.is_some()
+    }
+
+    /// Find the key sent by the client. The digests of every key are compared in constant time, so
+    /// that the time taken does not tell which key, how much of it, nor its length matched.
+    pub(crate) fn authenticate(&self, secret: &str) -> Result<Arc<ApiKey>, AppError> {
+        let digest: [u8; 32] = Sha256::digest(secret).into();
+        let mut found = None;
+        for key in self.keys.read().unwrap().iter() {
+            if bool::from(key.digest.ct_eq(&digest)) {
+                found = Some(key.clone());
+            }
+        }
+        let Some(key) = found else {
+            return Err(AppError::Unauthorized(
+                "Incorrect API key provided.".to_string(),
+            ));
+        };
+
+        if !key.enabled {
+            return Err(AppError::Unauthorized(format!(
+                "The API key {} is disabled.",
+                key.name
+            )));
+        }
+        if key
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+        {
+            return Err(AppError::Unauthorized(format!(
+                "The API key {} has expired.",
+                key.name
+            )));
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn write_keys(name: &str, keys: serde_json::Value) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}.json", name, std::process::id()));
+        std::fs::write(&path, keys.to_string()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    pub fn test_authenticate() {
+        let path = write_keys(
+            "test_authenticate",
+            json!([
+                {"name": "team-a", "key": "sk-a", "models": ["llama*"]},
+                {"name": "team-b", "key": "sk-b", "enabled": false},
+                {"name": "team-c", "key": "sk-c", "expires_at": "2020-01-01T00:00:00Z"},
+            ]),
+        );
+        let keys = ApiKeys::new(
+            Some("sk-default".to_string()),
+            Some(path.clone()),
+            RateLimits::default(),
+        )
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(keys.required());
+
+        let routes = ModelRoutes::default();
+        let key = keys.authenticate("sk-a").unwrap();
+        assert_eq!("team-a", key.name);
+        assert!(key.authorize("llama-3-8b", &routes).is_ok());
+        assert!(matches!(
+            key.authorize("mistral", &routes),
+            Err(AppError::PermissionDenied(_))
+        ));
+        let key = keys.authenticate("sk-default").unwrap();
+        assert!(key.authorize("mistral", &routes).is_ok());
+
+        for secret in ["sk-b", "sk-c", "sk-", "sk-aa", ""] {
+            assert!(matches!(
+                keys.authenticate(secret),
+                Err(AppError::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_reload() {
+        let path = write_keys("test_reload", json!([{"name": "team-a", "key": "sk-a"}]));
+        let keys = ApiKeys::new(None, Some(path.clone()), RateLimits::default()).unwrap();
+        assert!(keys.authenticate("sk-a").is_ok());
+
+        write_keys("test_reload", json!([{"name": "team-a", "key": "sk-a2"}]));
+        keys.reload().unwrap();
+        assert!(keys.authenticate("sk-a").is_err());
+        assert!(keys.authenticate("sk-a2").is_ok());
+
+        // An invalid file keeps the previous keys.
+        std::fs::write(&path, "[{\"name\": \"team-a\"}]").unwrap();
+        assert!(keys.reload().is_err());
+        assert!(keys.authenticate("sk-a2").is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
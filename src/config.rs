@@ -1,8 +1,49 @@
-use clap::Parser;
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use figment::providers::{Env, Format, Serialized, Toml, Yaml};
+use figment::Figment;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::model_routes::ModelRouteConfig;
+use crate::utils::string_or_seq_string;
+
+/// Prefix of the environment variables setting the configuration.
+const ENV_PREFIX: &str = "OPENAI_TRTLLM_";
 
 #[derive(Parser, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// TOML or YAML configuration file, whose settings are overridden by the environment variables
+    /// and the command line
+    #[arg(long, short = 'c')]
+    #[serde(skip)]
+    pub config: Option<String>,
+
+    #[command(flatten)]
+    pub server: ServerConfig,
+
+    #[command(flatten)]
+    pub triton: TritonConfig,
+
+    #[command(flatten)]
+    pub models: ModelsConfig,
+
+    #[command(flatten)]
+    pub auth: AuthConfig,
+
+    #[command(flatten)]
+    pub telemetry: TelemetryConfig,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
     /// Host to bind to
     #[arg(long, short = 'H', default_value_t = String::from("0.0.0.0"))]
     pub host: String,
@@ -11,15 +52,62 @@ pub struct Config {
     #[arg(long, short, default_value_t = 3000)]
     pub port: usize,
 
-    /// Triton gRPC endpoint
-    #[arg(long, short, default_value_t = String::from("http://localhost:8001"))]
-    pub triton_endpoint: String,
+    /// Largest request body in bytes, which requests with images may need to raise
+    #[arg(long, default_value_t = 2 * 1024 * 1024)]
+    pub max_body_size: usize,
+}
 
-    /// Endpoint of OpenTelemetry collector
-    #[arg(long, short)]
+#[derive(Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TritonConfig {
+    /// Triton gRPC endpoints, repeated or separated by commas for replicas serving the same models
+    #[arg(
+        long = "triton-endpoint",
+        short = 't',
+        value_name = "TRITON_ENDPOINT",
+        default_value = "http://localhost:8001",
+        value_delimiter = ','
+    )]
+    #[serde(deserialize_with = "comma_separated")]
+    pub endpoints: Vec<String>,
+
+    /// How inferences are balanced between the triton endpoints
+    #[arg(long, value_enum, default_value_t = LoadBalancing::RoundRobin)]
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+
+    /// Interval in seconds between the health checks of the triton endpoints
+    #[arg(long, default_value_t = 5)]
+    pub health_check_interval: u64,
+
+    /// Number of consecutive failures after which a triton endpoint is ejected, until it passes a
+    /// health check again
+    #[arg(long, default_value_t = 3)]
+    pub max_failures: usize,
+
+    /// Timeout in seconds of the connection to a triton endpoint
+    #[arg(long, default_value_t = 5)]
+    pub connect_timeout: u64,
+
+    /// Timeout in seconds of the requests to triton, until their response starts, which does not
+    /// limit the duration of streamed inferences
+    #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub otlp_endpoint: Option<String>,
+    pub request_timeout: Option<u64>,
 
+    /// Interval in seconds between the HTTP/2 keepalive pings sent to the triton endpoints
+    #[arg(long, default_value_t = 30)]
+    pub keepalive_interval: u64,
+
+    /// Time in seconds after which a triton endpoint not answering a keepalive ping is
+    /// disconnected
+    #[arg(long, default_value_t = 20)]
+    pub keepalive_timeout: u64,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelsConfig {
     /// Template for converting OpenAI message history to prompt
     #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -30,8 +118,243 @@ pub struct Config {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub history_template_file: Option<String>,
 
+    /// History template file used for the models matching a pattern, in the form of
+    /// pattern=file where the pattern is a model name or a glob like llama3*
+    #[arg(long = "model-history-template", value_name = "PATTERN=FILE")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history_templates: Vec<String>,
+
+    /// Number of times a chat completion is generated again when its output does not follow the
+    /// JSON response_format requested by the client
+    #[arg(long, default_value_t = 0)]
+    #[serde(default)]
+    pub response_format_retries: usize,
+
+    /// HuggingFace tokenizer.json used to count tokens, either for all models or for a specific
+    /// one in the form of triton_model=path
+    #[arg(long = "tokenizer", value_name = "[MODEL=]PATH")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokenizers: Vec<String>,
+
+    /// Additional model name exposed to clients, in the form of alias=model where the model is a
+    /// routed model id or a triton model
+    #[arg(long = "model-alias", value_name = "ALIAS=MODEL")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+
+    /// JSON file routing the model ids exposed to clients to triton models, with their endpoints,
+    /// history templates and default parameters
+    #[arg(long = "model-routes", value_name = "MODEL_ROUTES")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routes_file: Option<String>,
+
+    /// Number of inferences in flight per model, above which requests wait in the queue of the
+    /// model, unlimited by default
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<usize>,
+
+    /// Number of requests waiting per model with --max-concurrency, above which requests are
+    /// rejected with 429
+    #[arg(long, default_value_t = 64)]
+    pub max_queue: usize,
+
+    /// Time in seconds a request waits in the queue of a model before it is rejected with 503
+    #[arg(long, default_value_t = 30)]
+    pub max_queue_wait: u64,
+
+    /// Model routes set in the configuration file, besides the ones of the routes file.
+    #[arg(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<ModelRouteConfig>,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
     /// Api Key to access the server
     #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub api_key: Option<String>,
+
+    /// JSON file of the API keys accepted by the server, with their names, allowed models, expiry
+    /// and enabled flag, which is reloaded on SIGHUP
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys_file: Option<String>,
+
+    /// Limit of the requests per minute of each API key which does not set its own, or of all the
+    /// clients when the server has no key
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+
+    /// Limit of the generated tokens per minute of each API key which does not set its own, or of
+    /// all the clients when the server has no key
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_per_minute: Option<u32>,
+}
+
+#[derive(Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// Endpoint of OpenTelemetry collector
+    #[arg(long, short)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Export metrics to the OpenTelemetry collector, besides serving them on /metrics
+    #[arg(long)]
+    #[serde(default)]
+    pub otlp_metrics: bool,
+}
+
+/// How a triton endpoint is chosen for each inference.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoadBalancing {
+    /// Each endpoint in turn
+    #[default]
+    RoundRobin,
+    /// The endpoint with the fewest inferences in flight
+    LeastInFlight,
+    /// The endpoint with the fewest inferences in flight among two picked at random
+    PowerOfTwoChoices,
+}
+
+/// The settings, by environment variable name without prefix. The variables are named after the
+/// command line options, e.g. `OPENAI_TRTLLM_TRITON_ENDPOINT`, or after the fields of the former
+/// flat configuration, e.g. `OPENAI_TRTLLM_TRITON_ENDPOINTS`.
+const ENV_KEYS: &[(&str, &str)] = &[
+    ("host", "server.host"),
+    ("port", "server.port"),
+    ("max_body_size", "server.max_body_size"),
+    ("triton_endpoint", "triton.endpoints"),
+    ("triton_endpoints", "triton.endpoints"),
+    ("load_balancing", "triton.load_balancing"),
+    ("health_check_interval", "triton.health_check_interval"),
+    ("max_failures", "triton.max_failures"),
+    ("connect_timeout", "triton.connect_timeout"),
+    ("request_timeout", "triton.request_timeout"),
+    ("keepalive_interval", "triton.keepalive_interval"),
+    ("keepalive_timeout", "triton.keepalive_timeout"),
+    ("history_template", "models.history_template"),
+    ("history_template_file", "models.history_template_file"),
+    ("model_history_template", "models.history_templates"),
+    ("model_history_templates", "models.history_templates"),
+    ("response_format_retries", "models.response_format_retries"),
+    ("tokenizer", "models.tokenizers"),
+    ("tokenizers", "models.tokenizers"),
+    ("model_alias", "models.aliases"),
+    ("model_aliases", "models.aliases"),
+    ("model_routes", "models.routes_file"),
+    ("max_concurrency", "models.max_concurrency"),
+    ("max_queue", "models.max_queue"),
+    ("max_queue_wait", "models.max_queue_wait"),
+    ("api_key", "auth.api_key"),
+    ("api_keys_file", "auth.api_keys_file"),
+    ("requests_per_minute", "auth.requests_per_minute"),
+    ("tokens_per_minute", "auth.tokens_per_minute"),
+    ("otlp_endpoint", "telemetry.otlp_endpoint"),
+    ("otlp_metrics", "telemetry.otlp_metrics"),
+];
+
+impl Config {
+    /// Load the configuration from the command line arguments of the process.
+    pub fn load() -> anyhow::Result<Self> {
+        Self::load_from(std::env::args_os())
+    }
+
+    /// Load the configuration from the given command line arguments. Settings are taken from, in
+    /// order of precedence, the command line, the environment variables, the configuration file
+    /// and the defaults of the command line options.
+    pub fn load_from<I, T>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Self::command().get_matches_from(args);
+        let cli = Self::from_arg_matches(&matches)?;
+
+        let mut figment = Figment::from(Serialized::defaults(&cli));
+        if let Some(path) = &cli.config {
+            figment = figment.merge(config_file(path)?);
+        }
+        let env = Env::prefixed(ENV_PREFIX).filter_map(|key| {
+            ENV_KEYS
+                .iter()
+                .find(|(name, _)| key == *name)
+                .map(|(_, path)| (*path).into())
+        });
+        let config: Self = figment
+            .merge(env)
+            .merge(Serialized::defaults(command_line_settings(&cli, &matches)?))
+            .extract()
+            .map_err(|err| anyhow!("invalid configuration: {}", err))?;
+        Ok(Self {
+            config: cli.config,
+            ..config
+        })
+    }
+
+    /// Parse the model aliases into a map from alias to triton model name.
+    pub fn model_aliases(&self) -> anyhow::Result<HashMap<String, String>> {
+        self.models
+            .aliases
+            .iter()
+            .map(|alias| {
+                let (alias, model) = alias.split_once('=').with_context(|| {
+                    format!("invalid model alias {}, expected alias=model", alias)
+                })?;
+                Ok((alias.trim().to_string(), model.trim().to_string()))
+            })
+            .collect()
+    }
+}
+
+/// A list, or a string of values separated by commas as in the environment variables.
+fn comma_separated<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(string_or_seq_string(deserializer)?
+        .iter()
+        .flat_map(|values| values.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// The provider of the TOML or YAML configuration file, depending on its extension.
+fn config_file(path: &str) -> anyhow::Result<Figment> {
+    if !Path::new(path).is_file() {
+        bail!("configuration file {} does not exist", path);
+    }
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+    Ok(match extension {
+        Some("toml") => Figment::from(Toml::file(path)),
+        Some("yaml" | "yml") => Figment::from(Yaml::file(path)),
+        _ => bail!(
+            "unsupported configuration file {}, expected a .toml, .yaml or .yml file",
+            path
+        ),
+    })
+}
+
+/// The settings given on the command line, leaving out the defaults of the options.
+fn command_line_settings(cli: &Config, matches: &ArgMatches) -> anyhow::Result<Value> {
+    let Value::Object(sections) = serde_json::to_value(cli)? else {
+        unreachable!("the configuration is a struct");
+    };
+    let mut settings = Map::new();
+    for (section, values) in sections {
+        let Value::Object(mut values) = values else {
+            continue;
+        };
+        values.retain(|key, _| matches.value_source(key) == Some(ValueSource::CommandLine));
+        settings.insert(section, Value::Object(values));
+    }
+    Ok(Value::Object(settings))
 }
@@ -1,18 +1,177 @@
+use std::convert::Infallible;
+
+use axum::extract::rejection::JsonRejection;
+use axum::response::sse::Event;
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::Serialize;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
+use tonic::Code;
+
+use crate::request_metrics::RequestMetrics;
 
+/// Errors reported to clients in the OpenAI error format.
 #[derive(Debug)]
-pub struct AppError(anyhow::Error);
+pub enum AppError {
+    /// The request is malformed or has an invalid parameter.
+    InvalidRequest {
+        message: String,
+        param: Option<String>,
+    },
+    /// The API key is missing or invalid.
+    Unauthorized(String),
+    /// The API key is not allowed to perform the request.
+    PermissionDenied(String),
+    /// The requested model does not exist.
+    ModelNotFound(String),
+    /// Too many requests have been sent.
+    RateLimited(String),
+    /// Triton is unreachable or overloaded.
+    Unavailable(String),
+    /// Triton failed to process the request.
+    Triton(String),
+    /// The model output does not follow the requested `response_format` or `tool_choice`.
+    InvalidOutput { param: String, reason: String },
+    /// Any other failure, the details are not exposed to the client.
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    pub(crate) fn invalid_request<S: Into<String>>(message: S, param: Option<&str>) -> Self {
+        AppError::InvalidRequest {
+            message: message.into(),
+            param: param.map(Into::into),
+        }
+    }
+
+    pub(crate) fn invalid_output<S: Into<String>>(param: &str, reason: S) -> Self {
+        AppError::InvalidOutput {
+            param: param.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Classify the error message of a triton stream response, which has no status code. Only
+    /// the unknown models are recognized, other errors are failures of triton.
+    pub(crate) fn from_triton_message(message: &str) -> Self {
+        match unknown_model(message) {
+            Some(model) => AppError::ModelNotFound(model),
+            None => AppError::Triton(message.to_string()),
+        }
+    }
+
+    fn from_status(status: &tonic::Status) -> Self {
+        let message = status.message().to_string();
+        match status.code() {
+            Code::InvalidArgument | Code::OutOfRange | Code::FailedPrecondition => {
+                AppError::invalid_request(message, None)
+            }
+            Code::NotFound => match unknown_model(&message) {
+                Some(model) => AppError::ModelNotFound(model),
+                None => AppError::invalid_request(message, None),
+            },
+            Code::Unauthenticated => AppError::Unauthorized(message),
+            Code::PermissionDenied => AppError::PermissionDenied(message),
+            Code::ResourceExhausted => AppError::RateLimited(message),
+            Code::Unavailable | Code::DeadlineExceeded | Code::Aborted => {
+                AppError::Unavailable(format!("triton is unavailable: {}", message))
+            }
+            _ => AppError::Triton(message),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            AppError::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Triton(_) | AppError::InvalidOutput { .. } | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn body(&self) -> ErrorResponse {
+        let (message, r#type, param, code) = match self {
+            AppError::InvalidRequest { message, param } => (
+                message.clone(),
+                "invalid_request_error",
+                param.clone(),
+                None,
+            ),
+            AppError::Unauthorized(message) => (
+                message.clone(),
+                "authentication_error",
+                None,
+                Some("invalid_api_key"),
+            ),
+            AppError::PermissionDenied(message) => {
+                (message.clone(), "permission_error", None, None)
+            }
+            AppError::ModelNotFound(model) => (
+                format!("The model '{}' does not exist", model),
+                "invalid_request_error",
+                Some("model".to_string()),
+                Some("model_not_found"),
+            ),
+            AppError::RateLimited(message) => (
+                message.clone(),
+                "rate_limit_error",
+                None,
+                Some("rate_limit_exceeded"),
+            ),
+            AppError::Unavailable(message) | AppError::Triton(message) => {
+                (message.clone(), "server_error", None, None)
+            }
+            AppError::InvalidOutput { param, reason } => (
+                format!("the model failed to follow the {}: {}", param, reason),
+                "server_error",
+                Some(param.clone()),
+                Some("invalid_model_output"),
+            ),
+            AppError::Internal(_) => (
+                "An error occurred while trying to fulfill your request.".to_string(),
+                "server_error",
+                None,
+                None,
+            ),
+        };
+
+        ErrorResponse {
+            error: ErrorBody {
+                message,
+                r#type: r#type.to_string(),
+                param,
+                code: code.map(Into::into),
+            },
+        }
+    }
+
+    /// Convert the error into a server-sent event. Once streaming started, the status code is
+    /// already sent, so errors are reported in an `error` event instead.
+    pub(crate) fn into_event(self) -> Event {
+        match &self {
+            AppError::Internal(err) => tracing::error!("streaming failed: {:?}", err),
+            _ => tracing::error!("streaming failed: {:?}", self),
+        }
+
+        // Corresponds to https://github.com/openai/openai-python/blob/17ac6779958b2b74999c634c4ea4c7b74906027a/src/openai/_streaming.py#L113
+        Event::default()
+            .event("error")
+            .json_data(self.body())
+            .unwrap()
+    }
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "An error occurred while trying to fulfill your request.",
-        )
-            .into_response()
+        (self.status_code(), Json(self.body())).into_response()
     }
 }
 
@@ -21,6 +180,99 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err = err.into();
+
+        if let Some(rejection) = err.downcast_ref::<JsonRejection>() {
+            return AppError::invalid_request(rejection.body_text(), None);
+        }
+        if let Some(status) = err.chain().find_map(|e| e.downcast_ref::<tonic::Status>()) {
+            return AppError::from_status(status);
+        }
+        if err
+            .chain()
+            .any(|e| e.is::<liquid::Error>() || e.is::<minijinja::Error>())
+        {
+            return AppError::invalid_request(
+                format!("failed to render chat template: {:#}", err),
+                Some("messages"),
+            );
+        }
+        AppError::Internal(err)
+    }
+}
+
+/// Report the errors of a response stream as error events, and in the metrics of the request.
+pub(crate) fn error_events<S>(
+    stream: S,
+    metrics: RequestMetrics,
+) -> impl Stream<Item = Result<Event, Infallible>>
+where
+    S: Stream<Item = Result<Event, AppError>>,
+{
+    stream.map(move |event| {
+        Ok(event.unwrap_or_else(|err| {
+            metrics.error(&err);
+            err.into_event()
+        }))
+    })
+}
+
+/// Extract the model name from triton's "Request for unknown model: 'x' is not found" errors.
+fn unknown_model(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("unknown model")?;
+    let model = rest.split('\'').nth(1)?;
+    Some(model.to_string())
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    message: String,
+    r#type: String,
+    param: Option<String>,
+    code: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_triton_messages() {
+        let error =
+            AppError::from_triton_message("Request for unknown model: 'llama' is not found");
+        assert_eq!(StatusCode::NOT_FOUND, error.status_code());
+        assert!(matches!(error, AppError::ModelNotFound(model) if model == "llama"));
+
+        // Without status code, errors are not classified by their message.
+        let error = AppError::from_triton_message("input 'max_tokens' exceeds the limit");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, error.status_code());
+    }
+
+    #[test]
+    pub fn test_grpc_status() {
+        let error: AppError = anyhow::Error::from(tonic::Status::unavailable("connection refused"))
+            .context("failed to call triton grpc method model_stream_infer")
+            .into();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, error.status_code());
+
+        let error: AppError = tonic::Status::resource_exhausted("queue is full").into();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, error.status_code());
+
+        let error: AppError =
+            tonic::Status::invalid_argument("unexpected inference input 'foo'").into();
+        assert_eq!(StatusCode::BAD_REQUEST, error.status_code());
+
+        let error: AppError = tonic::Status::internal("CUDA out of memory").into();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, error.status_code());
+
+        let error: AppError = anyhow::anyhow!("something went wrong").into();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, error.status_code());
+        let body = serde_json::to_value(error.body()).unwrap();
+        assert_eq!("server_error", body["error"]["type"]);
     }
 }
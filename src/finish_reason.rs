@@ -0,0 +1,46 @@
+//! Find out why the generation of a choice ended. Triton does not tell whether TensorRT-LLM
+//! sampled the end token, matched a stop word or reached `max_tokens`.
+use crate::tokenizer::Tokenizer;
+
+/// Count the tokens generated for a choice with the tokenizer of the model.
+///
+/// Without tokenizer, streaming inferences are assumed to send one response per token, which does
+/// not hold when TensorRT-LLM sends several tokens at once, e.g. with speculative decoding. The
+/// count is then too low and `length` may be missed. Non-streaming inferences are not counted.
+pub(crate) fn generated_tokens(
+    text: &str,
+    responses: usize,
+    streaming: bool,
+    tokenizer: Option<&Tokenizer>,
+) -> anyhow::Result<Option<usize>> {
+    match tokenizer {
+        Some(tokenizer) => tokenizer.count_completion_tokens(text).map(Some),
+        None => Ok(streaming.then_some(responses)),
+    }
+}
+
+/// Whether the generation was cut by `max_tokens`, rather than ended by the end token or a stop
+/// sequence. Without token count, the generation is assumed to have ended by itself.
+pub(crate) fn reached_max_tokens(stopped: bool, tokens: Option<usize>, max_tokens: usize) -> bool {
+    !stopped && tokens.is_some_and(|tokens| tokens >= max_tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_reached_max_tokens() {
+        assert!(reached_max_tokens(false, Some(2), 2));
+        assert!(!reached_max_tokens(false, Some(2), 16));
+        // The stop sequence is the last token generated.
+        assert!(!reached_max_tokens(true, Some(2), 2));
+        assert!(!reached_max_tokens(false, None, 2));
+    }
+
+    #[test]
+    pub fn test_generated_tokens() {
+        assert_eq!(Some(3), generated_tokens("Hello", 3, true, None).unwrap());
+        assert_eq!(None, generated_tokens("Hello", 1, false, None).unwrap());
+    }
+}
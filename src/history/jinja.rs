@@ -0,0 +1,265 @@
+use std::fs;
+
+use anyhow::{bail, Context};
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::routes::chat::{
+    ChatCompletionMessageParams, ChatCompletionMessageToolCall, FunctionDefinition, MessageContent,
+};
+
+const DEFAULT_TEMPLATE_NAME: &str = "default";
+const TOOL_USE_TEMPLATE_NAME: &str = "tool_use";
+
+/// A Jinja chat template following the conventions of HuggingFace transformers, which renders
+/// `messages` in the OpenAI format, `tools`, `add_generation_prompt`, `bos_token` and `eos_token`.
+pub(crate) struct JinjaTemplate {
+    env: Environment<'static>,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+}
+
+impl JinjaTemplate {
+    pub(crate) fn new(template: String) -> anyhow::Result<Self> {
+        Self::with_templates(
+            vec![(DEFAULT_TEMPLATE_NAME.to_string(), template)],
+            None,
+            None,
+        )
+    }
+
+    /// Load the `chat_template`, `bos_token` and `eos_token` of a `tokenizer_config.json`.
+    pub(crate) fn from_tokenizer_config(path: &str) -> anyhow::Result<Self> {
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("failed to read tokenizer config {}", path))?;
+        let config: TokenizerConfig = serde_json::from_str(&config)
+            .with_context(|| format!("failed to parse tokenizer config {}", path))?;
+
+        let templates = match config.chat_template {
+            Some(ChatTemplate::Single(template)) => {
+                vec![(DEFAULT_TEMPLATE_NAME.to_string(), template)]
+            }
+            Some(ChatTemplate::Named(templates)) => templates
+                .into_iter()
+                .map(|template| (template.name, template.template))
+                .collect(),
+            None => bail!("tokenizer config {} has no chat_template", path),
+        };
+
+        Self::with_templates(
+            templates,
+            config.bos_token.map(SpecialToken::into_content),
+            config.eos_token.map(SpecialToken::into_content),
+        )
+    }
+
+    fn with_templates(
+        templates: Vec<(String, String)>,
+        bos_token: Option<String>,
+        eos_token: Option<String>,
+    ) -> anyhow::Result<Self> {
+        // Same settings as the environment of transformers' apply_chat_template.
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        for (name, template) in templates {
+            env.add_template_owned(name, template)?;
+        }
+        if env.get_template(DEFAULT_TEMPLATE_NAME).is_err() {
+            bail!("chat template has no default template");
+        }
+
+        Ok(Self {
+            env,
+            bos_token,
+            eos_token,
+        })
+    }
+
+    pub(crate) fn render(
+        &self,
+        messages: &[ChatCompletionMessageParams],
+        tools: &[FunctionDefinition],
+        tool_required: bool,
+        format_instructions: Option<&str>,
+    ) -> anyhow::Result<String> {
+        let template = match self.env.get_template(TOOL_USE_TEMPLATE_NAME) {
+            Ok(template) if !tools.is_empty() => template,
+            _ => self.env.get_template(DEFAULT_TEMPLATE_NAME)?,
+        };
+
+        let mut messages: Vec<_> = messages.iter().map(message_value).collect();
+        if let Some(format_instructions) = format_instructions {
+            append_to_last_user_message(&mut messages, format_instructions);
+        }
+        // Templates check whether tools are defined rather than empty.
+        let tools = (!tools.is_empty()).then(|| {
+            tools
+                .iter()
+                .map(|function| json!({"type": "function", "function": function}))
+                .collect::<Vec<_>>()
+        });
+        let tool_choice = if tool_required { "required" } else { "auto" };
+
+        Ok(template.render(context! {
+            messages,
+            tools,
+            tool_choice,
+            add_generation_prompt => true,
+            bos_token => self.bos_token.as_deref().unwrap_or_default(),
+            eos_token => self.eos_token.as_deref().unwrap_or_default(),
+        })?)
+    }
+}
+
+/// Convert a message to the OpenAI format expected by HuggingFace templates.
+fn message_value(message: &ChatCompletionMessageParams) -> Value {
+    match message {
+        ChatCompletionMessageParams::System { content, name } => {
+            named_message_value("system", &content.text(), name)
+        }
+        ChatCompletionMessageParams::User { content, name } => {
+            named_message_value("user", &content.text(), name)
+        }
+        ChatCompletionMessageParams::Assistant {
+            content,
+            tool_calls,
+        } => {
+            let content = content.as_ref().map(MessageContent::text);
+            let mut message = json!({"role": "assistant", "content": content});
+            if let Some(tool_calls) = tool_calls {
+                message["tool_calls"] = tool_calls.iter().map(tool_call_value).collect();
+            }
+            message
+        }
+        ChatCompletionMessageParams::Tool {
+            content,
+            tool_call_id,
+        } => json!({"role": "tool", "content": content.text(), "tool_call_id": tool_call_id}),
+    }
+}
+
+fn append_to_last_user_message(messages: &mut [Value], text: &str) {
+    let last_user_message = messages
+        .iter_mut()
+        .rev()
+        .find(|message| message["role"] == "user");
+    if let Some(message) = last_user_message {
+        let content = message["content"].as_str().unwrap_or_default();
+        message["content"] = format!("{}\n\n{}", content, text).into();
+    }
+}
+
+/// Templates check whether `name` is defined, so it is omitted rather than null.
+fn named_message_value(role: &str, content: &str, name: &Option<String>) -> Value {
+    let mut message = json!({"role": role, "content": content});
+    if let Some(name) = name {
+        message["name"] = name.as_str().into();
+    }
+    message
+}
+
+/// Templates usually serialize the arguments with `tojson`, so they are passed as an object
+/// rather than the JSON string of the OpenAI format.
+fn tool_call_value(tool_call: &ChatCompletionMessageToolCall) -> Value {
+    let ChatCompletionMessageToolCall::Function { id, function } = tool_call;
+    let arguments = serde_json::from_str(&function.arguments)
+        .unwrap_or_else(|_| Value::String(function.arguments.clone()));
+    json!({
+        "id": id,
+        "type": "function",
+        "function": {"name": function.name, "arguments": arguments},
+    })
+}
+
+fn raise_exception(message: String) -> Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, message))
+}
+
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    chat_template: Option<ChatTemplate>,
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChatTemplate {
+    Single(String),
+    Named(Vec<NamedChatTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedChatTemplate {
+    name: String,
+    template: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Content(String),
+    AddedToken { content: String },
+}
+
+impl SpecialToken {
+    fn into_content(self) -> String {
+        match self {
+            SpecialToken::Content(content) | SpecialToken::AddedToken { content } => content,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::routes::chat::FunctionCall;
+
+    #[test]
+    pub fn test_tool_calls() {
+        let template = JinjaTemplate::new(
+            "{% for tool in tools %}{{ tool.function.name }}\n{% endfor %}\
+{% for message in messages %}{{ message.role }}:\
+{% if message.tool_calls %}{{ message.tool_calls[0].function.arguments.city }}\
+{% else %}{{ message.content.strip() }}{% endif %}\n{% endfor %}"
+                .into(),
+        )
+        .expect("chat template should build correctly");
+
+        let tools = vec![FunctionDefinition {
+            name: "get_weather".into(),
+            description: None,
+            parameters: None,
+        }];
+        let messages = vec![
+            ChatCompletionMessageParams::User {
+                content: " weather in Paris? ".into(),
+                name: None,
+            },
+            ChatCompletionMessageParams::Assistant {
+                content: None,
+                tool_calls: Some(vec![ChatCompletionMessageToolCall::Function {
+                    id: "call_1".into(),
+                    function: FunctionCall {
+                        name: "get_weather".into(),
+                        arguments: "{\"city\":\"Paris\"}".into(),
+                    },
+                }]),
+            },
+        ];
+
+        let result = template
+            .render(&messages, &tools, false, None)
+            .expect("history should build correctly");
+        assert_eq!("get_weather\nuser:weather in Paris?assistant:Paris", result);
+
+        let template = JinjaTemplate::new("{{ raise_exception('roles must alternate') }}".into())
+            .expect("chat template should build correctly");
+        let error = template.render(&messages, &[], false, None).unwrap_err();
+        assert!(error.is::<Error>());
+    }
+}
@@ -1,19 +1,45 @@
-use crate::routes::chat::ChatCompletionMessageParams;
-use anyhow::bail;
+use crate::error::AppError;
+use crate::history::jinja::JinjaTemplate;
+use crate::routes::chat::{
+    ChatCompletionMessageParams, ChatCompletionMessageToolCall, FunctionDefinition, MessageContent,
+};
+use anyhow::{bail, Context};
+use glob::Pattern;
 use liquid::{ParserBuilder, Template};
 use serde::Serialize;
 use std::fs::File;
 use std::io::Read;
 use std::sync::Arc;
 
-const DEFAULT_TEMPLATE: &str = "{% for item in items %}\
-{{ item.identity }}{% if item.name %} {{ item.name }}{% endif %}: {{ item.content }}
+mod jinja;
+
+const DEFAULT_TEMPLATE: &str = "{% if tools != empty %}\
+System: You have access to the following functions:
+{% for tool in tools %}{{ tool.json }}
+{% endfor %}\
+{% if tool_choice == \"required\" %}You must call at least one of them. \
+{% else %}Only call them when they help to answer. {% endif %}\
+To call a function, reply with <tool_call>{\"name\": <function name>, \
+\"arguments\": <arguments object>}</tool_call> for each call.
+{% endif %}\
+{% for item in items %}\
+{{ item.identity }}{% if item.name %} {{ item.name }}{% endif %}: {{ item.content }}\
+{% for tool_call in item.tool_calls %}<tool_call>{{ tool_call.json }}</tool_call>{% endfor %}
 {% endfor %}\
+{% if format_instructions != empty %}System: {{ format_instructions }}
+{% endif %}\
 ASSISTANT:";
 
 #[derive(Clone)]
 pub struct HistoryBuilder {
-    history_template: Arc<Template>,
+    history_template: HistoryTemplate,
+}
+
+#[derive(Clone)]
+enum HistoryTemplate {
+    Liquid(Arc<Template>),
+    /// HuggingFace chat template.
+    Jinja(Arc<JinjaTemplate>),
 }
 
 impl HistoryBuilder {
@@ -21,31 +47,177 @@ impl HistoryBuilder {
         if template.is_some() && template_file.is_some() {
             bail!("cannot set both history-template and history-template-file")
         }
-        let mut _ref_holder = None;
 
-        let template = match template_file {
+        match template_file {
             None => match template {
-                None => DEFAULT_TEMPLATE,
-                Some(cfg) => cfg.as_str(),
+                None => Self::parse(DEFAULT_TEMPLATE),
+                Some(cfg) => Self::parse(cfg),
             },
-            Some(filename) => {
-                _ref_holder = Some(load_template_file(filename)?);
-                _ref_holder.as_ref().unwrap().as_str()
-            }
+            Some(filename) => Self::from_file(filename),
+        }
+    }
+
+    /// Load a template from a file. The template is a HuggingFace chat template if the file is a
+    /// `tokenizer_config.json` (any `.json` file) or a `.jinja` file, otherwise a liquid template.
+    fn from_file(template_file: &String) -> anyhow::Result<Self> {
+        let history_template = if template_file.ends_with(".json") {
+            HistoryTemplate::Jinja(Arc::new(JinjaTemplate::from_tokenizer_config(
+                template_file,
+            )?))
+        } else if template_file.ends_with(".jinja") {
+            let template = load_template_file(template_file)
+                .with_context(|| format!("failed to load history template {}", template_file))?;
+            HistoryTemplate::Jinja(Arc::new(JinjaTemplate::new(template)?))
+        } else {
+            let template = load_template_file(template_file)
+                .with_context(|| format!("failed to load history template {}", template_file))?;
+            return Self::parse(&template);
         };
 
+        Ok(HistoryBuilder { history_template })
+    }
+
+    fn parse(template: &str) -> anyhow::Result<Self> {
         let history_template = Arc::new(ParserBuilder::with_stdlib().build()?.parse(template)?);
 
-        Ok(HistoryBuilder { history_template })
+        Ok(HistoryBuilder {
+            history_template: HistoryTemplate::Liquid(history_template),
+        })
     }
 
     pub fn build_history(
         &self,
-        messages: &Vec<ChatCompletionMessageParams>,
+        messages: &[ChatCompletionMessageParams],
+    ) -> anyhow::Result<String> {
+        self.build_history_with_tools(messages, &[], false)
+    }
+
+    /// Build the prompt with the given functions exposed to the model. The template receives
+    /// them as `tools` and `tool_choice` is set to `required` if the model must call one of them,
+    /// otherwise `auto`.
+    pub fn build_history_with_tools(
+        &self,
+        messages: &[ChatCompletionMessageParams],
+        tools: &[FunctionDefinition],
+        tool_required: bool,
+    ) -> anyhow::Result<String> {
+        self.build_history_with_format(messages, tools, tool_required, None)
+    }
+
+    /// Build the prompt like [`Self::build_history_with_tools`], with instructions on the format of
+    /// the response. Liquid templates receive them as `format_instructions`, while they are
+    /// appended to the last user message for HuggingFace templates, which know nothing about them.
+    pub fn build_history_with_format(
+        &self,
+        messages: &[ChatCompletionMessageParams],
+        tools: &[FunctionDefinition],
+        tool_required: bool,
+        format_instructions: Option<&str>,
     ) -> anyhow::Result<String> {
-        let items: Vec<_> = messages.iter().map(|x| HistoryItem::new(x)).collect();
-        let context = liquid::object!({"items": items});
-        Ok(self.history_template.render(&context)?)
+        let history_template = match &self.history_template {
+            HistoryTemplate::Liquid(history_template) => history_template,
+            HistoryTemplate::Jinja(history_template) => {
+                return history_template.render(
+                    messages,
+                    tools,
+                    tool_required,
+                    format_instructions,
+                );
+            }
+        };
+
+        let items: Vec<_> = messages.iter().map(HistoryItem::new).collect();
+        let tools = tools
+            .iter()
+            .map(ToolItem::new)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let tool_choice = if tool_required { "required" } else { "auto" };
+        let context = liquid::object!({
+            "items": items,
+            "tools": tools,
+            "tool_choice": tool_choice,
+            "format_instructions": format_instructions.unwrap_or_default(),
+        });
+        Ok(history_template.render(&context)?)
+    }
+}
+
+/// History builders of the models, selected by model name.
+#[derive(Clone)]
+pub struct HistoryBuilders {
+    default: Option<HistoryBuilder>,
+    models: Arc<Vec<(Pattern, HistoryBuilder)>>,
+}
+
+impl HistoryBuilders {
+    /// Create the history builders from templates given as `pattern=file`, where the pattern is a
+    /// model name or a glob matching model names. Patterns are tried in order.
+    ///
+    /// `template` and `template_file` set the default used for models matching no pattern. Without
+    /// model templates, the built-in template is the default, otherwise there is no default unless
+    /// given explicitly.
+    pub fn new(
+        template: &Option<String>,
+        template_file: &Option<String>,
+        model_templates: &[String],
+    ) -> anyhow::Result<Self> {
+        let models = model_templates
+            .iter()
+            .map(|model_template| {
+                let (pattern, file) = model_template.split_once('=').with_context(|| {
+                    format!(
+                        "invalid model history template {}, expected pattern=file",
+                        model_template
+                    )
+                })?;
+                let pattern = Pattern::new(pattern.trim())
+                    .with_context(|| format!("invalid model pattern {}", pattern))?;
+                Ok((
+                    pattern,
+                    HistoryBuilder::from_file(&file.trim().to_string())?,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let default = if template.is_some() || template_file.is_some() || models.is_empty() {
+            Some(HistoryBuilder::new(template, template_file)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            default,
+            models: Arc::new(models),
+        })
+    }
+
+    /// Use the template file for the model with the given name, before trying the patterns.
+    pub fn insert_model_template(
+        &mut self,
+        model: &str,
+        template_file: &str,
+    ) -> anyhow::Result<()> {
+        let pattern = Pattern::new(&Pattern::escape(model))?;
+        let builder = HistoryBuilder::from_file(&template_file.to_string())?;
+        Arc::make_mut(&mut self.models).insert(0, (pattern, builder));
+        Ok(())
+    }
+
+    /// Get the history builder of a model, matching either the model name requested by the client
+    /// or the triton model it resolves to.
+    pub(crate) fn get(&self, model: &str, triton_model: &str) -> Result<HistoryBuilder, AppError> {
+        self.models
+            .iter()
+            .find(|(pattern, _)| pattern.matches(model) || pattern.matches(triton_model))
+            .map(|(_, builder)| builder)
+            .or(self.default.as_ref())
+            .cloned()
+            .ok_or_else(|| {
+                AppError::invalid_request(
+                    format!("no chat template is configured for model {}", model),
+                    Some("model"),
+                )
+            })
     }
 }
 
@@ -61,22 +233,41 @@ struct HistoryItem {
     identity: String,
     content: String,
     name: Option<String>,
+    tool_calls: Vec<ToolCallItem>,
+    tool_call_id: Option<String>,
 }
 
 impl HistoryItem {
     pub fn new(message: &ChatCompletionMessageParams) -> Self {
+        let mut tool_calls = Vec::new();
+        let mut tool_call_id = None;
         let (identity, content, name) = match message {
             ChatCompletionMessageParams::System { content, name } => {
-                ("System".into(), content.clone(), name.clone())
+                ("System".into(), content.text(), name.clone())
             }
             ChatCompletionMessageParams::User { content, name } => {
-                ("User".into(), content.clone(), name.clone())
+                ("User".into(), content.text(), name.clone())
             }
-            ChatCompletionMessageParams::Assistant { content } => {
-                ("Assistant".into(), content.clone(), None)
+            ChatCompletionMessageParams::Assistant {
+                content,
+                tool_calls: calls,
+            } => {
+                tool_calls = calls.iter().flatten().map(ToolCallItem::new).collect();
+                (
+                    "Assistant".into(),
+                    content
+                        .as_ref()
+                        .map(MessageContent::text)
+                        .unwrap_or_default(),
+                    None,
+                )
             }
-            ChatCompletionMessageParams::Tool { content, .. } => {
-                ("Tool".into(), content.clone(), None)
+            ChatCompletionMessageParams::Tool {
+                content,
+                tool_call_id: id,
+            } => {
+                tool_call_id = Some(id.clone());
+                ("Tool".into(), content.text(), None)
             }
         };
 
@@ -84,6 +275,56 @@ impl HistoryItem {
             identity,
             content,
             name,
+            tool_calls,
+            tool_call_id,
+        }
+    }
+}
+
+/// A function exposed to the model. `json` holds the whole definition for templates which simply
+/// dump it into the prompt.
+#[derive(Serialize)]
+struct ToolItem {
+    name: String,
+    description: Option<String>,
+    parameters: String,
+    json: String,
+}
+
+impl ToolItem {
+    fn new(function: &FunctionDefinition) -> anyhow::Result<Self> {
+        Ok(ToolItem {
+            name: function.name.clone(),
+            description: function.description.clone(),
+            parameters: serde_json::to_string(&function.parameters)?,
+            json: serde_json::to_string(function)?,
+        })
+    }
+}
+
+/// A tool call made by the assistant earlier in the conversation.
+#[derive(Serialize)]
+struct ToolCallItem {
+    id: String,
+    name: String,
+    arguments: String,
+    json: String,
+}
+
+impl ToolCallItem {
+    fn new(tool_call: &ChatCompletionMessageToolCall) -> Self {
+        let ChatCompletionMessageToolCall::Function { id, function } = tool_call;
+        // Inline the arguments as an object if they are valid JSON, so that the call is rendered
+        // exactly in the format the model is asked to produce.
+        let arguments = serde_json::from_str(&function.arguments)
+            .unwrap_or_else(|_| serde_json::Value::String(function.arguments.clone()));
+        let json = serde_json::json!({"name": function.name, "arguments": arguments});
+
+        ToolCallItem {
+            id: id.clone(),
+            name: function.name.clone(),
+            arguments: function.arguments.clone(),
+            json: json.to_string(),
         }
     }
 }
@@ -91,6 +332,7 @@ impl HistoryItem {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::routes::chat::{ContentPart, FunctionCall};
 
     #[test]
     pub fn test_default_template() {
@@ -109,7 +351,8 @@ mod test {
                 name: None,
             },
             ChatCompletionMessageParams::Assistant {
-                content: "test assistant 1".into(),
+                content: Some("test assistant 1".into()),
+                tool_calls: None,
             },
             ChatCompletionMessageParams::Tool {
                 content: "test tool 1".into(),
@@ -161,7 +404,8 @@ ASSISTANT:"
                 name: None,
             },
             ChatCompletionMessageParams::Assistant {
-                content: "test assistant 1".into(),
+                content: Some("test assistant 1".into()),
+                tool_calls: None,
             },
             ChatCompletionMessageParams::Tool {
                 content: "test tool 1".into(),
@@ -213,7 +457,8 @@ ASSISTANT:"
                 name: None,
             },
             ChatCompletionMessageParams::Assistant {
-                content: "test assistant 1".into(),
+                content: Some("test assistant 1".into()),
+                tool_calls: None,
             },
             ChatCompletionMessageParams::Tool {
                 content: "test tool 1".into(),
@@ -245,6 +490,270 @@ ASSISTANT:"
         assert_eq!(expected_result, result)
     }
 
+    #[test]
+    pub fn test_default_template_with_tools() {
+        let builder =
+            HistoryBuilder::new(&None, &None).expect("default template should build correctly");
+
+        let tools = vec![FunctionDefinition {
+            name: "get_weather".into(),
+            description: Some("Get the current weather".into()),
+            parameters: Some(serde_json::json!({"type": "object"})),
+        }];
+        let messages = vec![
+            ChatCompletionMessageParams::User {
+                content: "weather in Paris?".into(),
+                name: None,
+            },
+            ChatCompletionMessageParams::Assistant {
+                content: None,
+                tool_calls: Some(vec![ChatCompletionMessageToolCall::Function {
+                    id: "call_1".into(),
+                    function: FunctionCall {
+                        name: "get_weather".into(),
+                        arguments: "{\"city\":\"Paris\"}".into(),
+                    },
+                }]),
+            },
+            ChatCompletionMessageParams::Tool {
+                content: "sunny".into(),
+                tool_call_id: "call_1".into(),
+            },
+        ];
+
+        let result = builder
+            .build_history_with_tools(&messages, &tools, true)
+            .expect("history should build correctly");
+
+        let expected_result: String = "System: You have access to the following functions:
+{\"name\":\"get_weather\",\"description\":\"Get the current weather\",\"parameters\":{\"type\":\"object\"}}
+You must call at least one of them. To call a function, reply with <tool_call>{\"name\": <function name>, \"arguments\": <arguments object>}</tool_call> for each call.
+User: weather in Paris?
+Assistant: <tool_call>{\"arguments\":{\"city\":\"Paris\"},\"name\":\"get_weather\"}</tool_call>
+Tool: sunny
+ASSISTANT:"
+            .into();
+
+        assert_eq!(expected_result, result)
+    }
+
+    #[test]
+    pub fn test_template_files_with_tools() {
+        let tools = vec![FunctionDefinition {
+            name: "get_weather".into(),
+            description: None,
+            parameters: None,
+        }];
+        let messages = vec![
+            ChatCompletionMessageParams::User {
+                content: "weather in Paris?".into(),
+                name: None,
+            },
+            ChatCompletionMessageParams::Assistant {
+                content: None,
+                tool_calls: Some(vec![ChatCompletionMessageToolCall::Function {
+                    id: "call_1".into(),
+                    function: FunctionCall {
+                        name: "get_weather".into(),
+                        arguments: "{}".into(),
+                    },
+                }]),
+            },
+        ];
+
+        for (name, expected_result) in [
+            (
+                "history_template_baichuan",
+                "System: You have access to the following functions:
+{\"name\":\"get_weather\",\"description\":null}
+You must call at least one of them. To call a function, reply with <tool_call>{\"name\": <function name>, \"arguments\": <arguments object>}</tool_call> for each call.
+<reserved_106>: weather in Paris?
+<reserved_107>: <tool_call>{\"arguments\":{},\"name\":\"get_weather\"}</tool_call>
+<reserved_107>:",
+            ),
+            (
+                "history_template_llama3",
+                "<|start_header_id|>System<|end_header_id|>
+You have access to the following functions:
+{\"name\":\"get_weather\",\"description\":null}
+You must call at least one of them. To call a function, reply with <tool_call>{\"name\": <function name>, \"arguments\": <arguments object>}</tool_call> for each call.<|eot_id|>
+<|start_header_id|>User<|end_header_id|>
+weather in Paris?<|eot_id|>
+<|start_header_id|>Assistant<|end_header_id|>
+<tool_call>{\"arguments\":{},\"name\":\"get_weather\"}</tool_call><|eot_id|>
+<|start_header_id|>assistant<|end_header_id|>",
+            ),
+        ] {
+            let template_file = Some(format!(
+                "{}/templates/{}.liquid",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            ));
+            let builder = HistoryBuilder::new(&None, &template_file)
+                .expect("template file should build correctly");
+
+            let result = builder
+                .build_history_with_tools(&messages, &tools, true)
+                .expect("history should build correctly");
+
+            assert_eq!(expected_result, result, "{}", name);
+        }
+    }
+
+    #[test]
+    pub fn test_default_template_with_format() {
+        let builder =
+            HistoryBuilder::new(&None, &None).expect("default template should build correctly");
+
+        let messages = vec![ChatCompletionMessageParams::User {
+            content: "weather in Paris?".into(),
+            name: None,
+        }];
+
+        let result = builder
+            .build_history_with_format(&messages, &[], false, Some("Respond with JSON."))
+            .expect("history should build correctly");
+
+        assert_eq!(
+            "User: weather in Paris?\nSystem: Respond with JSON.\nASSISTANT:",
+            result
+        )
+    }
+
+    #[test]
+    pub fn test_template_files_with_format() {
+        let messages = vec![ChatCompletionMessageParams::User {
+            content: "weather in Paris?".into(),
+            name: None,
+        }];
+
+        for (name, expected_result) in [
+            (
+                "history_template",
+                "User: weather in Paris?\nSystem: Respond with JSON.\nASSISTANT:",
+            ),
+            (
+                "history_template_custom_roles",
+                "Customer: weather in Paris?\nRobot: Respond with JSON.\nASSISTANT:",
+            ),
+            (
+                "history_template_baichuan",
+                "<reserved_106>: weather in Paris?\nSystem: Respond with JSON.\n<reserved_107>:",
+            ),
+            (
+                "history_template_llama3",
+                "<|start_header_id|>User<|end_header_id|>\nweather in Paris?<|eot_id|>\n\
+<|start_header_id|>System<|end_header_id|>\nRespond with JSON.<|eot_id|>\n\
+<|start_header_id|>assistant<|end_header_id|>",
+            ),
+        ] {
+            let template_file = Some(format!(
+                "{}/templates/{}.liquid",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            ));
+            let builder = HistoryBuilder::new(&None, &template_file)
+                .expect("template file should build correctly");
+
+            let result = builder
+                .build_history_with_format(&messages, &[], false, Some("Respond with JSON."))
+                .expect("history should build correctly");
+
+            assert_eq!(expected_result, result, "{}", name);
+        }
+    }
+
+    #[test]
+    pub fn test_content_parts() {
+        let messages = vec![ChatCompletionMessageParams::User {
+            content: MessageContent::Parts(vec![
+                ContentPart::Text {
+                    text: "describe ".into(),
+                },
+                ContentPart::Text {
+                    text: "<image>".into(),
+                },
+            ]),
+            name: None,
+        }];
+        let result = HistoryBuilder::new(&None, &None)
+            .unwrap()
+            .build_history(&messages)
+            .expect("history should build correctly");
+        assert_eq!("User: describe <image>\nASSISTANT:", result);
+    }
+
+    #[test]
+    pub fn test_model_templates() {
+        let template_file =
+            |name: &str| format!("{}/templates/{}.liquid", env!("CARGO_MANIFEST_DIR"), name);
+        let model_templates = vec![
+            format!("llama3*={}", template_file("history_template_llama3")),
+            format!("baichuan={}", template_file("history_template_baichuan")),
+        ];
+        let messages = vec![ChatCompletionMessageParams::User {
+            content: "hello".into(),
+            name: None,
+        }];
+
+        let builders = HistoryBuilders::new(&None, &None, &model_templates)
+            .expect("model templates should build correctly");
+        let result = builders
+            .get("llama3-8b-instruct", "ensemble")
+            .expect("llama3 template should match")
+            .build_history(&messages)
+            .expect("history should build correctly");
+        assert!(result.starts_with("<|start_header_id|>User<|end_header_id|>"));
+        assert!(builders.get("chat", "baichuan").is_ok());
+        assert!(builders.get("mistral", "ensemble").is_err());
+
+        let builders = HistoryBuilders::new(&None, &None, &[])
+            .expect("default template should build correctly");
+        let result = builders
+            .get("mistral", "ensemble")
+            .expect("default template should match")
+            .build_history(&messages)
+            .expect("history should build correctly");
+        assert_eq!("User: hello\nASSISTANT:", result);
+    }
+
+    #[test]
+    pub fn test_tokenizer_config() {
+        let template = None;
+        let template_file = Some(format!(
+            "{}/templates/tokenizer_config_llama3.json",
+            env!("CARGO_MANIFEST_DIR")
+        ));
+        let builder = HistoryBuilder::new(&template, &template_file)
+            .expect("chat template should build correctly");
+
+        let messages = vec![
+            ChatCompletionMessageParams::System {
+                content: "test system 1 ".into(),
+                name: None,
+            },
+            ChatCompletionMessageParams::User {
+                content: "test user 1".into(),
+                name: Some("user 1".into()),
+            },
+        ];
+
+        let result = builder
+            .build_history(&messages)
+            .expect("history should build correctly");
+
+        let expected_result: String = "<|begin_of_text|><|start_header_id|>system<|end_header_id|>
+
+test system 1<|eot_id|><|start_header_id|>user<|end_header_id|>
+
+test user 1<|eot_id|><|start_header_id|>assistant<|end_header_id|>
+
+"
+        .into();
+
+        assert_eq!(expected_result, result)
+    }
+
     #[test]
     pub fn test_validations() {
         let template = Some("abc".into());
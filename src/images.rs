@@ -0,0 +1,91 @@
+//! Images of the chat messages, which are sent to the multimodal models in an input tensor next to
+//! `text_input`. Only base64 data URLs are accepted, the server never fetches remote images.
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::error::AppError;
+use crate::model_routes::ModelRoute;
+use crate::routes::chat::{ChatCompletionMessageParams, ContentPart, MessageContent};
+
+/// Take the images out of the messages, in order, and replace each of them by the image
+/// placeholder of the model, so that the template renders their position in the prompt. Models
+/// without image input only accept text.
+pub(crate) fn take_images(
+    messages: &mut [ChatCompletionMessageParams],
+    route: &ModelRoute,
+) -> Result<Vec<Vec<u8>>, AppError> {
+    let mut images = Vec::new();
+    for content in messages
+        .iter_mut()
+        .filter_map(ChatCompletionMessageParams::content_mut)
+    {
+        let MessageContent::Parts(parts) = content else {
+            continue;
+        };
+        for part in parts {
+            let ContentPart::ImageUrl { image_url } = part else {
+                continue;
+            };
+            if route.image_input.is_none() {
+                return Err(AppError::invalid_request(
+                    format!("the model {} does not accept images", route.id),
+                    Some("messages"),
+                ));
+            }
+            images.push(decode_data_url(&image_url.url)?);
+            *part = ContentPart::Text {
+                text: route.image_placeholder.clone(),
+            };
+        }
+    }
+    Ok(images)
+}
+
+/// Decode an image given as `data:image/<format>;base64,<data>`.
+fn decode_data_url(url: &str) -> Result<Vec<u8>, AppError> {
+    let invalid = |message: &str| AppError::invalid_request(message, Some("messages"));
+    let Some(data_url) = url.strip_prefix("data:") else {
+        return Err(invalid(
+            "image_url must be a base64 data URL, remote images are not fetched",
+        ));
+    };
+    let (header, data) = data_url
+        .split_once(',')
+        .ok_or_else(|| invalid("image_url is not a valid data URL"))?;
+    let Some(media_type) = header.strip_suffix(";base64") else {
+        return Err(invalid("image_url must be base64 encoded"));
+    };
+    if !media_type.starts_with("image/") {
+        return Err(invalid("image_url must have an image media type"));
+    }
+    BASE64
+        .decode(data)
+        .map_err(|err| invalid(&format!("image_url has invalid base64 data: {}", err)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_decode_data_url() {
+        assert_eq!(
+            b"\x89PNG".to_vec(),
+            decode_data_url("data:image/png;base64,iVBORw==").unwrap()
+        );
+
+        for url in [
+            "https://example.com/cat.png",
+            "data:image/png;base64",
+            "data:image/png,iVBORw==",
+            "data:text/plain;base64,aGVsbG8=",
+            "data:image/png;base64,not base64",
+        ] {
+            assert!(
+                matches!(decode_data_url(url), Err(AppError::InvalidRequest { .. })),
+                "{}",
+                url
+            );
+        }
+    }
+}
@@ -1,10 +1,22 @@
+pub mod admission;
+pub mod auth;
 pub mod config;
 mod error;
+mod finish_reason;
 pub mod history;
+mod images;
+mod logprobs;
+pub mod model_routes;
+pub mod rate_limit;
+mod request_metrics;
+mod response_format;
 pub mod routes;
 pub mod startup;
 pub mod state;
+mod stop_sequences;
 pub mod telemetry;
+mod tokenizer;
+mod tools;
 mod utils;
 
 mod triton;
@@ -0,0 +1,129 @@
+//! Log probabilities of the generated tokens, returned by TensorRT-LLM with `return_log_probs`.
+//!
+//! TensorRT-LLM returns the log probability of each sampled token but not the token itself, nor
+//! the most likely alternatives. The tokens are recovered by splitting the generated text with
+//! the tokenizer of the model, and the top log probabilities only hold the sampled token.
+use anyhow::bail;
+
+use crate::error::AppError;
+use crate::tokenizer::Tokenizer;
+
+/// Reject requests for more than one top log probability, since only the sampled token is known.
+pub(crate) fn check_top_logprobs(param: &str, top_logprobs: usize) -> Result<(), AppError> {
+    if top_logprobs > 1 {
+        return Err(AppError::invalid_request(
+            format!(
+                "{} must be 0 or 1, TensorRT-LLM only returns the log probability of the sampled token",
+                param
+            ),
+            Some(param),
+        ));
+    }
+    Ok(())
+}
+
+/// A generated token and its log probability.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TokenLogprob {
+    pub(crate) token: String,
+    pub(crate) logprob: f32,
+}
+
+/// Pair the text generated in a response with the log probabilities of its tokens.
+///
+/// A response of a single token needs no tokenizer. Otherwise the text must be split into exactly
+/// as many tokens as there are log probabilities, which fails without a tokenizer or when a token
+/// is only part of a character.
+pub(crate) fn token_logprobs(
+    text: &str,
+    logprobs: &[f32],
+    tokenizer: Option<&Tokenizer>,
+) -> anyhow::Result<Vec<TokenLogprob>> {
+    if text.is_empty() || logprobs.is_empty() {
+        return Ok(vec![]);
+    }
+
+    if logprobs.len() == 1 {
+        return Ok(vec![TokenLogprob {
+            token: text.to_string(),
+            logprob: logprobs[0],
+        }]);
+    }
+    let Some(tokenizer) = tokenizer else {
+        bail!(
+            "cannot split the output into its {} tokens to return their logprobs, the model has no tokenizer",
+            logprobs.len()
+        );
+    };
+    let tokens = tokenizer.split(text)?;
+    if tokens.len() != logprobs.len() {
+        tracing::warn!(
+            "cannot split {:?} into {} tokens, got {:?}",
+            text,
+            logprobs.len(),
+            tokens
+        );
+        bail!(
+            "cannot split the output into its {} tokens to return their logprobs",
+            logprobs.len()
+        );
+    }
+    Ok(tokens
+        .into_iter()
+        .zip(logprobs)
+        .map(|(token, &logprob)| TokenLogprob { token, logprob })
+        .collect())
+}
+
+/// Take the tokens making up the first `len` bytes of the text they were generated for, leaving
+/// the tokens of the text held back.
+pub(crate) fn take_tokens(tokens: &mut Vec<TokenLogprob>, len: usize) -> Vec<TokenLogprob> {
+    let mut taken_len = 0;
+    let count = tokens
+        .iter()
+        .take_while(|token| {
+            taken_len += token.token.len();
+            taken_len <= len
+        })
+        .count();
+    tokens.drain(..count).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_token_logprobs() {
+        assert!(token_logprobs("", &[-0.5], None).unwrap().is_empty());
+        assert!(token_logprobs("Hello", &[], None).unwrap().is_empty());
+
+        assert_eq!(
+            vec![TokenLogprob {
+                token: "Hello".to_string(),
+                logprob: -0.5,
+            }],
+            token_logprobs("Hello", &[-0.5], None).unwrap()
+        );
+
+        // Without a tokenizer, a response of several tokens cannot be split.
+        assert!(token_logprobs("Hello world", &[-0.5, -1.0], None).is_err());
+    }
+
+    #[test]
+    pub fn test_take_tokens() {
+        let mut tokens: Vec<_> = ["Hello", " wor", "ld"]
+            .into_iter()
+            .map(|token| TokenLogprob {
+                token: token.to_string(),
+                logprob: -0.5,
+            })
+            .collect();
+        assert!(take_tokens(&mut tokens, 3).is_empty());
+        assert_eq!(2, take_tokens(&mut tokens, 9).len());
+        assert_eq!(
+            vec!["ld"],
+            tokens.iter().map(|t| t.token.as_str()).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! Route the models requested by clients to triton models, so that clients only know public model
+//! ids while the triton models can be renamed or moved to other endpoints.
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+use crate::rate_limit::RateLimits;
+use crate::triton::pool::TritonPool;
+
+/// Input tensor of the texts of the embedding models, unless their route sets another one.
+const EMBEDDING_INPUT: &str = "text_input";
+
+/// Output tensor of the embeddings of the embedding models, unless their route sets another one.
+const EMBEDDING_OUTPUT: &str = "embeddings";
+
+/// Text replacing the images in the prompt of the multimodal models, unless their route sets
+/// another one.
+const IMAGE_PLACEHOLDER: &str = "<image>";
+
+/// A model exposed to clients, as configured in the routing table.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ModelRouteConfig {
+    /// Model id requested by clients.
+    pub id: String,
+    /// Other names of the model.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Name of the triton model, the id by default.
+    pub triton_model: Option<String>,
+    /// Version of the triton model, the latest one by default.
+    pub model_version: Option<String>,
+    /// Triton endpoints serving the model, the `--triton-endpoint` ones by default.
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// History template file of the chat completions of the model.
+    pub history_template_file: Option<String>,
+    /// Parameters of the requests for the model, used when the client does not set them.
+    #[serde(default)]
+    pub defaults: Map<String, Value>,
+    /// Limit of the requests per minute for the model, whatever the API key.
+    pub requests_per_minute: Option<u32>,
+    /// Limit of the generated tokens per minute for the model, whatever the API key.
+    pub tokens_per_minute: Option<u32>,
+    /// Inferences in flight for the model, `--max-concurrency` by default.
+    pub max_concurrency: Option<usize>,
+    /// Requests waiting for the model, `--max-queue` by default.
+    pub max_queue: Option<usize>,
+    /// Input tensor of the texts of an embedding model, `text_input` by default.
+    pub embedding_input: Option<String>,
+    /// FP32 output tensor of the embeddings of an embedding model, `embeddings` by default.
+    pub embedding_output: Option<String>,
+    /// BYTES input tensor of the images of a multimodal model, which only accepts text without it.
+    pub image_input: Option<String>,
+    /// Text replacing each image in the prompt of a multimodal model, `<image>` by default.
+    pub image_placeholder: Option<String>,
+}
+
+impl ModelRouteConfig {
+    /// Load the routing table from a JSON file holding an array of models.
+    pub fn load(path: &str) -> anyhow::Result<Vec<Self>> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open model routes file {}", path))?;
+        serde_json::from_reader(file)
+            .with_context(|| format!("failed to parse model routes file {}", path))
+    }
+}
+
+/// Where the requests for a model are sent.
+pub(crate) struct ModelRoute {
+    /// Model id, which is the name requested by the client for models without route.
+    pub(crate) id: String,
+    pub(crate) triton_model: String,
+    /// Empty for the latest version.
+    pub(crate) model_version: String,
+    /// Pool of the endpoints of the route, unless the model is served by the default pool.
+    pub(crate) triton: Option<TritonPool>,
+    pub(crate) rate_limits: RateLimits,
+    pub(crate) max_concurrency: Option<usize>,
+    pub(crate) max_queue: Option<usize>,
+    pub(crate) embedding_input: String,
+    pub(crate) embedding_output: String,
+    /// Input of the images, for multimodal models only.
+    pub(crate) image_input: Option<String>,
+    pub(crate) image_placeholder: String,
+    defaults: Map<String, Value>,
+}
+
+impl ModelRoute {
+    fn new(id: &str, triton_model: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            triton_model: triton_model.to_string(),
+            model_version: String::new(),
+            triton: None,
+            rate_limits: RateLimits::default(),
+            max_concurrency: None,
+            max_queue: None,
+            embedding_input: EMBEDDING_INPUT.to_string(),
+            embedding_output: EMBEDDING_OUTPUT.to_string(),
+            image_input: None,
+            image_placeholder: IMAGE_PLACEHOLDER.to_string(),
+            defaults: Map::new(),
+        }
+    }
+}
+
+/// The routing table of the models, by id and alias. Models without route are sent as is to the
+/// triton model of the same name.
+#[derive(Clone, Default)]
+pub struct ModelRoutes(Arc<HashMap<String, Arc<ModelRoute>>>);
+
+impl ModelRoutes {
+    /// Build the routing table from the configured routes and the `alias=model` aliases, where the
+    /// model is either a route id or a triton model. `new_pool` creates the pools of the routes
+    /// which have their own endpoints.
+    pub fn new<F>(
+        configs: &[ModelRouteConfig],
+        aliases: HashMap<String, String>,
+        new_pool: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn(&[String]) -> anyhow::Result<TritonPool>,
+    {
+        let mut pools: HashMap<Vec<String>, TritonPool> = HashMap::new();
+        let mut routes = HashMap::new();
+        for config in configs {
+            let triton = match config.endpoints.as_slice() {
+                [] => None,
+                endpoints => {
+                    let endpoints = endpoints.to_vec();
+                    if !pools.contains_key(&endpoints) {
+                        let pool = new_pool(&endpoints)?;
+                        pools.insert(endpoints.clone(), pool);
+                    }
+                    pools.get(&endpoints).cloned()
+                }
+            };
+            let route = Arc::new(ModelRoute {
+                id: config.id.clone(),
+                triton_model: config.triton_model.clone().unwrap_or(config.id.clone()),
+                model_version: config.model_version.clone().unwrap_or_default(),
+                triton,
+                rate_limits: RateLimits::new(config.requests_per_minute, config.tokens_per_minute)
+                    .with_context(|| {
+                        format!("invalid rate limits of the model route {}", config.id)
+                    })?,
+                max_concurrency: config.max_concurrency,
+                max_queue: config.max_queue,
+                embedding_input: config
+                    .embedding_input
+                    .clone()
+                    .unwrap_or(EMBEDDING_INPUT.to_string()),
+                embedding_output: config
+                    .embedding_output
+                    .clone()
+                    .unwrap_or(EMBEDDING_OUTPUT.to_string()),
+                image_input: config.image_input.clone(),
+                image_placeholder: config
+                    .image_placeholder
+                    .clone()
+                    .unwrap_or(IMAGE_PLACEHOLDER.to_string()),
+                defaults: config.defaults.clone(),
+            });
+            for name in std::iter::once(&config.id).chain(&config.aliases) {
+                if routes.insert(name.clone(), route.clone()).is_some() {
+                    bail!("model {} is routed more than once", name);
+                }
+            }
+        }
+
+        for (alias, model) in aliases {
+            let route = routes
+                .get(&model)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(ModelRoute::new(&alias, &model)));
+            if routes.insert(alias.clone(), route).is_some() {
+                bail!("model {} is routed more than once", alias);
+            }
+        }
+
+        Ok(Self(Arc::new(routes)))
+    }
+
+    /// Get the route of the model requested by the client.
+    pub(crate) fn resolve(&self, model: &str) -> Arc<ModelRoute> {
+        self.0
+            .get(model)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(ModelRoute::new(model, model)))
+    }
+
+    /// The routed model names, with their routes.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &Arc<ModelRoute>)> {
+        self.0.iter()
+    }
+
+    /// The pools of the routes which have their own endpoints.
+    pub(crate) fn pools(&self) -> Vec<&TritonPool> {
+        let mut pools: Vec<&TritonPool> = Vec::new();
+        for pool in self.0.values().filter_map(|route| route.triton.as_ref()) {
+            if !pools.iter().any(|other| other.same(pool)) {
+                pools.push(pool);
+            }
+        }
+        pools
+    }
+
+    /// Whether a route has rate limits.
+    pub(crate) fn is_rate_limited(&self) -> bool {
+        self.0.values().any(|route| route.rate_limits.is_limited())
+    }
+
+    /// Check the readiness of the routed triton models with the health checks of their pool, or
+    /// of the `default` pool for routes without endpoints.
+    pub fn watch_models(&self, default: &TritonPool) {
+        for route in self.0.values() {
+            let triton = route.triton.as_ref().unwrap_or(default);
+            triton.watch_model(&route.triton_model, &route.model_version);
+        }
+    }
+
+    /// Whether the model requested by the client is routed, or known by the pool of its route,
+    /// or by the `default` pool for routes without endpoints. Arbitrary model names sent by
+    /// clients are not known.
+    pub(crate) fn is_known(&self, model: &str, default: &TritonPool) -> bool {
+        self.0.contains_key(model) || {
+            let route = self.resolve(model);
+            let triton = route.triton.as_ref().unwrap_or(default);
+            triton.knows_model(&route.triton_model)
+        }
+    }
+
+    /// The model label of the request metrics, "unknown" for the models which are not known to
+    /// avoid creating series for arbitrary model names.
+    pub(crate) fn metric_label(&self, model: &str, default: &TritonPool) -> String {
+        if self.is_known(model, default) {
+            model.to_string()
+        } else {
+            "unknown".to_string()
+        }
+    }
+
+    /// Deserialize a request body, after filling the parameters it does not set with the defaults
+    /// of its model.
+    pub(crate) fn parse_request<T: DeserializeOwned>(
+        &self,
+        mut body: Value,
+    ) -> Result<T, AppError> {
+        let route = body
+            .get("model")
+            .and_then(Value::as_str)
+            .map(|model| self.resolve(model));
+        if let (Some(route), Some(params)) = (route, body.as_object_mut()) {
+            for (param, value) in &route.defaults {
+                if params.get(param).unwrap_or(&Value::Null).is_null() {
+                    params.insert(param.clone(), value.clone());
+                }
+            }
+        }
+        serde_json::from_value(body).map_err(|err| {
+            AppError::invalid_request(
+                format!(
+                    "Failed to deserialize the JSON body into the target type: {}",
+                    err
+                ),
+                None,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn routes(configs: Value, aliases: &[(&str, &str)]) -> ModelRoutes {
+        let configs: Vec<ModelRouteConfig> = serde_json::from_value(configs).unwrap();
+        let aliases = aliases
+            .iter()
+            .map(|(alias, model)| (alias.to_string(), model.to_string()))
+            .collect();
+        ModelRoutes::new(&configs, aliases, |_| bail!("the routes have no endpoints")).unwrap()
+    }
+
+    #[test]
+    pub fn test_resolve() {
+        let routes = routes(
+            json!([{
+                "id": "llama-3-8b",
+                "aliases": ["gpt-3.5-turbo"],
+                "triton_model": "ensemble",
+                "model_version": "2",
+            }]),
+            &[("llama", "llama-3-8b"), ("bls", "tensorrt_llm_bls")],
+        );
+
+        for model in ["llama-3-8b", "gpt-3.5-turbo", "llama"] {
+            let route = routes.resolve(model);
+            assert_eq!("llama-3-8b", route.id);
+            assert_eq!("ensemble", route.triton_model);
+            assert_eq!("2", route.model_version);
+        }
+        let route = routes.resolve("bls");
+        assert_eq!("bls", route.id);
+        assert_eq!("tensorrt_llm_bls", route.triton_model);
+        let route = routes.resolve("ensemble");
+        assert_eq!("ensemble", route.triton_model);
+        assert_eq!("", route.model_version);
+    }
+
+    #[test]
+    pub fn test_parse_request() {
+        let routes = routes(
+            json!([{
+                "id": "llama-3-8b",
+                "defaults": {"temperature": 0.6, "max_tokens": 512},
+            }]),
+            &[],
+        );
+
+        let request: Value = routes
+            .parse_request(json!({"model": "llama-3-8b", "max_tokens": 16, "top_p": null}))
+            .unwrap();
+        assert_eq!(
+            json!({"model": "llama-3-8b", "temperature": 0.6, "max_tokens": 16, "top_p": null}),
+            request
+        );
+        let request: Value = routes.parse_request(json!({"model": "other"})).unwrap();
+        assert_eq!(json!({"model": "other"}), request);
+    }
+}
@@ -0,0 +1,491 @@
+//! Token bucket rate limits on the requests and the generated tokens per minute, per API key and
+//! per model. Buckets refill continuously, so that a limit of 60 requests per minute allows a
+//! burst of 60 requests, then one request per second.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use axum::body::{to_bytes, Body};
+use axum::http::{HeaderMap, HeaderValue, Method, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+use crate::auth::ApiKey;
+use crate::error::AppError;
+use crate::model_routes::ModelRoutes;
+use crate::request_metrics::record_rate_limited;
+
+/// Rate limits of an API key or a model, unlimited when not set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    /// Limit of the generated tokens, counted with the tokenizer of the model if any, otherwise
+    /// by responses of triton.
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl RateLimits {
+    /// The given limits, rejecting limits of 0, which are left unset to be unlimited.
+    pub fn new(
+        requests_per_minute: Option<u32>,
+        tokens_per_minute: Option<u32>,
+    ) -> anyhow::Result<Self> {
+        if requests_per_minute == Some(0) {
+            bail!("requests_per_minute must be greater than 0, leave it unset to be unlimited");
+        }
+        if tokens_per_minute == Some(0) {
+            bail!("tokens_per_minute must be greater than 0, leave it unset to be unlimited");
+        }
+        Ok(Self {
+            requests_per_minute,
+            tokens_per_minute,
+        })
+    }
+
+    /// The limits, with the given ones for the limits which are not set.
+    pub(crate) fn or(self, defaults: RateLimits) -> Self {
+        Self {
+            requests_per_minute: self.requests_per_minute.or(defaults.requests_per_minute),
+            tokens_per_minute: self.tokens_per_minute.or(defaults.tokens_per_minute),
+        }
+    }
+
+    pub(crate) fn is_limited(&self) -> bool {
+        self.requests_per_minute.is_some() || self.tokens_per_minute.is_some()
+    }
+}
+
+/// What a bucket limits, named as in the `x-ratelimit-*` headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resource {
+    Requests,
+    Tokens,
+}
+
+impl Resource {
+    fn name(self) -> &'static str {
+        match self {
+            Resource::Requests => "requests",
+            Resource::Tokens => "tokens",
+        }
+    }
+
+    /// The names of the limit, remaining and reset headers.
+    fn headers(self) -> [&'static str; 3] {
+        match self {
+            Resource::Requests => [
+                "x-ratelimit-limit-requests",
+                "x-ratelimit-remaining-requests",
+                "x-ratelimit-reset-requests",
+            ],
+            Resource::Tokens => [
+                "x-ratelimit-limit-tokens",
+                "x-ratelimit-remaining-tokens",
+                "x-ratelimit-reset-tokens",
+            ],
+        }
+    }
+}
+
+/// A bucket holding up to a minute of its limit.
+#[derive(Debug)]
+struct Bucket {
+    level: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: u32, now: Instant) -> Self {
+        Self {
+            level: limit as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.level = (self.level + elapsed * limit as f64 / 60.0).min(limit as f64);
+        self.updated = now;
+    }
+
+    /// Time until the bucket holds the given amount.
+    fn wait(&self, limit: u32, amount: f64) -> Duration {
+        if self.level >= amount {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((amount - self.level) * 60.0 / limit as f64)
+    }
+}
+
+/// Who a bucket belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Scope {
+    ApiKey(String),
+    Model(String),
+}
+
+impl Scope {
+    fn describe(&self) -> String {
+        match self {
+            Scope::ApiKey(name) => format!("API key {}", name),
+            Scope::Model(id) => format!("model {}", id),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Scope::ApiKey(_) => "api_key",
+            Scope::Model(_) => "model",
+        }
+    }
+}
+
+/// The remaining quota of a resource, for the `x-ratelimit-*` headers.
+#[derive(Clone, Copy, Debug)]
+struct Quota {
+    limit: u32,
+    remaining: u32,
+    /// Time until the bucket is full again.
+    reset: Duration,
+}
+
+/// The quotas of the most limited bucket of each resource.
+#[derive(Default, Debug)]
+struct Quotas(Vec<(Resource, Quota)>);
+
+impl Quotas {
+    fn update(&mut self, resource: Resource, limit: u32, bucket: &Bucket) {
+        let quota = Quota {
+            limit,
+            remaining: bucket.level.max(0.0) as u32,
+            reset: bucket.wait(limit, limit as f64),
+        };
+        match self.0.iter_mut().find(|(other, _)| *other == resource) {
+            Some((_, other)) if other.remaining <= quota.remaining => {}
+            Some((_, other)) => *other = quota,
+            None => self.0.push((resource, quota)),
+        }
+    }
+
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        for (resource, quota) in &self.0 {
+            let [limit, remaining, reset] = resource.headers();
+            headers.insert(limit, quota.limit.into());
+            headers.insert(remaining, quota.remaining.into());
+            headers.insert(
+                reset,
+                HeaderValue::from_str(&format_reset(quota.reset)).unwrap(),
+            );
+        }
+    }
+}
+
+/// Format a duration like the `x-ratelimit-reset-*` headers of OpenAI, e.g. `6m0s` or `20ms`.
+fn format_reset(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        return format!("{}ms", duration.as_millis());
+    }
+    let secs = duration.as_secs_f64().ceil() as u64;
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// A request rejected by a rate limit.
+#[derive(Debug)]
+struct Limited {
+    scope: Scope,
+    resource: Resource,
+    retry_after: Duration,
+    quotas: Quotas,
+}
+
+impl IntoResponse for Limited {
+    fn into_response(self) -> Response {
+        record_rate_limited(self.scope.label(), self.resource.name());
+        let retry_after = self.retry_after.max(Duration::from_millis(1));
+        let mut response = AppError::RateLimited(format!(
+            "Rate limit reached for {} per minute of the {}. Please try again in {}.",
+            self.resource.name(),
+            self.scope.describe(),
+            format_reset(retry_after)
+        ))
+        .into_response();
+        let headers = response.headers_mut();
+        self.quotas.insert_headers(headers);
+        headers.insert(
+            "retry-after",
+            (retry_after.as_secs_f64().ceil() as u64).into(),
+        );
+        headers.insert("retry-after-ms", (retry_after.as_millis() as u64).into());
+        response
+    }
+}
+
+/// The rate limits of the API keys and of the models.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    model_routes: ModelRoutes,
+    /// Largest request body read to find the requested model, the one of the `Json` extractor.
+    body_limit: usize,
+    buckets: Arc<Mutex<HashMap<Scope, Buckets>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(model_routes: ModelRoutes, body_limit: usize) -> Self {
+        Self {
+            model_routes,
+            body_limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Take a request from the buckets of the scopes, unless a bucket is exhausted. Token buckets
+    /// only need to hold a token, since the tokens are charged once generated.
+    fn acquire(&self, scopes: &[(Scope, RateLimits)]) -> Result<(TokenCharge, Quotas), Limited> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut limited: Option<(Scope, Resource, Duration)> = None;
+        for (scope, limits) in scopes {
+            let scope_buckets = buckets.entry(scope.clone()).or_insert(Buckets {
+                requests: None,
+                tokens: None,
+            });
+            for (resource, limit, bucket) in [
+                (
+                    Resource::Requests,
+                    limits.requests_per_minute,
+                    &mut scope_buckets.requests,
+                ),
+                (
+                    Resource::Tokens,
+                    limits.tokens_per_minute,
+                    &mut scope_buckets.tokens,
+                ),
+            ] {
+                let Some(limit) = limit else {
+                    *bucket = None;
+                    continue;
+                };
+                let bucket = bucket.get_or_insert_with(|| Bucket::new(limit, now));
+                bucket.refill(limit, now);
+                let wait = bucket.wait(limit, 1.0);
+                let longest = match &limited {
+                    Some((_, _, longest)) => wait > *longest,
+                    None => true,
+                };
+                if !wait.is_zero() && longest {
+                    limited = Some((scope.clone(), resource, wait));
+                }
+            }
+        }
+
+        let mut quotas = Quotas::default();
+        for (scope, limits) in scopes {
+            let scope_buckets = buckets.get_mut(scope).unwrap();
+            if let (Some(limit), Some(bucket)) =
+                (limits.requests_per_minute, scope_buckets.requests.as_mut())
+            {
+                if limited.is_none() {
+                    bucket.level -= 1.0;
+                }
+                quotas.update(Resource::Requests, limit, bucket);
+            }
+            if let (Some(limit), Some(bucket)) =
+                (limits.tokens_per_minute, scope_buckets.tokens.as_ref())
+            {
+                quotas.update(Resource::Tokens, limit, bucket);
+            }
+        }
+
+        match limited {
+            Some((scope, resource, retry_after)) => Err(Limited {
+                scope,
+                resource,
+                retry_after,
+                quotas,
+            }),
+            None => {
+                let scopes = scopes
+                    .iter()
+                    .filter(|(_, limits)| limits.tokens_per_minute.is_some())
+                    .map(|(scope, _)| scope.clone())
+                    .collect();
+                let charge = TokenCharge(Some(Arc::new((self.clone(), scopes))));
+                Ok((charge, quotas))
+            }
+        }
+    }
+
+    fn charge(&self, scopes: &[Scope], tokens: usize) {
+        let mut buckets = self.buckets.lock().unwrap();
+        for scope in scopes {
+            if let Some(bucket) = buckets
+                .get_mut(scope)
+                .and_then(|buckets| buckets.tokens.as_mut())
+            {
+                bucket.level -= tokens as f64;
+            }
+        }
+    }
+}
+
+/// The token buckets to charge with the tokens generated for a request.
+#[derive(Clone, Default)]
+pub(crate) struct TokenCharge(Option<Arc<(RateLimiter, Vec<Scope>)>>);
+
+impl TokenCharge {
+    pub(crate) fn charge(&self, tokens: usize) {
+        if let Some(charge) = &self.0 {
+            let (limiter, scopes) = charge.as_ref();
+            limiter.charge(scopes, tokens);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RequestModel {
+    model: Option<String>,
+}
+
+/// Rate limit the generation requests of the API key of the request and of the requested model.
+/// Every request gets a [`TokenCharge`] extension, for the routes to charge the generated tokens.
+pub(crate) async fn rate_limit_middleware(
+    mut req: Request<Body>,
+    next: Next,
+    limiter: RateLimiter,
+) -> Response {
+    if req.method() != Method::POST {
+        req.extensions_mut().insert(TokenCharge::default());
+        return next.run(req).await;
+    }
+
+    let mut scopes = Vec::new();
+    if let Some(api_key) = req.extensions().get::<Arc<ApiKey>>() {
+        if api_key.rate_limits.is_limited() {
+            scopes.push((Scope::ApiKey(api_key.name.clone()), api_key.rate_limits));
+        }
+    }
+    if limiter.model_routes.is_rate_limited() {
+        let (parts, body) = req.into_parts();
+        let bytes = match to_bytes(body, limiter.body_limit).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                return AppError::invalid_request(
+                    format!("Failed to read the request body: {}", err),
+                    None,
+                )
+                .into_response()
+            }
+        };
+        if let Ok(RequestModel { model: Some(model) }) = serde_json::from_slice(&bytes) {
+            let route = limiter.model_routes.resolve(&model);
+            if route.rate_limits.is_limited() {
+                scopes.push((Scope::Model(route.id.clone()), route.rate_limits));
+            }
+        }
+        req = Request::from_parts(parts, Body::from(bytes));
+    }
+    if scopes.is_empty() {
+        req.extensions_mut().insert(TokenCharge::default());
+        return next.run(req).await;
+    }
+
+    match limiter.acquire(&scopes) {
+        Ok((charge, quotas)) => {
+            req.extensions_mut().insert(charge);
+            let mut response = next.run(req).await;
+            quotas.insert_headers(response.headers_mut());
+            response
+        }
+        Err(limited) => limited.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(requests_per_minute: Option<u32>, tokens_per_minute: Option<u32>) -> RateLimits {
+        RateLimits {
+            requests_per_minute,
+            tokens_per_minute,
+        }
+    }
+
+    #[test]
+    pub fn test_requests_per_minute() {
+        let limiter = RateLimiter::new(ModelRoutes::default(), 1024);
+        let scopes = [
+            (Scope::ApiKey("team-a".to_string()), limits(Some(2), None)),
+            (Scope::Model("llama".to_string()), limits(Some(60), None)),
+        ];
+        for remaining in [1, 0] {
+            let (_, quotas) = limiter.acquire(&scopes).unwrap();
+            let (resource, quota) = quotas.0[0];
+            assert_eq!(Resource::Requests, resource);
+            assert_eq!(2, quota.limit);
+            assert_eq!(remaining, quota.remaining);
+        }
+
+        let Err(limited) = limiter.acquire(&scopes) else {
+            panic!("the request is not rate limited");
+        };
+        assert_eq!(Scope::ApiKey("team-a".to_string()), limited.scope);
+        assert_eq!(Resource::Requests, limited.resource);
+        assert!(limited.retry_after > Duration::from_secs(29));
+        assert!(limited.retry_after <= Duration::from_secs(30));
+        // A rejected request does not take from the other buckets.
+        let model = [(Scope::Model("llama".to_string()), limits(Some(60), None))];
+        let (_, quotas) = limiter.acquire(&model).unwrap();
+        assert_eq!(57, quotas.0[0].1.remaining);
+    }
+
+    #[test]
+    pub fn test_tokens_per_minute() {
+        let limiter = RateLimiter::new(ModelRoutes::default(), 1024);
+        let scopes = [(Scope::ApiKey("team-a".to_string()), limits(None, Some(100)))];
+        let (charge, _) = limiter.acquire(&scopes).unwrap();
+        charge.charge(150);
+
+        let Err(limited) = limiter.acquire(&scopes) else {
+            panic!("the request is not rate limited");
+        };
+        assert_eq!(Resource::Tokens, limited.resource);
+        assert!(limited.retry_after > Duration::from_secs(30));
+        assert_eq!(0, limited.quotas.0[0].1.remaining);
+    }
+
+    #[test]
+    pub fn test_limits_of_zero() {
+        assert_eq!(
+            limits(Some(60), None),
+            RateLimits::new(Some(60), None).unwrap()
+        );
+        let error = RateLimits::new(Some(0), None).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("requests_per_minute must be greater than 0"));
+        let error = RateLimits::new(None, Some(0)).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("tokens_per_minute must be greater than 0"));
+    }
+
+    #[test]
+    pub fn test_format_reset() {
+        assert_eq!("20ms", format_reset(Duration::from_millis(20)));
+        assert_eq!("2s", format_reset(Duration::from_millis(1500)));
+        assert_eq!("6m0s", format_reset(Duration::from_secs(360)));
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use metrics::{counter, gauge, histogram};
+
+use crate::admission::AdmissionPermit;
+use crate::error::AppError;
+use crate::rate_limit::TokenCharge;
+
+/// Measures a generation request, from the moment it is received until its response is
+/// completed. The request is recorded when the last clone is dropped, if no status is set by then
+/// the client disconnected and the request is reported with status 499. The generated tokens are
+/// then charged to the rate limits of the request.
+#[derive(Clone)]
+pub(crate) struct RequestMetrics(Arc<Mutex<RequestState>>);
+
+struct RequestState {
+    route: &'static str,
+    model: String,
+    start: Instant,
+    first_token: Option<Instant>,
+    /// Time of the last token of each choice.
+    last_tokens: Vec<Option<Instant>>,
+    completion_tokens: Option<usize>,
+    /// Number of responses of triton, which count the tokens without tokenizer.
+    responses: usize,
+    status: Option<StatusCode>,
+    token_charge: TokenCharge,
+    /// The slot of the inference, released with the request.
+    permit: Option<AdmissionPermit>,
+}
+
+impl RequestMetrics {
+    /// Start measuring a request, with the model label given by `ModelRoutes::metric_label`.
+    pub(crate) fn new(route: &'static str, model: &str) -> Self {
+        gauge!(
+            "openai_trtllm_requests_in_flight",
+            "route" => route,
+            "model" => model.to_string()
+        )
+        .increment(1);
+
+        Self(Arc::new(Mutex::new(RequestState {
+            route,
+            model: model.to_string(),
+            start: Instant::now(),
+            first_token: None,
+            last_tokens: Vec::new(),
+            completion_tokens: None,
+            responses: 0,
+            status: None,
+            token_charge: TokenCharge::default(),
+            permit: None,
+        })))
+    }
+
+    /// Charge the generated tokens to the rate limits of the request.
+    pub(crate) fn charging(self, token_charge: TokenCharge) -> Self {
+        self.0.lock().unwrap().token_charge = token_charge;
+        self
+    }
+
+    /// Hold the slot of the inference until the request is completed.
+    pub(crate) fn hold(&self, permit: Option<AdmissionPermit>) {
+        self.0.lock().unwrap().permit = permit;
+    }
+
+    /// Record the generation of new tokens for the choice at the given index.
+    pub(crate) fn tokens(&self, index: usize) {
+        let now = Instant::now();
+        let mut state = self.0.lock().unwrap();
+        let labels = [
+            ("route", state.route.to_string()),
+            ("model", state.model.clone()),
+        ];
+
+        state.responses += 1;
+        if state.first_token.is_none() {
+            state.first_token = Some(now);
+            histogram!("openai_trtllm_time_to_first_token_seconds", &labels)
+                .record(now.duration_since(state.start));
+        }
+        if state.last_tokens.len() <= index {
+            state.last_tokens.resize(index + 1, None);
+        }
+        if let Some(last_token) = state.last_tokens[index].replace(now) {
+            histogram!("openai_trtllm_inter_token_latency_seconds", &labels)
+                .record(now.duration_since(last_token));
+        }
+    }
+
+    /// Mark the request as successfully completed, with the number of generated tokens if they
+    /// could be counted.
+    pub(crate) fn finish(&self, completion_tokens: Option<usize>) {
+        let mut state = self.0.lock().unwrap();
+        state.status = Some(StatusCode::OK);
+        state.completion_tokens = completion_tokens;
+    }
+
+    /// Mark the request as failed.
+    pub(crate) fn error(&self, error: &AppError) {
+        let mut state = self.0.lock().unwrap();
+        state.status = Some(error.status_code());
+    }
+}
+
+impl Drop for RequestState {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+        let labels = [
+            ("route", self.route.to_string()),
+            ("model", self.model.clone()),
+        ];
+
+        gauge!("openai_trtllm_requests_in_flight", &labels).decrement(1);
+        self.token_charge
+            .charge(self.completion_tokens.unwrap_or(self.responses));
+        let status = self.status.map_or(499, |status| status.as_u16());
+        counter!(
+            "openai_trtllm_requests_total",
+            "route" => self.route,
+            "model" => self.model.clone(),
+            "status" => status.to_string()
+        )
+        .increment(1);
+        histogram!("openai_trtllm_request_duration_seconds", &labels).record(duration);
+
+        if let Some(completion_tokens) = self.completion_tokens {
+            counter!("openai_trtllm_generation_tokens_total", &labels)
+                .increment(completion_tokens as u64);
+            // The throughput of the generation, without the time spent on the prompt.
+            let generation = self
+                .first_token
+                .map_or(Duration::ZERO, |first_token| first_token.elapsed());
+            if !generation.is_zero() {
+                histogram!("openai_trtllm_generation_tokens_per_second", &labels)
+                    .record(completion_tokens as f64 / generation.as_secs_f64());
+            }
+        }
+    }
+}
+
+/// Record an error returned by triton, either as gRPC status or in a response.
+pub(crate) fn record_triton_error(model: &str, code: &str) {
+    counter!(
+        "openai_trtllm_triton_errors_total",
+        "model" => model.to_string(),
+        "code" => code.to_string()
+    )
+    .increment(1);
+}
+
+/// Record an inference cancelled before it completed because the client disconnected. Inferences
+/// stopped on a stop sequence are not counted.
+pub(crate) fn record_cancelled(model: &str) {
+    counter!(
+        "openai_trtllm_cancelled_requests_total",
+        "model" => model.to_string()
+    )
+    .increment(1);
+}
+
+/// Record a request rejected by the rate limit of an API key or a model.
+pub(crate) fn record_rate_limited(scope: &'static str, resource: &'static str) {
+    counter!(
+        "openai_trtllm_rate_limited_requests_total",
+        "scope" => scope,
+        "resource" => resource
+    )
+    .increment(1);
+}
+
+/// Record the number of requests waiting for a model.
+pub(crate) fn record_queue_depth(model: &str, depth: usize) {
+    gauge!(
+        "openai_trtllm_queue_depth",
+        "model" => model.to_string()
+    )
+    .set(depth as f64);
+}
+
+/// Record the time a request waited for a model, whether it was admitted or not.
+pub(crate) fn record_queue_wait(model: &str, wait: Duration) {
+    histogram!(
+        "openai_trtllm_queue_wait_seconds",
+        "model" => model.to_string()
+    )
+    .record(wait);
+}
+
+/// Record whether a triton endpoint receives inferences, or is ejected.
+pub(crate) fn record_replica_health(endpoint: &str, healthy: bool) {
+    gauge!(
+        "openai_trtllm_triton_endpoint_healthy",
+        "endpoint" => endpoint.to_string()
+    )
+    .set(if healthy { 1.0 } else { 0.0 });
+}
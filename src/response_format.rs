@@ -0,0 +1,160 @@
+//! Constrain the model output to the JSON format requested with `response_format`.
+//!
+//! The model is instructed to answer with JSON through the history template, and its output is
+//! validated once complete. Models of the tensorrtllm_backend which declare the guided decoding
+//! inputs receive the schema instead, so that TensorRT-LLM only samples valid JSON.
+use jsonschema::JSONSchema;
+use serde_json::Value;
+
+use crate::error::AppError;
+use crate::routes::chat::ResponseFormat;
+
+/// Inputs of the tensorrtllm_backend for guided decoding.
+pub(crate) const GUIDE_TYPE_INPUT: &str = "guided_decoding_guide_type";
+pub(crate) const GUIDE_INPUT: &str = "guided_decoding_guide";
+
+/// A JSON format the model output must follow.
+pub(crate) struct JsonFormat {
+    schema: Option<Schema>,
+}
+
+struct Schema {
+    value: Value,
+    compiled: JSONSchema,
+    /// Whether the output must match the schema, rather than only be a JSON object.
+    strict: bool,
+}
+
+impl JsonFormat {
+    /// Get the JSON format requested by the client, if any.
+    pub(crate) fn new(response_format: Option<&ResponseFormat>) -> Result<Option<Self>, AppError> {
+        let schema = match response_format {
+            None | Some(ResponseFormat::Text) => return Ok(None),
+            Some(ResponseFormat::JsonObject) => None,
+            Some(ResponseFormat::JsonSchema { json_schema }) => match &json_schema.schema {
+                None => None,
+                Some(value) => {
+                    let compiled = JSONSchema::compile(value).map_err(|err| {
+                        AppError::invalid_request(
+                            format!("invalid JSON schema {}: {}", json_schema.name, err),
+                            Some("response_format"),
+                        )
+                    })?;
+                    Some(Schema {
+                        value: value.clone(),
+                        compiled,
+                        strict: json_schema.strict.unwrap_or(false),
+                    })
+                }
+            },
+        };
+        Ok(Some(Self { schema }))
+    }
+
+    /// Instructions added to the prompt, for models without guided decoding.
+    pub(crate) fn instructions(&self) -> String {
+        let mut instructions =
+            "Respond with a single valid JSON object and nothing else.".to_string();
+        if let Some(schema) = &self.schema {
+            instructions.push_str(" The object must follow this JSON schema:\n");
+            instructions.push_str(&schema.value.to_string());
+        }
+        instructions
+    }
+
+    /// The guide type and guide of TensorRT-LLM guided decoding.
+    pub(crate) fn guide(&self) -> (&'static str, String) {
+        match &self.schema {
+            Some(schema) => ("json_schema", schema.value.to_string()),
+            None => ("json", String::new()),
+        }
+    }
+
+    /// Check that a complete output follows the format, otherwise return the reason why not.
+    pub(crate) fn validate(&self, output: &str) -> Result<(), String> {
+        let value: Value = serde_json::from_str(output.trim())
+            .map_err(|err| format!("the output is not valid JSON: {}", err))?;
+        if !value.is_object() {
+            return Err("the output is not a JSON object".to_string());
+        }
+
+        match &self.schema {
+            Some(schema) if schema.strict => schema.compiled.validate(&value).map_err(|errors| {
+                let errors: Vec<_> = errors
+                    .map(|err| format!("{} at '{}'", err, err.instance_path))
+                    .collect();
+                format!(
+                    "the output does not match the schema: {}",
+                    errors.join(", ")
+                )
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::routes::chat::JsonSchemaFormat;
+
+    fn json_schema(strict: bool) -> ResponseFormat {
+        ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat {
+                name: "weather".to_string(),
+                description: None,
+                schema: Some(json!({
+                    "type": "object",
+                    "properties": {"city": {"type": "string"}},
+                    "required": ["city"],
+                })),
+                strict: Some(strict),
+            },
+        }
+    }
+
+    #[test]
+    pub fn test_validate() {
+        assert!(JsonFormat::new(Some(&ResponseFormat::Text))
+            .unwrap()
+            .is_none());
+
+        let json_object = JsonFormat::new(Some(&ResponseFormat::JsonObject))
+            .unwrap()
+            .unwrap();
+        assert!(json_object.validate(" {\"answer\": 42}\n").is_ok());
+        assert!(json_object.validate("[1, 2]").is_err());
+        assert!(json_object
+            .validate("The answer is {\"answer\": 42}")
+            .is_err());
+        assert_eq!(("json", String::new()), json_object.guide());
+
+        let strict = JsonFormat::new(Some(&json_schema(true))).unwrap().unwrap();
+        assert!(strict.validate("{\"city\": \"Paris\"}").is_ok());
+        let error = strict.validate("{\"town\": \"Paris\"}").unwrap_err();
+        assert!(error.contains("\"city\" is a required property"));
+        assert!(strict.instructions().contains("\"required\":[\"city\"]"));
+
+        // The schema is only a hint without strict.
+        let hint = JsonFormat::new(Some(&json_schema(false))).unwrap().unwrap();
+        assert!(hint.validate("{\"town\": \"Paris\"}").is_ok());
+    }
+
+    #[test]
+    pub fn test_invalid_schema() {
+        let response_format = ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat {
+                name: "broken".to_string(),
+                description: None,
+                schema: Some(json!({"type": "no-such-type"})),
+                strict: None,
+            },
+        };
+        assert!(matches!(
+            JsonFormat::new(Some(&response_format)),
+            Err(AppError::InvalidRequest { .. })
+        ));
+    }
+}
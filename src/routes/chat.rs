@@ -1,191 +1,504 @@
 //! https://platform.openai.com/docs/api-reference/chat/create
-use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
 use std::iter::IntoIterator;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
-use async_stream::{stream, try_stream};
+use async_stream::try_stream;
+use axum::extract::rejection::JsonRejection;
 use axum::extract::State;
 use axum::http::HeaderMap;
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::{Extension, Json};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tonic::codegen::tokio_stream::Stream;
+use serde_json::Value;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
 use tonic::transport::Channel;
 use tracing;
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::error::AppError;
-use crate::history::HistoryBuilder;
+use crate::auth::ApiKey;
+use crate::error::{error_events, AppError};
+use crate::finish_reason::{generated_tokens, reached_max_tokens};
+use crate::images::take_images;
+use crate::logprobs::{check_top_logprobs, take_tokens, token_logprobs, TokenLogprob};
+use crate::model_routes::ModelRoute;
+use crate::rate_limit::TokenCharge;
+use crate::request_metrics::RequestMetrics;
+use crate::response_format::{JsonFormat, GUIDE_INPUT, GUIDE_TYPE_INPUT};
+use crate::routes::sampling::SamplingParams;
 use crate::state::AppState;
+use crate::stop_sequences::StopBuffer;
+use crate::tokenizer::Tokenizer;
+use crate::tools::{parse_tool_calls, ParsedToolCall, ToolCallStream};
+use crate::triton::cancellation::new_request_id;
 use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::triton::infer::{random_seed, sampling_seeds, stream_infer, InferEvent};
+use crate::triton::metadata::ModelInputs;
+use crate::triton::pool::TritonPool;
 use crate::triton::request::{Builder, InferTensorData};
-use crate::triton::telemetry::propagate_context;
 use crate::triton::ModelInferRequest;
-use crate::utils::deserialize_bytes_tensor;
 
-#[instrument(name = "chat_completions", skip(grpc_client, history_builder, request))]
+/// Upper bound of `n`, since every choice runs a separate inference.
+const MAX_N: usize = 128;
+
+const ROUTE: &str = "/v1/chat/completions";
+
+#[instrument(name = "chat_completions", skip(state, api_key, token_charge, request))]
 pub(crate) async fn compat_chat_completions(
     headers: HeaderMap,
-    State(AppState {
-        grpc_client,
-        history_builder,
-    }): State<AppState>,
-    request: Json<ChatCompletionCreateParams>,
+    State(state): State<AppState>,
+    Extension(api_key): Extension<Arc<ApiKey>>,
+    Extension(token_charge): Extension<TokenCharge>,
+    request: Result<Json<Value>, JsonRejection>,
 ) -> Response {
+    let request = request
+        .map_err(AppError::from)
+        .and_then(|Json(body)| {
+            state
+                .model_routes
+                .parse_request::<ChatCompletionCreateParams>(body)
+        })
+        .and_then(|request| {
+            api_key.authorize(&request.model, &state.model_routes)?;
+            Ok(request)
+        });
+    let request = match request {
+        Ok(request) => Json(request),
+        Err(err) => {
+            RequestMetrics::new(ROUTE, "unknown").error(&err);
+            return err.into_response();
+        }
+    };
     tracing::info!("request: {:?}", request);
 
-    if request.stream {
-        chat_completions_stream(headers, grpc_client, history_builder, request)
-            .await
-            .into_response()
-    } else {
-        chat_completions(headers, grpc_client, history_builder, request)
-            .await
-            .into_response()
-    }
+    let metrics = RequestMetrics::new(
+        ROUTE,
+        &state
+            .model_routes
+            .metric_label(&request.model, &state.triton),
+    )
+    .charging(token_charge);
+    let response = match state.admission.admit(&request.model, request.n).await {
+        Ok(permit) => {
+            metrics.hold(permit);
+            if request.stream {
+                chat_completions_stream(headers, state, metrics.clone(), request)
+                    .await
+                    .map(IntoResponse::into_response)
+            } else {
+                chat_completions(headers, state, metrics.clone(), request)
+                    .await
+                    .map(IntoResponse::into_response)
+            }
+        }
+        Err(err) => Err(err),
+    };
+    response.unwrap_or_else(|err| {
+        metrics.error(&err);
+        err.into_response()
+    })
 }
 
-#[instrument(
-    name = "streaming chat completions",
-    skip(client, history_builder, request)
-)]
+#[instrument(name = "streaming chat completions", skip(state, metrics, request))]
 async fn chat_completions_stream(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    history_builder: HistoryBuilder,
-    Json(request): Json<ChatCompletionCreateParams>,
-) -> Result<Sse<impl Stream<Item = anyhow::Result<Event>>>, AppError> {
+    state: AppState,
+    metrics: RequestMetrics,
+    Json(mut request): Json<ChatCompletionCreateParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
     let id = format!("cmpl-{}", Uuid::new_v4());
     let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
+    let AppState {
+        triton,
+        history_builders,
+        model_routes,
+        tokenizers,
+        ..
+    } = state;
+    let route = model_routes.resolve(&request.model);
+    let triton = route.triton.clone().unwrap_or(triton);
+    let mut client = triton.client();
+    let model_inputs = triton.model_inputs();
+
+    request.validate()?;
+    let images = take_images(&mut request.messages, &route)?;
+    if let (Some(image_input), false) = (&route.image_input, images.is_empty()) {
+        model_inputs
+            .require(
+                &mut client,
+                &route.triton_model,
+                &route.model_version,
+                image_input,
+            )
+            .await?;
+    }
     let model_name = request.model.clone();
-    let request = build_triton_request(request, &history_builder)?;
+    let tokenizer = tokenizers.get(&route.triton_model);
+    request.sampling.resolve_logit_bias(tokenizer.as_ref())?;
+    let history_builder = history_builders.get(&route.id, &route.triton_model)?;
+    let include_usage = request
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.include_usage);
+    let tools = Tools::from_request(&request)?;
+    let json_format = JsonFormat::new(request.response_format.as_ref())?;
+    let format_instructions = format_instructions(
+        &mut client,
+        model_inputs,
+        &route.triton_model,
+        &route.model_version,
+        json_format.as_ref(),
+    )
+    .await?;
+    let prompt = history_builder.build_history_with_format(
+        &request.messages,
+        &tools.functions,
+        tools.required,
+        format_instructions.as_deref(),
+    )?;
+    let mut requests =
+        build_triton_requests(&request, &prompt, &images, json_format.as_ref(), &route)?;
+    model_inputs
+        .retain_declared(&mut client, &mut requests)
+        .await?;
+    let mut events = stream_infer(&triton, &headers, requests);
+    events.started().await?;
 
+    let error_metrics = metrics.clone();
     let response_stream = try_stream! {
-        let request = stream! { yield request };
-        let mut request = tonic::Request::new(request);
-
-        propagate_context(&mut request, &headers);
-
-        let mut stream = client
-            .model_stream_infer(request)
-            .await
-            .context("failed to call triton grpc method model_stream_infer")?
-            .into_inner();
-
-        while let Some(response) = stream.message().await? {
-            if !response.error_message.is_empty() {
-                tracing::error!("received error message from triton: {}", response.error_message);
-
-                // Corresponds to https://github.com/openai/openai-python/blob/17ac6779958b2b74999c634c4ea4c7b74906027a/src/openai/_streaming.py#L113
-                yield Event::default().event("error").json_data(json!({
-                    "error": {
-                        "status_code": 500,
-                        "message": "Internal Server Error"
+        let mut tool_call_streams: Vec<_> = (0..request.n)
+            .map(|_| {
+                tools
+                    .is_enabled()
+                    .then(|| ToolCallStream::new(tools.names(), tools.required))
+            })
+            .collect();
+        let mut completions = vec![String::new(); request.n];
+        let mut stop_buffers = vec![StopBuffer::new(request.sampling.stop_sequences()); request.n];
+        // Log probabilities of the tokens held back by the stop buffers, then by the tool calls.
+        let mut unchecked_logprobs = vec![Vec::new(); request.n];
+        let mut pending_logprobs = vec![Vec::new(); request.n];
+        let mut responses = vec![0; request.n];
+
+        while let Some((index, event)) = events.next().await.transpose()? {
+            let choice = match event {
+                InferEvent::Response(response) => {
+                    // Counted before the output is held back by the stop and tool call buffers.
+                    metrics.tokens(index);
+                    responses[index] += 1;
+                    let text = response.text_output()?;
+                    if request.logprobs {
+                        unchecked_logprobs[index].extend(token_logprobs(
+                            &text,
+                            &response.output_log_probs(),
+                            tokenizer.as_ref(),
+                        )?);
                     }
-                })).unwrap();
-                return;
-            }
-            let infer_response = response
-                .infer_response
-                .context("empty infer response received")?;
-            tracing::debug!("triton infer response: {:?}", infer_response);
-
-            let raw_content = infer_response.raw_output_contents[0].clone();
-            let content = deserialize_bytes_tensor(raw_content)?.into_iter().collect::<String>();
-            tracing::debug!("deserialized triton infer response content: {:?}", content);
-
-            if !content.is_empty() {
-                let response = ChatCompletionChunk {
-                    id: id.clone(),
-                    object: "text_completion".to_string(),
-                    created,
-                    model: model_name.clone(),
-                    system_fingerprint: None,
-                    choices: vec![ChatCompletionChunkChoice {
-                        index: 0,
+                    let content = stop_buffers[index].push(&text);
+                    if stop_buffers[index].is_stopped() {
+                        events.stop(index);
+                    }
+                    completions[index].push_str(&content);
+                    pending_logprobs[index]
+                        .extend(take_tokens(&mut unchecked_logprobs[index], content.len()));
+
+                    let content = match tool_call_streams[index].as_mut() {
+                        Some(tool_call_stream) => tool_call_stream.push(&content),
+                        None => content,
+                    };
+                    if content.is_empty() {
+                        continue;
+                    }
+                    ChatCompletionChunkChoice {
+                        index,
                         delta: ChatCompletionChunkDelta {
                             role: Some(Role::Assistant),
                             content: Some(content),
+                            tool_calls: None,
                         },
+                        logprobs: request.logprobs_of(std::mem::take(&mut pending_logprobs[index])),
                         finish_reason: None,
-                    }],
-                };
-                yield Event::default().json_data(response).unwrap();
-            }
+                    }
+                }
+                InferEvent::Completed => {
+                    // Text held back which turned out not to be a stop sequence.
+                    let mut content = stop_buffers[index].finish();
+                    completions[index].push_str(&content);
+                    pending_logprobs[index]
+                        .extend(take_tokens(&mut unchecked_logprobs[index], content.len()));
+
+                    // The output was already streamed, so it cannot be generated again.
+                    if let Some(json_format) = &json_format {
+                        check_output(json_format, &tools, &completions[index])
+                            .map_err(|reason| AppError::invalid_output("response_format", reason))?;
+                    }
+                    let mut finish_reason = request.finish_reason(
+                        &completions[index],
+                        responses[index],
+                        stop_buffers[index].is_stopped(),
+                        tokenizer.as_ref(),
+                    )?;
+                    let mut tool_calls = None;
+                    if let Some(mut tool_call_stream) = tool_call_streams[index].take() {
+                        content = tool_call_stream.push(&content);
+                        let (rest, parsed) = tool_call_stream.finish();
+                        tools.check_forced(parsed.as_deref())?;
+                        content.push_str(&rest);
+                        tool_calls = parsed.map(|tool_calls| {
+                            tool_calls
+                                .into_iter()
+                                .enumerate()
+                                .map(|(index, tool_call)| ChatCompletionChunkToolCall {
+                                    index,
+                                    tool_call: tool_call.into(),
+                                })
+                                .collect::<Vec<_>>()
+                        });
+                        if tool_calls.is_some() {
+                            finish_reason = FinishReason::ToolCalls;
+                        }
+                    }
+                    if !content.is_empty() || tool_calls.is_some() {
+                        let response = ChatCompletionChunk {
+                            id: id.clone(),
+                            object: "text_completion".to_string(),
+                            created,
+                            model: model_name.clone(),
+                            system_fingerprint: None,
+                            choices: vec![ChatCompletionChunkChoice {
+                                index,
+                                delta: ChatCompletionChunkDelta {
+                                    role: Some(Role::Assistant),
+                                    content: (!content.is_empty()).then_some(content),
+                                    tool_calls,
+                                },
+                                logprobs: request
+                                    .logprobs_of(std::mem::take(&mut pending_logprobs[index])),
+                                finish_reason: None,
+                            }],
+                            usage: None,
+                        };
+                        yield Event::default().json_data(response).unwrap();
+                    }
+
+                    ChatCompletionChunkChoice {
+                        index,
+                        delta: ChatCompletionChunkDelta {
+                            role: None,
+                            content: None,
+                            tool_calls: None,
+                        },
+                        logprobs: None,
+                        finish_reason: Some(finish_reason),
+                    }
+                }
+            };
+
+            let response = ChatCompletionChunk {
+                id: id.clone(),
+                object: "text_completion".to_string(),
+                created,
+                model: model_name.clone(),
+                system_fingerprint: None,
+                choices: vec![choice],
+                usage: None,
+            };
+            yield Event::default().json_data(response).unwrap();
+        }
+
+        let usage = Usage::count(tokenizer.as_ref(), &prompt, &completions)?;
+        metrics.finish(tokenizer.is_some().then_some(usage.completion_tokens));
+        if include_usage {
+            let response = ChatCompletionChunk {
+                id,
+                object: "text_completion".to_string(),
+                created,
+                model: model_name,
+                system_fingerprint: None,
+                choices: vec![],
+                usage: Some(usage),
+            };
+            yield Event::default().json_data(response).unwrap();
         }
-        let response = ChatCompletionChunk {
-            id,
-            object: "text_completion".to_string(),
-            created,
-            model: model_name,
-            system_fingerprint: None,
-            choices: vec![ChatCompletionChunkChoice {
-                index: 0,
-                delta: ChatCompletionChunkDelta {
-                    role: None,
-                    content: None,
-                },
-                finish_reason: Some(FinishReason::Stop),
-            }],
-        };
-        yield Event::default().json_data(response).unwrap();
 
         // OpenAI stream response terminated by a data: [DONE] message.
         yield Event::default().data("[DONE]");
     };
 
-    Ok(Sse::new(response_stream).keep_alive(KeepAlive::default()))
+    Ok(Sse::new(error_events(response_stream, error_metrics)).keep_alive(KeepAlive::default()))
 }
 
 #[instrument(
     name = "non-streaming chat completions",
-    skip(client, history_builder, request),
+    skip(state, metrics, request),
     err(Debug)
 )]
 async fn chat_completions(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    history_builder: HistoryBuilder,
-    Json(request): Json<ChatCompletionCreateParams>,
+    state: AppState,
+    metrics: RequestMetrics,
+    Json(mut request): Json<ChatCompletionCreateParams>,
 ) -> Result<Json<ChatCompletion>, AppError> {
-    let model_name = request.model.clone();
+    let AppState {
+        triton,
+        history_builders,
+        model_routes,
+        tokenizers,
+        response_format_retries,
+        ..
+    } = state;
+    let route = model_routes.resolve(&request.model);
+    let triton = route.triton.clone().unwrap_or(triton);
+    let mut client = triton.client();
+    let model_inputs = triton.model_inputs();
 
-    let request = build_triton_request(request, &history_builder)?;
-    let request = stream! { yield request };
-    let mut request = tonic::Request::new(request);
+    request.validate()?;
+    let images = take_images(&mut request.messages, &route)?;
+    if let (Some(image_input), false) = (&route.image_input, images.is_empty()) {
+        model_inputs
+            .require(
+                &mut client,
+                &route.triton_model,
+                &route.model_version,
+                image_input,
+            )
+            .await?;
+    }
+    let model_name = request.model.clone();
+    let tokenizer = tokenizers.get(&route.triton_model);
+    request.sampling.resolve_logit_bias(tokenizer.as_ref())?;
+    let history_builder = history_builders.get(&route.id, &route.triton_model)?;
+    let tools = Tools::from_request(&request)?;
+    let json_format = JsonFormat::new(request.response_format.as_ref())?;
+    let format_instructions = format_instructions(
+        &mut client,
+        model_inputs,
+        &route.triton_model,
+        &route.model_version,
+        json_format.as_ref(),
+    )
+    .await?;
+    let prompt = history_builder.build_history_with_format(
+        &request.messages,
+        &tools.functions,
+        tools.required,
+        format_instructions.as_deref(),
+    )?;
 
-    propagate_context(&mut request, &headers);
+    let mut requests =
+        build_triton_requests(&request, &prompt, &images, json_format.as_ref(), &route)?;
+    model_inputs
+        .retain_declared(&mut client, &mut requests)
+        .await?;
+    let indices: Vec<_> = (0..request.n).collect();
+    let mut completions = generate(
+        &triton,
+        &headers,
+        requests,
+        &indices,
+        &request,
+        tokenizer.as_ref(),
+        &metrics,
+    )
+    .await?;
 
-    let mut stream = client
-        .model_stream_infer(request)
-        .await
-        .context("failed to call triton grpc method model_stream_infer")?
-        .into_inner();
+    // The outputs generated again are counted in the usage and charged as well.
+    let mut discarded = Vec::new();
+    if let Some(json_format) = &json_format {
+        for _ in 0..response_format_retries {
+            let invalid: Vec<_> = (0..request.n)
+                .filter(|&index| {
+                    check_output(json_format, &tools, &completions[index].text).is_err()
+                })
+                .collect();
+            if invalid.is_empty() {
+                break;
+            }
+            tracing::info!(
+                "generating {} choices again, their output does not follow the response_format",
+                invalid.len()
+            );
 
-    let mut contents: Vec<String> = Vec::new();
-    while let Some(response) = stream.message().await? {
-        if !response.error_message.is_empty() {
-            return Err(anyhow::anyhow!(
-                "error message received from triton: {}",
-                response.error_message
+            // Sample with new seeds, the same seeds would generate the same outputs.
+            let mut requests = invalid
+                .iter()
+                .map(|_| {
+                    build_triton_request(
+                        &request,
+                        &prompt,
+                        &images,
+                        Some(random_seed()),
+                        Some(json_format),
+                        &route,
+                    )
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            model_inputs
+                .retain_declared(&mut client, &mut requests)
+                .await?;
+            let retried = generate(
+                &triton,
+                &headers,
+                requests,
+                &invalid,
+                &request,
+                tokenizer.as_ref(),
+                &metrics,
             )
-            .into());
+            .await?;
+            for (index, completion) in invalid.into_iter().zip(retried) {
+                discarded.push(std::mem::replace(&mut completions[index], completion).text);
+            }
         }
-        let infer_response = response
-            .infer_response
-            .context("empty infer response received")?;
-        tracing::debug!("triton infer response: {:?}", infer_response);
-
-        let raw_content = infer_response.raw_output_contents[0].clone();
-        let content = deserialize_bytes_tensor(raw_content)?.into_iter().collect();
-        tracing::debug!("deserialized triton infer response content: {:?}", content);
+        for completion in &completions {
+            check_output(json_format, &tools, &completion.text)
+                .map_err(|reason| AppError::invalid_output("response_format", reason))?;
+        }
+    }
 
-        contents.push(content);
+    let texts: Vec<_> = completions
+        .iter()
+        .map(|c| c.text.clone())
+        .chain(discarded)
+        .collect();
+    let usage = Usage::count(tokenizer.as_ref(), &prompt, &texts)?;
+    metrics.finish(tokenizer.is_some().then_some(usage.completion_tokens));
+    let mut choices = Vec::with_capacity(completions.len());
+    for (index, generation) in completions.into_iter().enumerate() {
+        let mut finish_reason = request.finish_reason(
+            &generation.text,
+            generation.responses,
+            generation.stopped,
+            tokenizer.as_ref(),
+        )?;
+        let parsed = tools.parse(&generation.text);
+        tools.check_forced(parsed.as_ref().map(|(_, tool_calls)| tool_calls.as_slice()))?;
+        let (content, tool_calls) = match parsed {
+            Some((content, tool_calls)) => (
+                (!content.is_empty()).then_some(content),
+                Some(tool_calls.into_iter().map(Into::into).collect()),
+            ),
+            None => (Some(generation.text), None),
+        };
+        if tool_calls.is_some() {
+            finish_reason = FinishReason::ToolCalls;
+        }
+        choices.push(ChatCompletionChoice {
+            index,
+            message: ChatCompletionMessage {
+                role: Role::Assistant,
+                content,
+                tool_calls,
+            },
+            logprobs: request.logprobs_of(generation.logprobs),
+            finish_reason: Some(finish_reason),
+        });
     }
 
     Ok(Json(ChatCompletion {
@@ -194,76 +507,131 @@ async fn chat_completions(
         created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
         model: model_name,
         system_fingerprint: None,
-        choices: vec![ChatCompletionChoice {
-            index: 0,
-            message: ChatCompletionMessage {
-                role: Role::Assistant,
-                content: Some(contents.into_iter().collect()),
-            },
-            finish_reason: Some(FinishReason::Stop),
-        }],
-        // Not supported yet, need triton to return usage stats
-        // but add a fake one to make LangChain happy
-        usage: Some(Usage {
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            total_tokens: 0,
-        }),
+        choices,
+        usage: Some(usage),
     }))
 }
 
-fn build_triton_request(
-    request: ChatCompletionCreateParams,
-    history_builder: &HistoryBuilder,
-) -> anyhow::Result<ModelInferRequest> {
-    let chat_history = history_builder.build_history(&request.messages)?;
+/// The complete output of a choice.
+#[derive(Clone, Default)]
+struct Generation {
+    text: String,
+    /// The log probabilities of the tokens, if requested with `logprobs`.
+    logprobs: Vec<TokenLogprob>,
+    /// The number of responses received from triton.
+    responses: usize,
+    /// Whether the generation ended on a stop sequence.
+    stopped: bool,
+}
+
+/// Run the inference requests and collect the complete output of each. `indices` are the indices
+/// of the choices generated by the requests.
+async fn generate(
+    triton: &TritonPool,
+    headers: &HeaderMap,
+    requests: Vec<ModelInferRequest>,
+    indices: &[usize],
+    request: &ChatCompletionCreateParams,
+    tokenizer: Option<&Tokenizer>,
+    metrics: &RequestMetrics,
+) -> Result<Vec<Generation>, AppError> {
+    let mut events = stream_infer(triton, headers, requests);
+    let mut completions = vec![Generation::default(); indices.len()];
+    let mut stop_buffers = vec![StopBuffer::new(request.sampling.stop_sequences()); indices.len()];
+    while let Some((index, event)) = events.next().await.transpose()? {
+        if let InferEvent::Response(response) = event {
+            metrics.tokens(indices[index]);
+            let completion = &mut completions[index];
+            let text = response.text_output()?;
+            if request.logprobs {
+                completion.logprobs.extend(token_logprobs(
+                    &text,
+                    &response.output_log_probs(),
+                    tokenizer,
+                )?);
+            }
+            completion.text.push_str(&stop_buffers[index].push(&text));
+            completion.responses += 1;
+            if stop_buffers[index].is_stopped() {
+                events.stop(index);
+            }
+        }
+    }
+
+    for (completion, stop_buffer) in completions.iter_mut().zip(&mut stop_buffers) {
+        completion.text.push_str(&stop_buffer.finish());
+        completion.stopped = stop_buffer.is_stopped();
+        // Drop the log probabilities of the stop sequence.
+        completion.logprobs = take_tokens(&mut completion.logprobs, completion.text.len());
+    }
+    Ok(completions)
+}
+
+/// Instructions on the JSON format added to the prompt. Models declaring the guided decoding inputs
+/// get the format in their inputs instead.
+async fn format_instructions(
+    client: &mut GrpcInferenceServiceClient<Channel>,
+    model_inputs: &ModelInputs,
+    triton_model_name: &str,
+    model_version: &str,
+    json_format: Option<&JsonFormat>,
+) -> Result<Option<String>, AppError> {
+    let Some(json_format) = json_format else {
+        return Ok(None);
+    };
+    let inputs = model_inputs
+        .get(client, triton_model_name, model_version)
+        .await?;
+    if inputs.contains(GUIDE_TYPE_INPUT) && inputs.contains(GUIDE_INPUT) {
+        return Ok(None);
+    }
+    Ok(Some(json_format.instructions()))
+}
+
+/// Check that a complete output follows the JSON format, unless it calls tools.
+fn check_output(json_format: &JsonFormat, tools: &Tools, output: &str) -> Result<(), String> {
+    if tools.parse(output).is_some() {
+        return Ok(());
+    }
+    json_format.validate(output)
+}
+
+/// Build one triton request per choice, each sampled with a different seed.
+fn build_triton_requests(
+    request: &ChatCompletionCreateParams,
+    chat_history: &str,
+    images: &[Vec<u8>],
+    json_format: Option<&JsonFormat>,
+    route: &ModelRoute,
+) -> anyhow::Result<Vec<ModelInferRequest>> {
     tracing::debug!("chat history after formatting: {}", chat_history);
 
-    let mut builder = Builder::new()
-        .model_name(request.model)
+    sampling_seeds(request.seed, request.n)
+        .into_iter()
+        .map(|seed| build_triton_request(request, chat_history, images, seed, json_format, route))
+        .collect()
+}
+
+fn build_triton_request(
+    request: &ChatCompletionCreateParams,
+    chat_history: &str,
+    images: &[Vec<u8>],
+    seed: Option<u64>,
+    json_format: Option<&JsonFormat>,
+    route: &ModelRoute,
+) -> anyhow::Result<ModelInferRequest> {
+    let builder = Builder::new()
+        .id(new_request_id())
+        .model_name(&route.triton_model)
+        .model_version(&route.model_version)
         .input(
             "text_input",
             [1, 1],
             InferTensorData::Bytes(vec![chat_history.as_bytes().to_vec()]),
-        )
-        .input(
-            "max_tokens",
-            [1, 1],
-            InferTensorData::Int32(vec![request.max_tokens as i32]),
-        )
-        .input(
-            "bad_words",
-            [1, 1],
-            InferTensorData::Bytes(vec!["".as_bytes().to_vec()]),
-        )
-        .input(
-            "stop_words",
-            [1, request.stop.as_ref().map_or(1, |s| s.len() as i64)],
-            InferTensorData::Bytes(
-                request
-                    .stop
-                    .unwrap_or_else(|| vec!["</s>".to_string()])
-                    .into_iter()
-                    .map(|s| s.into_bytes())
-                    .collect(),
-            ),
-        )
-        .input("top_p", [1, 1], InferTensorData::FP32(vec![request.top_p]))
-        .input(
-            "temperature",
-            [1, 1],
-            InferTensorData::FP32(vec![request.temperature]),
-        )
-        .input(
-            "presence_penalty",
-            [1, 1],
-            InferTensorData::FP32(vec![request.presence_penalty]),
-        )
-        .input(
-            "beam_width",
-            [1, 1],
-            InferTensorData::Int32(vec![request.n as i32]),
-        )
+        );
+    let mut builder = request
+        .sampling
+        .inputs(builder)
         .input(
             "stream",
             [1, 1],
@@ -271,17 +639,137 @@ fn build_triton_request(
         )
         .output("text_output");
 
-    if request.seed.is_some() {
+    if let Some(seed) = seed {
+        builder = builder.input("random_seed", [1, 1], InferTensorData::UInt64(vec![seed]));
+    }
+    if request.logprobs {
+        builder = builder
+            .input(
+                "return_log_probs",
+                [1, 1],
+                InferTensorData::Bool(vec![true]),
+            )
+            .output("output_log_probs");
+    }
+    if let (Some(image_input), false) = (&route.image_input, images.is_empty()) {
         builder = builder.input(
-            "random_seed",
-            [1, 1],
-            InferTensorData::UInt64(vec![request.seed.unwrap() as u64]),
+            image_input,
+            [1, images.len() as i64],
+            InferTensorData::Bytes(images.to_vec()),
         );
     }
+    // Only kept for the models declaring the guided decoding inputs.
+    if let Some(json_format) = json_format {
+        let (guide_type, guide) = json_format.guide();
+        builder = builder
+            .input(
+                GUIDE_TYPE_INPUT,
+                [1, 1],
+                InferTensorData::Bytes(vec![guide_type.as_bytes().to_vec()]),
+            )
+            .input(
+                GUIDE_INPUT,
+                [1, 1],
+                InferTensorData::Bytes(vec![guide.into_bytes()]),
+            );
+    }
 
     builder.build().context("failed to build triton request")
 }
 
+/// The functions exposed to the model for a request, after applying `tool_choice`.
+struct Tools {
+    functions: Vec<FunctionDefinition>,
+    /// Whether the model must call at least one of the functions.
+    required: bool,
+    /// The function the model must call, set by a named `tool_choice`.
+    forced: Option<String>,
+}
+
+impl Tools {
+    fn from_request(request: &ChatCompletionCreateParams) -> Result<Self, AppError> {
+        let functions: Vec<_> = request
+            .tools
+            .iter()
+            .flatten()
+            .map(|ChatCompletionTool::Function { function }| function.clone())
+            .collect();
+
+        match &request.tool_choice {
+            None | Some(ToolChoice::Mode(ToolChoiceMode::Auto)) => Ok(Self {
+                functions,
+                required: false,
+                forced: None,
+            }),
+            Some(ToolChoice::Mode(ToolChoiceMode::None)) => Ok(Self {
+                functions: Vec::new(),
+                required: false,
+                forced: None,
+            }),
+            Some(ToolChoice::Mode(ToolChoiceMode::Required)) => {
+                if functions.is_empty() {
+                    return Err(AppError::invalid_request(
+                        "tool_choice is required but no tools are provided",
+                        Some("tool_choice"),
+                    ));
+                }
+                Ok(Self {
+                    functions,
+                    required: true,
+                    forced: None,
+                })
+            }
+            Some(ToolChoice::Named(NamedToolChoice::Function { function })) => {
+                let functions: Vec<_> = functions
+                    .into_iter()
+                    .filter(|f| f.name == function.name)
+                    .collect();
+                if functions.is_empty() {
+                    return Err(AppError::invalid_request(
+                        format!("tool_choice refers to unknown function {}", function.name),
+                        Some("tool_choice"),
+                    ));
+                }
+                Ok(Self {
+                    functions,
+                    required: true,
+                    forced: Some(function.name.clone()),
+                })
+            }
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.functions.is_empty()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.functions.iter().map(|f| f.name.clone()).collect()
+    }
+
+    /// Extract the tool calls from a complete model output.
+    fn parse(&self, text: &str) -> Option<(String, Vec<ParsedToolCall>)> {
+        if !self.is_enabled() {
+            return None;
+        }
+        parse_tool_calls(text, &self.names())
+    }
+
+    /// Check that the tool calls of an output call the function forced by a named `tool_choice`.
+    fn check_forced(&self, tool_calls: Option<&[ParsedToolCall]>) -> Result<(), AppError> {
+        let Some(forced) = &self.forced else {
+            return Ok(());
+        };
+        match tool_calls {
+            Some(tool_calls) if tool_calls.iter().all(|call| &call.name == forced) => Ok(()),
+            _ => Err(AppError::invalid_output(
+                "tool_choice",
+                format!("the function {} was not called", forced),
+            )),
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub(crate) struct ChatCompletionCreateParams {
@@ -289,51 +777,92 @@ pub(crate) struct ChatCompletionCreateParams {
     messages: Vec<ChatCompletionMessageParams>,
     /// ID of the model to use.
     model: String,
-    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing
-    /// frequency in the text so far, decreasing the model's likelihood to repeat the same line
-    /// verbatim.
-    #[serde(default = "default_frequency_penalty")]
-    frequency_penalty: f32,
-    /// Modify the likelihood of specified tokens appearing in the completion.
-    logit_bias: Option<HashMap<String, f32>>,
-    /// The maximum number of tokens to generate in the completion.
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
-    /// How many completions to generate for each prompt.
+    /// Whether to return log probabilities of the output tokens or not. If true, returns the log
+    /// probabilities of each output token returned in the content of message.
+    #[serde(default = "default_logprobs")]
+    logprobs: bool,
+    /// The number of most likely tokens to return at each token position, each with an
+    /// associated log probability. logprobs must be set to true if this parameter is used.
+    /// TensorRT-LLM only returns the log probability of the sampled token, so at most this one
+    /// is returned.
+    top_logprobs: Option<usize>,
+    /// How many chat completion choices to generate for each input message. Each choice is
+    /// sampled by a separate triton request.
     #[serde(default = "default_n")]
     n: usize,
-    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they
-    /// appear in the text so far, increasing the model's likelihood to talk about new topics.
-    #[serde(default = "default_presence_penalty")]
-    presence_penalty: f32,
     /// An object specifying the format that the model must output.
     /// Setting to { "type": "json_object" } enables JSON mode, which guarantees the message the
-    /// model generates is valid JSON.
+    /// model generates is valid JSON. { "type": "json_schema", "json_schema": {...} } enables
+    /// structured outputs, which ensures the model will match the supplied JSON schema.
     response_format: Option<ResponseFormat>,
     /// If specified, our system will make a best effort to sample deterministically, such that
     /// repeated requests with the same seed and parameters should return the same result.
     seed: Option<usize>,
-    /// Up to 4 sequences where the API will stop generating further tokens. The returned text will
-    /// not contain the stop sequence.
-    stop: Option<Vec<String>>,
     /// Whether to stream back partial progress.
     #[serde(default = "default_stream")]
     stream: bool,
-    /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the
-    /// output more random, while lower values like 0.2 will make it more focused and deterministic.
-    #[serde(default = "default_temperature")]
-    temperature: f32,
-    /// An alternative to sampling with temperature, called nucleus sampling, where the model
-    /// considers the results of the tokens with top_p probability mass. So 0.1 means only the
-    /// tokens comprising the top 10% probability mass are considered.
-    #[serde(default = "default_top_p")]
-    top_p: f32,
+    /// Options for streaming response. Only set this when you set stream: true.
+    stream_options: Option<StreamOptions>,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect
     /// abuse.
     user: Option<String>,
-    // Not supported yet:
-    // tools
-    // tool_choices
+    /// A list of tools the model may call. Currently, only functions are supported as a tool.
+    tools: Option<Vec<ChatCompletionTool>>,
+    /// Controls which (if any) function is called by the model. `none` is the default when no
+    /// tools are present, `auto` is the default if tools are present.
+    tool_choice: Option<ToolChoice>,
+    /// The sampling parameters, including the extensions of the OpenAI API forwarded to
+    /// TensorRT-LLM.
+    #[serde(flatten)]
+    sampling: SamplingParams,
+}
+
+impl ChatCompletionCreateParams {
+    /// Validate the parameters which are not checked when deserializing the request.
+    fn validate(&self) -> Result<(), AppError> {
+        if !(1..=MAX_N).contains(&self.n) {
+            return Err(AppError::invalid_request(
+                format!("n must be between 1 and {}", MAX_N),
+                Some("n"),
+            ));
+        }
+        self.sampling.validate()?;
+        if let Some(top_logprobs) = self.top_logprobs {
+            check_top_logprobs("top_logprobs", top_logprobs)?;
+            if !self.logprobs {
+                return Err(AppError::invalid_request(
+                    "logprobs must be true when top_logprobs is set",
+                    Some("top_logprobs"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Why the generation of a choice ended, given its text, the number of triton responses and
+    /// whether a stop sequence was generated. Tool calls are not detected here.
+    fn finish_reason(
+        &self,
+        text: &str,
+        responses: usize,
+        stopped: bool,
+        tokenizer: Option<&Tokenizer>,
+    ) -> anyhow::Result<FinishReason> {
+        let tokens = generated_tokens(text, responses, self.stream, tokenizer)?;
+        Ok(
+            if reached_max_tokens(stopped, tokens, self.sampling.max_tokens) {
+                FinishReason::Length
+            } else {
+                FinishReason::Stop
+            },
+        )
+    }
+
+    /// The log probabilities returned for tokens, if requested with `logprobs`.
+    fn logprobs_of(&self, tokens: Vec<TokenLogprob>) -> Option<ChatCompletionLogprobs> {
+        self.logprobs
+            .then(|| ChatCompletionLogprobs::new(tokens, self.top_logprobs.unwrap_or(0)))
+    }
 }
 
 #[allow(dead_code)]
@@ -341,30 +870,202 @@ pub(crate) struct ChatCompletionCreateParams {
 #[serde(tag = "role", rename_all = "lowercase")]
 pub enum ChatCompletionMessageParams {
     System {
-        content: String,
+        content: MessageContent,
         name: Option<String>,
     },
     User {
-        content: String,
+        content: MessageContent,
         name: Option<String>,
     },
     Assistant {
-        content: String,
-        // Not supported yet:
-        // tool_calls
+        content: Option<MessageContent>,
+        tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
     },
     Tool {
-        content: String,
+        content: MessageContent,
         tool_call_id: String,
     },
 }
 
-#[allow(dead_code)]
+impl ChatCompletionMessageParams {
+    pub(crate) fn content_mut(&mut self) -> Option<&mut MessageContent> {
+        match self {
+            ChatCompletionMessageParams::System { content, .. }
+            | ChatCompletionMessageParams::User { content, .. }
+            | ChatCompletionMessageParams::Tool { content, .. } => Some(content),
+            ChatCompletionMessageParams::Assistant { content, .. } => content.as_mut(),
+        }
+    }
+}
+
+/// The content of a message, either a string or an array of content parts.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl MessageContent {
+    /// The text of the content, where the text parts are concatenated and the images are left
+    /// out.
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        MessageContent::Text(text.to_string())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ImageUrl {
+    /// A base64 data URL of the image, since remote images are not fetched.
+    pub url: String,
+    /// The detail level of the image, which is left to the model.
+    pub detail: Option<String>,
+}
+
+/// Requests are logged, so the data of the image is left out.
+impl fmt::Debug for ImageUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let url = match self.url.split_once(',') {
+            Some((header, data)) => format!("{},<{} bytes>", header, data.len()),
+            None => self.url.clone(),
+        };
+        f.debug_struct("ImageUrl")
+            .field("url", &url)
+            .field("detail", &self.detail)
+            .finish()
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
-enum ResponseFormat {
+enum ChatCompletionTool {
+    Function { function: FunctionDefinition },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    /// The name of the function to be called.
+    pub name: String,
+    /// A description of what the function does, used by the model to choose when and how to call
+    /// the function.
+    pub description: Option<String>,
+    /// The parameters the functions accepts, described as a JSON Schema object.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Named(NamedToolChoice),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ToolChoiceMode {
+    /// The model will not call any tool and instead generates a message.
+    None,
+    /// The model can pick between generating a message or calling one or more tools.
+    Auto,
+    /// The model must call one or more tools.
+    Required,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum NamedToolChoice {
+    /// Forces the model to call the specified function.
+    Function { function: FunctionName },
+}
+
+#[derive(Deserialize, Debug)]
+struct FunctionName {
+    name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionMessageToolCall {
+    Function {
+        /// The ID of the tool call.
+        id: String,
+        /// The function that the model called.
+        function: FunctionCall,
+    },
+}
+
+impl From<ParsedToolCall> for ChatCompletionMessageToolCall {
+    fn from(tool_call: ParsedToolCall) -> Self {
+        ChatCompletionMessageToolCall::Function {
+            id: format!("call_{}", Uuid::new_v4()),
+            function: FunctionCall {
+                name: tool_call.name,
+                arguments: tool_call.arguments,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FunctionCall {
+    /// The name of the function to call.
+    pub name: String,
+    /// The arguments to call the function with, as generated by the model in JSON format.
+    pub arguments: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamOptions {
+    /// If set, an additional chunk will be streamed before the data: [DONE] message. The usage
+    /// field on this chunk shows the token usage statistics for the entire request, and the
+    /// choices field will always be an empty array.
+    #[serde(default)]
+    include_usage: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ResponseFormat {
     Text,
     JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+pub(crate) struct JsonSchemaFormat {
+    /// The name of the response format.
+    pub name: String,
+    /// A description of what the response format is for.
+    pub description: Option<String>,
+    /// The schema for the response format, described as a JSON Schema object.
+    pub schema: Option<serde_json::Value>,
+    /// Whether to enable strict schema adherence. The output is only validated against the schema
+    /// if set, otherwise it must only be a JSON object.
+    pub strict: Option<bool>,
 }
 
 #[derive(Serialize, Debug)]
@@ -389,17 +1090,76 @@ struct ChatCompletion {
 struct ChatCompletionChoice {
     index: usize,
     message: ChatCompletionMessage,
+    /// Log probability information for the choice.
+    logprobs: Option<ChatCompletionLogprobs>,
     finish_reason: Option<FinishReason>,
 }
 
+#[derive(Serialize, Debug)]
+struct ChatCompletionLogprobs {
+    /// A list of message content tokens with log probability information.
+    content: Vec<ChatCompletionTokenLogprob>,
+}
+
+impl ChatCompletionLogprobs {
+    /// Shape the log probabilities of tokens, with the sampled token as the only top log
+    /// probability when `top_logprobs` is positive.
+    fn new(tokens: Vec<TokenLogprob>, top_logprobs: usize) -> Self {
+        let content = tokens
+            .into_iter()
+            .map(|TokenLogprob { token, logprob }| {
+                let top_logprobs = if top_logprobs > 0 {
+                    vec![TopLogprob {
+                        token: token.clone(),
+                        logprob,
+                        bytes: Some(token.as_bytes().to_vec()),
+                    }]
+                } else {
+                    vec![]
+                };
+                ChatCompletionTokenLogprob {
+                    bytes: Some(token.as_bytes().to_vec()),
+                    token,
+                    logprob,
+                    top_logprobs,
+                }
+            })
+            .collect();
+        Self { content }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ChatCompletionTokenLogprob {
+    /// The token.
+    token: String,
+    /// The log probability of this token.
+    logprob: f32,
+    /// The UTF-8 bytes representation of the token.
+    bytes: Option<Vec<u8>>,
+    /// List of the most likely tokens and their log probability, at this token position.
+    top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Serialize, Debug)]
+struct TopLogprob {
+    /// The token.
+    token: String,
+    /// The log probability of this token.
+    logprob: f32,
+    /// The UTF-8 bytes representation of the token.
+    bytes: Option<Vec<u8>>,
+}
+
 #[derive(Serialize, Debug)]
 struct ChatCompletionMessage {
     /// The role of the author of this message.
     role: Role,
     /// The contents of the chunk message.
     content: Option<String>,
-    // Not supported yet:
-    // tool_calls
+    /// The tool calls generated by the model, such as function calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
 }
 
 #[allow(dead_code)]
@@ -426,6 +1186,30 @@ struct Usage {
     pub total_tokens: usize,
 }
 
+impl Usage {
+    /// Count the tokens with the tokenizer of the model. Without tokenizer a zeroed usage is
+    /// reported, since clients like LangChain expect it to be present.
+    fn count(
+        tokenizer: Option<&Tokenizer>,
+        prompt: &str,
+        completions: &[String],
+    ) -> anyhow::Result<Self> {
+        let Some(tokenizer) = tokenizer else {
+            return Ok(Self::default());
+        };
+        let prompt_tokens = tokenizer.count_prompt_tokens(prompt)?;
+        let mut completion_tokens = 0;
+        for completion in completions {
+            completion_tokens += tokenizer.count_completion_tokens(completion)?;
+        }
+        Ok(Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        })
+    }
+}
+
 #[derive(Serialize, Debug)]
 struct ChatCompletionChunk {
     /// A unique identifier for the chat completion. Each chunk has the same ID.
@@ -441,12 +1225,18 @@ struct ChatCompletionChunk {
     system_fingerprint: Option<String>,
     /// A list of chat completion choices. Can be more than one if n is greater than 1.
     choices: Vec<ChatCompletionChunkChoice>,
+    /// Usage statistics for the completion request, only present in the last chunk if
+    /// stream_options.include_usage is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
 }
 
 #[derive(Serialize, Debug)]
 struct ChatCompletionChunkChoice {
     index: usize,
     delta: ChatCompletionChunkDelta,
+    /// Log probability information for the tokens of the delta.
+    logprobs: Option<ChatCompletionLogprobs>,
     finish_reason: Option<FinishReason>,
 }
 
@@ -458,8 +1248,16 @@ struct ChatCompletionChunkDelta {
     /// The contents of the chunk message.
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
-    // Not supported yet:
-    // tool_calls
+    /// The tool calls generated by the model, such as function calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ChatCompletionChunkToolCall>>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatCompletionChunkToolCall {
+    index: usize,
+    #[serde(flatten)]
+    tool_call: ChatCompletionMessageToolCall,
 }
 
 #[allow(dead_code)]
@@ -472,30 +1270,14 @@ enum Role {
     Tool,
 }
 
-fn default_frequency_penalty() -> f32 {
-    0.0
-}
-
-fn default_max_tokens() -> usize {
-    16
-}
-
 fn default_n() -> usize {
     1
 }
 
-fn default_presence_penalty() -> f32 {
-    0.0
-}
-
 fn default_stream() -> bool {
     false
 }
 
-fn default_temperature() -> f32 {
-    1.0
-}
-
-fn default_top_p() -> f32 {
-    1.0
+fn default_logprobs() -> bool {
+    false
 }
@@ -1,175 +1,346 @@
 //! https://platform.openai.com/docs/api-reference/completions/create
 use std::collections::HashMap;
-use std::iter::IntoIterator;
+use std::convert::Infallible;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
-use async_stream::{stream, try_stream};
+use async_stream::try_stream;
+use axum::extract::rejection::JsonRejection;
 use axum::extract::State;
 use axum::http::HeaderMap;
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::{Extension, Json};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tonic::codegen::tokio_stream::Stream;
-use tonic::transport::Channel;
+use serde_json::Value;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
 use tracing;
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::error::AppError;
+use crate::auth::ApiKey;
+use crate::error::{error_events, AppError};
+use crate::finish_reason::{generated_tokens, reached_max_tokens};
+use crate::logprobs::{check_top_logprobs, take_tokens, token_logprobs, TokenLogprob};
+use crate::model_routes::ModelRoute;
+use crate::rate_limit::TokenCharge;
+use crate::request_metrics::RequestMetrics;
+use crate::routes::sampling::SamplingParams;
 use crate::state::AppState;
-use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::stop_sequences::StopBuffer;
+use crate::tokenizer::Tokenizer;
+use crate::triton::cancellation::new_request_id;
+use crate::triton::infer::{sampling_seeds, stream_infer, InferEvent};
 use crate::triton::request::{Builder, InferTensorData};
-use crate::triton::telemetry::propagate_context;
 use crate::triton::ModelInferRequest;
-use crate::utils::{deserialize_bytes_tensor, string_or_seq_string};
+use crate::utils::string_or_seq_string;
 
-#[instrument(name = "completions", skip(grpc_client, request))]
+/// Upper bound of `n` and `best_of`, since every candidate runs a separate inference.
+const MAX_N: usize = 128;
+
+const ROUTE: &str = "/v1/completions";
+
+#[instrument(name = "completions", skip(state, api_key, token_charge, request))]
 pub(crate) async fn compat_completions(
     headers: HeaderMap,
-    State(AppState { grpc_client, .. }): State<AppState>,
-    request: Json<CompletionCreateParams>,
+    State(state): State<AppState>,
+    Extension(api_key): Extension<Arc<ApiKey>>,
+    Extension(token_charge): Extension<TokenCharge>,
+    request: Result<Json<Value>, JsonRejection>,
 ) -> Response {
+    let request = request
+        .map_err(AppError::from)
+        .and_then(|Json(body)| {
+            state
+                .model_routes
+                .parse_request::<CompletionCreateParams>(body)
+        })
+        .and_then(|request| {
+            api_key.authorize(&request.model, &state.model_routes)?;
+            Ok(request)
+        });
+    let request = match request {
+        Ok(request) => Json(request),
+        Err(err) => {
+            RequestMetrics::new(ROUTE, "unknown").error(&err);
+            return err.into_response();
+        }
+    };
     tracing::info!("request: {:?}", request);
 
-    if request.stream {
-        completions_stream(headers, grpc_client, request)
-            .await
-            .into_response()
-    } else {
-        completions(headers, grpc_client, request)
-            .await
-            .into_response()
-    }
+    let metrics = RequestMetrics::new(
+        ROUTE,
+        &state
+            .model_routes
+            .metric_label(&request.model, &state.triton),
+    )
+    .charging(token_charge);
+    let response = match state
+        .admission
+        .admit(&request.model, request.inferences())
+        .await
+    {
+        Ok(permit) => {
+            metrics.hold(permit);
+            if request.stream {
+                completions_stream(headers, state, metrics.clone(), request)
+                    .await
+                    .map(IntoResponse::into_response)
+            } else {
+                completions(headers, state, metrics.clone(), request)
+                    .await
+                    .map(IntoResponse::into_response)
+            }
+        }
+        Err(err) => Err(err),
+    };
+    response.unwrap_or_else(|err| {
+        metrics.error(&err);
+        err.into_response()
+    })
 }
 
-#[instrument(name = "streaming completions", skip(client, request))]
+#[instrument(name = "streaming completions", skip(state, metrics, request))]
 async fn completions_stream(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    Json(request): Json<CompletionCreateParams>,
-) -> Result<Sse<impl Stream<Item = anyhow::Result<Event>>>, AppError> {
+    state: AppState,
+    metrics: RequestMetrics,
+    Json(mut request): Json<CompletionCreateParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
     let id = format!("cmpl-{}", Uuid::new_v4());
     let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
+    let AppState {
+        triton,
+        model_routes,
+        tokenizers,
+        ..
+    } = state;
+    let route = model_routes.resolve(&request.model);
+    let triton = route.triton.clone().unwrap_or(triton);
+    let mut client = triton.client();
+    let model_inputs = triton.model_inputs();
+
+    request.validate()?;
+    if request.best_of() > request.n {
+        return Err(AppError::invalid_request(
+            "best_of cannot be greater than n when streaming",
+            Some("best_of"),
+        ));
+    }
     let model_name = request.model.clone();
-    let request = build_triton_request(request)?;
-
+    let tokenizer = tokenizers.get(&route.triton_model);
+    request.sampling.resolve_logit_bias(tokenizer.as_ref())?;
+    let include_usage = request
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.include_usage);
+    let prompt = request.prompt.clone();
+    let mut requests = build_triton_requests(&request, &route)?;
+    model_inputs
+        .retain_declared(&mut client, &mut requests)
+        .await?;
+    let mut events = stream_infer(&triton, &headers, requests);
+    events.started().await?;
+
+    let error_metrics = metrics.clone();
     let response_stream = try_stream! {
-        let request = stream! { yield request };
-        let mut request = tonic::Request::new(request);
-
-        propagate_context(&mut request, &headers);
-
-        let mut stream = client
-            .model_stream_infer(request)
-            .await
-            .context("failed to call triton grpc method model_stream_infer")?
-            .into_inner();
-
-        while let Some(response) = stream.message().await? {
-            if !response.error_message.is_empty() {
-                tracing::error!("received error message from triton: {}", response.error_message);
-
-                // Corresponds to https://github.com/openai/openai-python/blob/17ac6779958b2b74999c634c4ea4c7b74906027a/src/openai/_streaming.py#L113
-                yield Event::default().event("error").json_data(json!({
-                    "error": {
-                        "status_code": 500,
-                        "message": "Internal Server Error"
+        let mut completions = vec![String::new(); prompt.len() * request.n];
+        let mut responses = vec![0; completions.len()];
+        let mut stop_buffers = vec![StopBuffer::new(request.sampling.stop_sequences()); completions.len()];
+        // Log probabilities of the tokens not streamed yet, held back by the stop buffers.
+        let mut pending_logprobs = vec![Vec::new(); completions.len()];
+        while let Some((index, event)) = events.next().await.transpose()? {
+            let choice = match event {
+                InferEvent::Response(response) => {
+                    // Counted before the output is held back by the stop and tool call buffers.
+                    metrics.tokens(index);
+                    responses[index] += 1;
+                    let text = response.text_output()?;
+                    if request.logprobs.is_some() {
+                        pending_logprobs[index].extend(token_logprobs(
+                            &text,
+                            &response.output_log_probs(),
+                            tokenizer.as_ref(),
+                        )?);
                     }
-                })).unwrap();
-                return;
-            }
-            let infer_response = response
-                .infer_response
-                .context("empty infer response received")?;
-            tracing::debug!("triton infer response: {:?}", infer_response);
-
-            let raw_content = infer_response.raw_output_contents[0].clone();
-            let content = deserialize_bytes_tensor(raw_content)?
-                .into_iter()
-                .collect::<String>();
-            tracing::debug!("deserialized triton infer response content: {:?}", content);
-
-            if !content.is_empty() {
-                let response = Completion {
-                    id: id.clone(),
-                    object: "text_completion".to_string(),
-                    created,
-                    model: model_name.clone(),
-                    choices: vec![CompletionChoice {
+                    let content = stop_buffers[index].push(&text);
+                    if stop_buffers[index].is_stopped() {
+                        events.stop(index);
+                    }
+                    if content.is_empty() {
+                        continue;
+                    }
+                    let logprobs = request.logprobs_of(
+                        &mut pending_logprobs[index],
+                        &prompt[index / request.n],
+                        &completions[index],
+                        &content,
+                    );
+                    completions[index].push_str(&content);
+                    CompletionChoice {
                         text: content,
-                        index: 0,
-                        logprobs: None,
+                        index,
+                        logprobs,
                         finish_reason: None,
-                    }],
-                    usage: None,
-                };
-                yield Event::default().json_data(response).unwrap();
-            }
+                    }
+                }
+                InferEvent::Completed => {
+                    // Text held back which turned out not to be a stop sequence.
+                    let content = stop_buffers[index].finish();
+                    let logprobs = request.logprobs_of(
+                        &mut pending_logprobs[index],
+                        &prompt[index / request.n],
+                        &completions[index],
+                        &content,
+                    );
+                    completions[index].push_str(&content);
+                    let finish_reason = request.finish_reason(
+                        &completions[index],
+                        responses[index],
+                        stop_buffers[index].is_stopped(),
+                        tokenizer.as_ref(),
+                    )?;
+                    CompletionChoice {
+                        text: content,
+                        index,
+                        logprobs,
+                        finish_reason: Some(finish_reason),
+                    }
+                }
+            };
+
+            let response = Completion {
+                id: id.clone(),
+                object: "text_completion".to_string(),
+                created,
+                model: model_name.clone(),
+                choices: vec![choice],
+                usage: None,
+            };
+            yield Event::default().json_data(response).unwrap();
+        }
+
+        let usage = Usage::count(tokenizer.as_ref(), &prompt, &completions)?;
+        metrics.finish(tokenizer.is_some().then_some(usage.completion_tokens));
+        if include_usage {
+            let response = Completion {
+                id,
+                object: "text_completion".to_string(),
+                created,
+                model: model_name,
+                choices: vec![],
+                usage: Some(usage),
+            };
+            yield Event::default().json_data(response).unwrap();
         }
-        let response = Completion {
-            id,
-            object: "text_completion".to_string(),
-            created,
-            model: model_name,
-            choices: vec![CompletionChoice {
-                text: String::new(),
-                index: 0,
-                logprobs: None,
-                finish_reason: Some(FinishReason::Stop),
-            }],
-            usage: None,
-        };
-        yield Event::default().json_data(response).unwrap();
 
         // OpenAI stream response terminated by a data: [DONE] message.
         yield Event::default().data("[DONE]");
     };
 
-    Ok(Sse::new(response_stream).keep_alive(KeepAlive::default()))
+    Ok(Sse::new(error_events(response_stream, error_metrics)).keep_alive(KeepAlive::default()))
 }
 
-#[instrument(name = "non-streaming completions", skip(client, request), err(Debug))]
+#[instrument(
+    name = "non-streaming completions",
+    skip(state, metrics, request),
+    err(Debug)
+)]
 async fn completions(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    Json(request): Json<CompletionCreateParams>,
+    state: AppState,
+    metrics: RequestMetrics,
+    Json(mut request): Json<CompletionCreateParams>,
 ) -> Result<Json<Completion>, AppError> {
+    let AppState {
+        triton,
+        model_routes,
+        tokenizers,
+        ..
+    } = state;
+    let route = model_routes.resolve(&request.model);
+    let triton = route.triton.clone().unwrap_or(triton);
+    let mut client = triton.client();
+    let model_inputs = triton.model_inputs();
+
+    request.validate()?;
     let model_name = request.model.clone();
-    let request = build_triton_request(request)?;
-    let request = stream! { yield request };
-    let mut request = tonic::Request::new(request);
-
-    propagate_context(&mut request, &headers);
-
-    let mut stream = client
-        .model_stream_infer(request)
-        .await
-        .context("failed to call triton grpc method model_stream_infer")?
-        .into_inner();
-
-    let mut contents: Vec<String> = Vec::new();
-    while let Some(response) = stream.message().await? {
-        if !response.error_message.is_empty() {
-            return Err(anyhow::anyhow!(
-                "error message received from triton: {}",
-                response.error_message
-            )
-            .into());
+    let tokenizer = tokenizers.get(&route.triton_model);
+    request.sampling.resolve_logit_bias(tokenizer.as_ref())?;
+    let best_of = request.best_of();
+    let mut requests = build_triton_requests(&request, &route)?;
+    model_inputs
+        .retain_declared(&mut client, &mut requests)
+        .await?;
+    let mut events = stream_infer(&triton, &headers, requests);
+
+    let candidate = Candidate {
+        stop_buffer: StopBuffer::new(request.sampling.stop_sequences()),
+        ..Default::default()
+    };
+    let mut candidates = vec![candidate; request.prompt.len() * best_of];
+    while let Some((index, event)) = events.next().await.transpose()? {
+        if let InferEvent::Response(response) = event {
+            metrics.tokens(index);
+            let candidate = &mut candidates[index];
+            candidate.responses += 1;
+            let text = response.text_output()?;
+            if request.logprobs.is_some() {
+                candidate.logprobs.extend(token_logprobs(
+                    &text,
+                    &response.output_log_probs(),
+                    tokenizer.as_ref(),
+                )?);
+            }
+            candidate.text.push_str(&candidate.stop_buffer.push(&text));
+            if candidate.stop_buffer.is_stopped() {
+                events.stop(index);
+            }
+            if let Some(cum_log_prob) = response.cum_log_prob() {
+                candidate.cum_log_prob = cum_log_prob;
+            }
         }
-        let infer_response = response
-            .infer_response
-            .context("empty infer response received")?;
-        tracing::debug!("triton infer response: {:?}", infer_response);
-
-        let raw_content = infer_response.raw_output_contents[0].clone();
-        let content = deserialize_bytes_tensor(raw_content)?.into_iter().collect();
-        tracing::debug!("deserialized triton infer response content: {:?}", content);
+    }
 
-        contents.push(content);
+    for candidate in &mut candidates {
+        let rest = candidate.stop_buffer.finish();
+        candidate.text.push_str(&rest);
+        // Drop the log probabilities of the stop sequence.
+        candidate.logprobs = take_tokens(&mut candidate.logprobs, candidate.text.len());
+    }
+    let completions: Vec<_> = candidates.iter().map(|c| c.text.clone()).collect();
+    let usage = Usage::count(tokenizer.as_ref(), &request.prompt, &completions)?;
+    metrics.finish(tokenizer.is_some().then_some(usage.completion_tokens));
+
+    // Keep the n candidates with the highest cumulative log probability for each prompt.
+    let mut choices = Vec::with_capacity(request.prompt.len() * request.n);
+    for (prompt, prompt_candidates) in request.prompt.iter().zip(candidates.chunks_mut(best_of)) {
+        if best_of > request.n {
+            prompt_candidates.sort_by(|a, b| b.cum_log_prob.total_cmp(&a.cum_log_prob));
+        }
+        for candidate in prompt_candidates.iter_mut().take(request.n) {
+            let logprobs = request.logprobs.map(|top_logprobs| {
+                CompletionLogprobs::new(
+                    &candidate.logprobs,
+                    prompt.chars().count(),
+                    top_logprobs > 0,
+                )
+            });
+            let finish_reason = request.finish_reason(
+                &candidate.text,
+                candidate.responses,
+                candidate.stop_buffer.is_stopped(),
+                tokenizer.as_ref(),
+            )?;
+            choices.push(CompletionChoice {
+                text: std::mem::take(&mut candidate.text),
+                index: choices.len(),
+                logprobs,
+                finish_reason: Some(finish_reason),
+            });
+        }
     }
 
     Ok(Json(Completion {
@@ -177,74 +348,65 @@ async fn completions(
         object: "text_completion".to_string(),
         created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
         model: model_name,
-        choices: vec![CompletionChoice {
-            text: contents.into_iter().collect(),
-            index: 0,
-            logprobs: None,
-            finish_reason: Some(FinishReason::Stop),
-        }],
-        // Not supported yet, need triton to return usage stats
-        // but add a fake one to make LangChain happy
-        usage: Some(Usage {
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            total_tokens: 0,
-        }),
+        choices,
+        usage: Some(usage),
     }))
 }
 
-fn build_triton_request(request: CompletionCreateParams) -> anyhow::Result<ModelInferRequest> {
-    let mut builder = Builder::new()
-        .model_name(request.model)
+/// A sequence generated for a prompt, before choosing the best ones.
+#[derive(Clone, Default)]
+struct Candidate {
+    text: String,
+    cum_log_prob: f32,
+    logprobs: Vec<TokenLogprob>,
+    /// The number of responses received from triton.
+    responses: usize,
+    stop_buffer: StopBuffer,
+}
+
+/// Build `best_of` triton requests for each prompt, each sampled with a different seed. The
+/// request for candidate `i` of prompt `p` is at index `p * best_of + i`.
+fn build_triton_requests(
+    request: &CompletionCreateParams,
+    route: &ModelRoute,
+) -> anyhow::Result<Vec<ModelInferRequest>> {
+    let best_of = request.best_of();
+    let return_log_probs = best_of > request.n || request.logprobs.is_some();
+
+    let mut requests = Vec::with_capacity(request.prompt.len() * best_of);
+    for prompt in &request.prompt {
+        for seed in sampling_seeds(request.seed, best_of) {
+            requests.push(build_triton_request(
+                request,
+                prompt,
+                seed,
+                return_log_probs,
+                route,
+            )?);
+        }
+    }
+    Ok(requests)
+}
+
+fn build_triton_request(
+    request: &CompletionCreateParams,
+    prompt: &str,
+    seed: Option<u64>,
+    return_log_probs: bool,
+    route: &ModelRoute,
+) -> anyhow::Result<ModelInferRequest> {
+    let builder = Builder::new()
+        .id(new_request_id())
+        .model_name(&route.triton_model)
+        .model_version(&route.model_version)
         .input(
             "text_input",
             [1, 1],
-            InferTensorData::Bytes(
-                request
-                    .prompt
-                    .into_iter()
-                    .map(|s| s.as_bytes().to_vec())
-                    .collect(),
-            ),
-        )
-        .input(
-            "max_tokens",
-            [1, 1],
-            InferTensorData::Int32(vec![request.max_tokens as i32]),
-        )
-        .input(
-            "bad_words",
-            [1, 1],
-            InferTensorData::Bytes(vec!["".as_bytes().to_vec()]),
-        )
-        .input(
-            "stop_words",
-            [1, request.stop.as_ref().map_or(1, |s| s.len() as i64)],
-            InferTensorData::Bytes(
-                request
-                    .stop
-                    .unwrap_or_else(|| vec!["</s>".to_string()])
-                    .into_iter()
-                    .map(|s| s.into_bytes())
-                    .collect(),
-            ),
-        )
-        .input("top_p", [1, 1], InferTensorData::FP32(vec![request.top_p]))
-        .input(
-            "temperature",
-            [1, 1],
-            InferTensorData::FP32(vec![request.temperature]),
-        )
-        .input(
-            "presence_penalty",
-            [1, 1],
-            InferTensorData::FP32(vec![request.presence_penalty]),
-        )
-        .input(
-            "beam_width",
-            [1, 1],
-            InferTensorData::Int32(vec![request.n as i32]),
-        )
+            InferTensorData::Bytes(vec![prompt.as_bytes().to_vec()]),
+        );
+    let mut builder = request
+        .sampling
+        .inputs(builder)
         .input(
             "stream",
             [1, 1],
@@ -252,12 +414,18 @@ fn build_triton_request(request: CompletionCreateParams) -> anyhow::Result<Model
         )
         .output("text_output");
 
-    if request.seed.is_some() {
-        builder = builder.input(
-            "random_seed",
-            [1, 1],
-            InferTensorData::UInt64(vec![request.seed.unwrap() as u64]),
-        );
+    if let Some(seed) = seed {
+        builder = builder.input("random_seed", [1, 1], InferTensorData::UInt64(vec![seed]));
+    }
+    if return_log_probs {
+        builder = builder
+            .input(
+                "return_log_probs",
+                [1, 1],
+                InferTensorData::Bool(vec![true]),
+            )
+            .output("cum_log_probs")
+            .output("output_log_probs");
     }
 
     builder.build().context("failed to build triton request")
@@ -273,54 +441,121 @@ pub(crate) struct CompletionCreateParams {
     #[serde(deserialize_with = "string_or_seq_string")]
     prompt: Vec<String>,
     /// Generates best_of completions server-side and returns the "best" (the one with the highest
-    /// log probability per token). Results cannot be streamed.
-    #[serde(default = "default_best_of")]
-    best_of: usize,
+    /// cumulative log probability). Results cannot be streamed. Defaults to n.
+    best_of: Option<usize>,
     /// Echo back the prompt in addition to the completion
     #[serde(default = "default_echo")]
     echo: bool,
-    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing
-    /// frequency in the text so far, decreasing the model's likelihood to repeat the same line
-    /// verbatim.
-    #[serde(default = "default_frequency_penalty")]
-    frequency_penalty: f32,
-    /// Modify the likelihood of specified tokens appearing in the completion.
-    logit_bias: Option<HashMap<String, f32>>,
     /// Include the log probabilities on the logprobs most likely tokens, as well the chosen tokens.
+    /// TensorRT-LLM only returns the log probability of the chosen tokens, so the most likely
+    /// tokens are limited to the chosen one.
     logprobs: Option<usize>,
-    /// The maximum number of tokens to generate in the completion.
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
-    /// How many completions to generate for each prompt.
+    /// How many completions to generate for each prompt. Each completion is sampled by a separate
+    /// triton request.
     #[serde(default = "default_n")]
     n: usize,
-    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they
-    /// appear in the text so far, increasing the model's likelihood to talk about new topics.
-    #[serde(default = "default_presence_penalty")]
-    presence_penalty: f32,
     /// If specified, our system will make a best effort to sample deterministically, such that
     /// repeated requests with the same seed and parameters should return the same result.
     seed: Option<usize>,
-    /// Up to 4 sequences where the API will stop generating further tokens. The returned text will
-    /// not contain the stop sequence.
-    stop: Option<Vec<String>>,
     /// Whether to stream back partial progress.
     #[serde(default = "default_stream")]
     stream: bool,
+    /// Options for streaming response. Only set this when you set stream: true.
+    stream_options: Option<StreamOptions>,
     /// The suffix that comes after a completion of inserted text.
     suffix: Option<String>,
-    /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the
-    /// output more random, while lower values like 0.2 will make it more focused and deterministic.
-    #[serde(default = "default_temperature")]
-    temperature: f32,
-    /// An alternative to sampling with temperature, called nucleus sampling, where the model
-    /// considers the results of the tokens with top_p probability mass. So 0.1 means only the
-    /// tokens comprising the top 10% probability mass are considered.
-    #[serde(default = "default_top_p")]
-    top_p: f32,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect
     /// abuse.
     user: Option<String>,
+    /// The sampling parameters, including the extensions of the OpenAI API forwarded to
+    /// TensorRT-LLM.
+    #[serde(flatten)]
+    sampling: SamplingParams,
+}
+
+impl CompletionCreateParams {
+    /// Validate the parameters which are not checked when deserializing the request.
+    fn validate(&self) -> Result<(), AppError> {
+        if self.prompt.is_empty() {
+            return Err(AppError::invalid_request(
+                "prompt must not be empty",
+                Some("prompt"),
+            ));
+        }
+        if !(1..=MAX_N).contains(&self.n) {
+            return Err(AppError::invalid_request(
+                format!("n must be between 1 and {}", MAX_N),
+                Some("n"),
+            ));
+        }
+        if !(self.n..=MAX_N).contains(&self.best_of()) {
+            return Err(AppError::invalid_request(
+                format!("best_of must be between n and {}", MAX_N),
+                Some("best_of"),
+            ));
+        }
+        self.sampling.validate()?;
+        if let Some(logprobs) = self.logprobs {
+            check_top_logprobs("logprobs", logprobs)?;
+        }
+        Ok(())
+    }
+
+    /// The log probabilities of the `text` about to be appended to the `completion` of `prompt`,
+    /// taken from the `pending` tokens, if requested with `logprobs`.
+    fn logprobs_of(
+        &self,
+        pending: &mut Vec<TokenLogprob>,
+        prompt: &str,
+        completion: &str,
+        text: &str,
+    ) -> Option<CompletionLogprobs> {
+        let top_logprobs = self.logprobs?;
+        if text.is_empty() {
+            return None;
+        }
+        let tokens = take_tokens(pending, text.len());
+        let offset = prompt.chars().count() + completion.chars().count();
+        Some(CompletionLogprobs::new(&tokens, offset, top_logprobs > 0))
+    }
+
+    /// The number of candidates generated for each prompt.
+    fn best_of(&self) -> usize {
+        self.best_of.unwrap_or(self.n)
+    }
+
+    /// The number of triton requests run for the request.
+    fn inferences(&self) -> usize {
+        self.prompt.len() * self.n.max(self.best_of())
+    }
+
+    /// Why the generation of a choice ended, given its text, the number of triton responses and
+    /// whether a stop sequence was generated.
+    fn finish_reason(
+        &self,
+        text: &str,
+        responses: usize,
+        stopped: bool,
+        tokenizer: Option<&Tokenizer>,
+    ) -> anyhow::Result<FinishReason> {
+        let tokens = generated_tokens(text, responses, self.stream, tokenizer)?;
+        Ok(
+            if reached_max_tokens(stopped, tokens, self.sampling.max_tokens) {
+                FinishReason::Length
+            } else {
+                FinishReason::Stop
+            },
+        )
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamOptions {
+    /// If set, an additional chunk will be streamed before the data: [DONE] message. The usage
+    /// field on this chunk shows the token usage statistics for the entire request, and the
+    /// choices field will always be an empty array.
+    #[serde(default)]
+    include_usage: bool,
 }
 
 #[derive(Serialize, Debug)]
@@ -343,10 +578,43 @@ struct Completion {
 struct CompletionChoice {
     text: String,
     index: usize,
-    logprobs: Option<()>,
+    logprobs: Option<CompletionLogprobs>,
     finish_reason: Option<FinishReason>,
 }
 
+#[derive(Serialize, Debug, Default)]
+struct CompletionLogprobs {
+    /// The generated tokens.
+    tokens: Vec<String>,
+    /// The log probability of each token.
+    token_logprobs: Vec<f32>,
+    /// The most likely tokens at each position with their log probability, only holding the
+    /// chosen token.
+    top_logprobs: Option<Vec<HashMap<String, f32>>>,
+    /// The character offset of each token in the prompt followed by the completion.
+    text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    /// Shape the log probabilities of tokens starting at the character `offset`.
+    fn new(tokens: &[TokenLogprob], mut offset: usize, top_logprobs: bool) -> Self {
+        let mut logprobs = Self {
+            top_logprobs: top_logprobs.then(Vec::new),
+            ..Default::default()
+        };
+        for token in tokens {
+            logprobs.tokens.push(token.token.clone());
+            logprobs.token_logprobs.push(token.logprob);
+            if let Some(top_logprobs) = &mut logprobs.top_logprobs {
+                top_logprobs.push(HashMap::from([(token.token.clone(), token.logprob)]));
+            }
+            logprobs.text_offset.push(offset);
+            offset += token.token.chars().count();
+        }
+        logprobs
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -369,38 +637,41 @@ struct Usage {
     pub total_tokens: usize,
 }
 
-fn default_best_of() -> usize {
-    1
+impl Usage {
+    /// Count the tokens with the tokenizer of the model. Without tokenizer a zeroed usage is
+    /// reported, since clients like LangChain expect it to be present.
+    fn count(
+        tokenizer: Option<&Tokenizer>,
+        prompts: &[String],
+        completions: &[String],
+    ) -> anyhow::Result<Self> {
+        let Some(tokenizer) = tokenizer else {
+            return Ok(Self::default());
+        };
+        let mut prompt_tokens = 0;
+        for prompt in prompts {
+            prompt_tokens += tokenizer.count_prompt_tokens(prompt)?;
+        }
+        let mut completion_tokens = 0;
+        for completion in completions {
+            completion_tokens += tokenizer.count_completion_tokens(completion)?;
+        }
+        Ok(Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        })
+    }
 }
 
 fn default_echo() -> bool {
     false
 }
 
-fn default_frequency_penalty() -> f32 {
-    0.0
-}
-
-fn default_max_tokens() -> usize {
-    16
-}
-
 fn default_n() -> usize {
     1
 }
 
-fn default_presence_penalty() -> f32 {
-    0.0
-}
-
 fn default_stream() -> bool {
     false
 }
-
-fn default_temperature() -> f32 {
-    1.0
-}
-
-fn default_top_p() -> f32 {
-    1.0
-}
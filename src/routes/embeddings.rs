@@ -0,0 +1,298 @@
+//! https://platform.openai.com/docs/api-reference/embeddings/create
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::codegen::tokio_stream::StreamExt;
+use tracing::instrument;
+
+use crate::auth::ApiKey;
+use crate::error::AppError;
+use crate::model_routes::ModelRoute;
+use crate::request_metrics::RequestMetrics;
+use crate::state::AppState;
+use crate::triton::cancellation::new_request_id;
+use crate::triton::infer::{stream_infer, InferEvent};
+use crate::triton::request::{Builder, InferTensorData};
+use crate::triton::ModelInferRequest;
+use crate::utils::string_or_seq_string;
+
+/// Upper bound of the number of inputs of a request, as in the OpenAI API.
+const MAX_INPUTS: usize = 2048;
+
+/// Number of inputs embedded concurrently, the inputs of larger requests are sent in batches.
+const MAX_PARALLEL_INFERENCES: usize = 32;
+
+const ROUTE: &str = "/v1/embeddings";
+
+#[instrument(name = "embeddings", skip(state, api_key, request))]
+pub(crate) async fn compat_embeddings(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Extension(api_key): Extension<Arc<ApiKey>>,
+    request: Result<Json<Value>, JsonRejection>,
+) -> Response {
+    let request = request
+        .map_err(AppError::from)
+        .and_then(|Json(body)| {
+            state
+                .model_routes
+                .parse_request::<EmbeddingCreateParams>(body)
+        })
+        .and_then(|request| {
+            api_key.authorize(&request.model, &state.model_routes)?;
+            Ok(request)
+        });
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            RequestMetrics::new(ROUTE, "unknown").error(&err);
+            return err.into_response();
+        }
+    };
+    tracing::info!("request: {:?}", request);
+
+    let metrics = RequestMetrics::new(
+        ROUTE,
+        &state
+            .model_routes
+            .metric_label(&request.model, &state.triton),
+    );
+    let response = match state
+        .admission
+        .admit(
+            &request.model,
+            request.input.len().min(MAX_PARALLEL_INFERENCES),
+        )
+        .await
+    {
+        Ok(permit) => {
+            metrics.hold(permit);
+            embeddings(headers, state, request).await
+        }
+        Err(err) => Err(err),
+    };
+    match response {
+        Ok(response) => {
+            // Embedding models generate no tokens.
+            metrics.finish(None);
+            response.into_response()
+        }
+        Err(err) => {
+            metrics.error(&err);
+            err.into_response()
+        }
+    }
+}
+
+#[instrument(name = "create embeddings", skip(state, request), err(Debug))]
+async fn embeddings(
+    headers: HeaderMap,
+    state: AppState,
+    request: EmbeddingCreateParams,
+) -> Result<Json<EmbeddingList>, AppError> {
+    let AppState {
+        triton,
+        model_routes,
+        tokenizers,
+        ..
+    } = state;
+    let route = model_routes.resolve(&request.model);
+    let triton = route.triton.clone().unwrap_or(triton);
+
+    request.validate()?;
+    let tokenizer = tokenizers.get(&route.triton_model);
+    let mut embeddings = vec![None; request.input.len()];
+    for (batch, inputs) in request.input.chunks(MAX_PARALLEL_INFERENCES).enumerate() {
+        let offset = batch * MAX_PARALLEL_INFERENCES;
+        let requests = inputs
+            .iter()
+            .map(|input| build_triton_request(input, &route))
+            .collect::<anyhow::Result<_>>()?;
+        let mut events = stream_infer(&triton, &headers, requests);
+        while let Some((index, event)) = events.next().await.transpose()? {
+            if let InferEvent::Response(response) = event {
+                embeddings[offset + index] = Some(response.fp32_output(&route.embedding_output)?);
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(embeddings.len());
+    for (index, embedding) in embeddings.into_iter().enumerate() {
+        let mut embedding = embedding.ok_or_else(|| {
+            AppError::Triton(format!(
+                "the model {} returned no embedding for the input {}",
+                request.model, index
+            ))
+        })?;
+        if let Some(dimensions) = request.dimensions {
+            if dimensions > embedding.len() {
+                return Err(AppError::invalid_request(
+                    format!(
+                        "dimensions must be at most {} for the model {}",
+                        embedding.len(),
+                        request.model
+                    ),
+                    Some("dimensions"),
+                ));
+            }
+            shorten(&mut embedding, dimensions);
+        }
+        data.push(Embedding {
+            object: "embedding".to_string(),
+            index,
+            embedding: request.encoding_format.encode(embedding),
+        });
+    }
+
+    // Without tokenizer a zeroed usage is reported, as for the completions.
+    let mut prompt_tokens = 0;
+    if let Some(tokenizer) = &tokenizer {
+        for input in &request.input {
+            prompt_tokens += tokenizer.count_prompt_tokens(input)?;
+        }
+    }
+
+    Ok(Json(EmbeddingList {
+        object: "list".to_string(),
+        data,
+        model: request.model,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }))
+}
+
+fn build_triton_request(input: &str, route: &ModelRoute) -> anyhow::Result<ModelInferRequest> {
+    Builder::new()
+        .id(new_request_id())
+        .model_name(&route.triton_model)
+        .model_version(&route.model_version)
+        .input(
+            &route.embedding_input,
+            [1, 1],
+            InferTensorData::Bytes(vec![input.as_bytes().to_vec()]),
+        )
+        .output(&route.embedding_output)
+        .build()
+        .context("failed to build triton request")
+}
+
+/// Keep the first `dimensions` of the embedding, normalized again so that it keeps a unit length
+/// like the embeddings shortened by OpenAI.
+fn shorten(embedding: &mut Vec<f32>, dimensions: usize) {
+    embedding.truncate(dimensions);
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct EmbeddingCreateParams {
+    /// ID of the model to use.
+    model: String,
+    /// Input text to embed, encoded as a string or array of strings.
+    #[serde(deserialize_with = "string_or_seq_string")]
+    input: Vec<String>,
+    /// The format to return the embeddings in.
+    #[serde(default)]
+    encoding_format: EncodingFormat,
+    /// The number of dimensions the resulting output embeddings should have, at most the ones of
+    /// the model.
+    dimensions: Option<usize>,
+    /// A unique identifier representing your end-user.
+    #[allow(dead_code)]
+    user: Option<String>,
+}
+
+impl EmbeddingCreateParams {
+    fn validate(&self) -> Result<(), AppError> {
+        if !(1..=MAX_INPUTS).contains(&self.input.len()) {
+            return Err(AppError::invalid_request(
+                format!("input must have between 1 and {} elements", MAX_INPUTS),
+                Some("input"),
+            ));
+        }
+        if self.input.iter().any(String::is_empty) {
+            return Err(AppError::invalid_request(
+                "input must not contain empty strings",
+                Some("input"),
+            ));
+        }
+        if self.dimensions == Some(0) {
+            return Err(AppError::invalid_request(
+                "dimensions must be at least 1",
+                Some("dimensions"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum EncodingFormat {
+    #[default]
+    Float,
+    /// The little-endian bytes of the FP32 values, encoded in base64.
+    Base64,
+}
+
+impl EncodingFormat {
+    fn encode(self, embedding: Vec<f32>) -> EmbeddingVector {
+        match self {
+            EncodingFormat::Float => EmbeddingVector::Float(embedding),
+            EncodingFormat::Base64 => {
+                let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+                EmbeddingVector::Base64(BASE64.encode(bytes))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct EmbeddingList {
+    /// The object type, which is always "list".
+    object: String,
+    /// The embeddings, in the order of the inputs.
+    data: Vec<Embedding>,
+    /// The model used for the embeddings.
+    model: String,
+    /// The usage information for the request.
+    usage: EmbeddingUsage,
+}
+
+#[derive(Serialize, Debug)]
+struct Embedding {
+    /// The object type, which is always "embedding".
+    object: String,
+    /// The index of the input of the embedding.
+    index: usize,
+    /// The embedding vector, as floats or base64 depending on `encoding_format`.
+    embedding: EmbeddingVector,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Serialize, Debug)]
+struct EmbeddingUsage {
+    /// Number of tokens in the inputs.
+    prompt_tokens: usize,
+    /// Total number of tokens used in the request.
+    total_tokens: usize,
+}
@@ -1 +1,63 @@
-pub async fn health_check() {}
+//! Health probes. The readiness of triton is the result of the last health checks of the pools,
+//! which run in the background, so that probes never wait on triton.
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+
+use crate::state::AppState;
+use crate::triton::pool::{EndpointHealth, TritonPool};
+
+/// Report the liveness and the readiness of the server, answering 503 until it is ready. Kept for
+/// compatibility, see [`liveness`] and [`readiness`].
+pub(crate) async fn health_check(State(state): State<AppState>) -> (StatusCode, Json<Health>) {
+    let ready = pools(&state).iter().all(|pool| pool.is_ready());
+    (status(ready), Json(Health { live: true, ready }))
+}
+
+/// Liveness probe, the server is live as long as it answers.
+pub(crate) async fn liveness() -> Json<Liveness> {
+    Json(Liveness { live: true })
+}
+
+/// Readiness probe, the server is ready once every triton pool has a healthy endpoint on which the
+/// routed models are ready. It answers 503 until then, with the health of every endpoint.
+pub(crate) async fn readiness(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let pools = pools(&state);
+    let ready = pools.iter().all(|pool| pool.is_ready());
+    let triton = pools.iter().flat_map(|pool| pool.health()).collect();
+    (status(ready), Json(Readiness { ready, triton }))
+}
+
+/// The default pool and the pools of the model routes.
+fn pools(state: &AppState) -> Vec<&TritonPool> {
+    let mut pools = vec![&state.triton];
+    pools.extend(state.model_routes.pools());
+    pools
+}
+
+fn status(ready: bool) -> StatusCode {
+    if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Health {
+    live: bool,
+    ready: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Liveness {
+    live: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Readiness {
+    ready: bool,
+    /// The health of the triton endpoints.
+    triton: Vec<EndpointHealth>,
+}
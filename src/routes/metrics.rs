@@ -0,0 +1,8 @@
+use axum::extract::State;
+
+use crate::state::AppState;
+
+/// Render the metrics in the Prometheus text format.
+pub(crate) async fn render_metrics(State(AppState { prometheus, .. }): State<AppState>) -> String {
+    prometheus.render()
+}
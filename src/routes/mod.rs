@@ -1,7 +1,14 @@
 pub(crate) use chat::compat_chat_completions;
 pub(crate) use completions::compat_completions;
-pub(crate) use health_check::health_check;
+pub(crate) use embeddings::compat_embeddings;
+pub(crate) use health_check::{health_check, liveness, readiness};
+pub(crate) use metrics::render_metrics;
+pub(crate) use models::{list_models, retrieve_model};
 
 pub(crate) mod chat;
 mod completions;
+mod embeddings;
 mod health_check;
+mod metrics;
+mod models;
+mod sampling;
@@ -0,0 +1,177 @@
+//! https://platform.openai.com/docs/api-reference/models
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use axum::extract::{Path, State};
+use axum::http::HeaderMap;
+use axum::{Extension, Json};
+use serde::Serialize;
+use tonic::transport::Channel;
+use tonic::Code;
+use tracing::instrument;
+
+use crate::auth::ApiKey;
+use crate::error::AppError;
+use crate::model_routes::ModelRoute;
+use crate::state::AppState;
+use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::triton::telemetry::propagate_context;
+use crate::triton::{ModelMetadataRequest, ModelReadyRequest, RepositoryIndexRequest};
+
+/// Lists the ready models of the triton model repository, together with the routed models whose
+/// triton model is ready, which the API key may use.
+#[instrument(name = "list_models", skip(triton, model_routes, api_key))]
+pub(crate) async fn list_models(
+    headers: HeaderMap,
+    State(AppState {
+        triton,
+        model_routes,
+        ..
+    }): State<AppState>,
+    Extension(api_key): Extension<Arc<ApiKey>>,
+) -> Result<Json<ModelList>, AppError> {
+    let mut grpc_client = triton.client();
+    let mut request = tonic::Request::new(RepositoryIndexRequest {
+        repository_name: String::new(),
+        ready: true,
+    });
+    propagate_context(&mut request, &headers);
+
+    let index = grpc_client
+        .repository_index(request)
+        .await
+        .context("failed to call triton grpc method repository_index")?
+        .into_inner();
+    tracing::debug!("triton repository index: {:?}", index);
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    // The index lists every version of a model, so deduplicate by name.
+    let mut models: BTreeMap<String, Model> = index
+        .models
+        .iter()
+        .map(|model| {
+            (
+                model.name.clone(),
+                Model::new(model.name.clone(), model.name.clone(), created),
+            )
+        })
+        .collect();
+    for (name, route) in model_routes.iter() {
+        let ready = match &route.triton {
+            None => index
+                .models
+                .iter()
+                .any(|model| model.name == route.triton_model),
+            Some(triton) => model_ready(&mut triton.client(), route, &headers).await?,
+        };
+        if ready {
+            models.insert(
+                name.clone(),
+                Model::new(name.clone(), route.triton_model.clone(), created),
+            );
+        }
+    }
+
+    models.retain(|name, _| api_key.allows(name, &model_routes));
+
+    Ok(Json(ModelList {
+        object: "list".to_string(),
+        data: models.into_values().collect(),
+    }))
+}
+
+/// Retrieves a model by name or alias, if it is ready to serve requests.
+#[instrument(name = "retrieve_model", skip(triton, model_routes, api_key))]
+pub(crate) async fn retrieve_model(
+    headers: HeaderMap,
+    Path(model_id): Path<String>,
+    State(AppState {
+        triton,
+        model_routes,
+        ..
+    }): State<AppState>,
+    Extension(api_key): Extension<Arc<ApiKey>>,
+) -> Result<Json<Model>, AppError> {
+    api_key.authorize(&model_id, &model_routes)?;
+    let route = model_routes.resolve(&model_id);
+    let mut grpc_client = route.triton.as_ref().unwrap_or(&triton).client();
+
+    if !model_ready(&mut grpc_client, &route, &headers).await? {
+        return Err(AppError::ModelNotFound(model_id));
+    }
+
+    let mut request = tonic::Request::new(ModelMetadataRequest {
+        name: route.triton_model.clone(),
+        version: route.model_version.clone(),
+    });
+    propagate_context(&mut request, &headers);
+
+    let metadata = grpc_client
+        .model_metadata(request)
+        .await
+        .context("failed to call triton grpc method model_metadata")?
+        .into_inner();
+    tracing::debug!("triton model metadata: {:?}", metadata);
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(Json(Model::new(model_id, metadata.name, created)))
+}
+
+/// Whether the triton model of the route is ready.
+async fn model_ready(
+    grpc_client: &mut GrpcInferenceServiceClient<Channel>,
+    route: &ModelRoute,
+    headers: &HeaderMap,
+) -> Result<bool, AppError> {
+    let mut request = tonic::Request::new(ModelReadyRequest {
+        name: route.triton_model.clone(),
+        version: route.model_version.clone(),
+    });
+    propagate_context(&mut request, headers);
+
+    match grpc_client.model_ready(request).await {
+        Ok(response) => Ok(response.into_inner().ready),
+        // Triton reports unknown models as errors rather than as not ready.
+        Err(status) if matches!(status.code(), Code::NotFound | Code::InvalidArgument) => Ok(false),
+        Err(status) => Err(anyhow::Error::from(status)
+            .context("failed to call triton grpc method model_ready")
+            .into()),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ModelList {
+    /// The object type, which is always "list".
+    object: String,
+    /// The list of models.
+    data: Vec<Model>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Model {
+    /// The model identifier, which can be referenced in the API endpoints.
+    id: String,
+    /// The object type, which is always "model".
+    object: String,
+    /// The Unix timestamp (in seconds) when the model was created.
+    created: u64,
+    /// The organization that owns the model.
+    owned_by: String,
+    /// The name of the triton model serving this model.
+    root: String,
+}
+
+impl Model {
+    fn new(id: String, root: String, created: u64) -> Self {
+        Model {
+            id,
+            object: "model".to_string(),
+            created,
+            owned_by: "triton".to_string(),
+            root,
+        }
+    }
+}
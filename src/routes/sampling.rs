@@ -0,0 +1,282 @@
+//! Sampling parameters shared by the completions and the chat completions, with the extensions of
+//! the OpenAI API forwarded to TensorRT-LLM.
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::error::AppError;
+use crate::tokenizer::Tokenizer;
+use crate::triton::request::{Builder, InferTensorData};
+use crate::utils::check_range;
+
+/// Upper bound of `top_k` supported by the sampling kernels of TensorRT-LLM.
+const MAX_TOP_K: u32 = 1024;
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct SamplingParams {
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing
+    /// frequency in the text so far, decreasing the model's likelihood to repeat the same line
+    /// verbatim.
+    #[serde(default = "default_frequency_penalty")]
+    frequency_penalty: f32,
+    /// Modify the likelihood of specified tokens appearing in the completion. Maps token ids to
+    /// a bias value from -100 to 100.
+    logit_bias: Option<HashMap<String, f32>>,
+    /// The maximum number of tokens to generate in the completion.
+    #[serde(default = "default_max_tokens")]
+    pub(super) max_tokens: usize,
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they
+    /// appear in the text so far, increasing the model's likelihood to talk about new topics.
+    #[serde(default = "default_presence_penalty")]
+    presence_penalty: f32,
+    /// Up to 4 sequences where the API will stop generating further tokens. The returned text will
+    /// not contain the stop sequence.
+    stop: Option<Vec<String>>,
+    /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the
+    /// output more random, while lower values like 0.2 will make it more focused and deterministic.
+    #[serde(default = "default_temperature")]
+    temperature: f32,
+    /// An alternative to sampling with temperature, called nucleus sampling, where the model
+    /// considers the results of the tokens with top_p probability mass. So 0.1 means only the
+    /// tokens comprising the top 10% probability mass are considered.
+    #[serde(default = "default_top_p")]
+    top_p: f32,
+
+    // Extensions of the OpenAI API, forwarded to TensorRT-LLM. Parameters which are not set are
+    // left to the defaults of the model.
+    /// Number of beams used for beam search. The default of 1 disables beam search.
+    #[serde(default = "default_beam_width")]
+    beam_width: usize,
+    /// Only sample from the top_k most likely tokens. 0 disables top-k sampling.
+    top_k: Option<u32>,
+    /// Minimum probability of a token to be sampled, relative to the probability of the most
+    /// likely token.
+    min_p: Option<f32>,
+    /// Penalty applied to the tokens of the prompt and the text so far, where 1.0 means no
+    /// penalty.
+    repetition_penalty: Option<f32>,
+    /// Exponent of the sequence length used to score beams in beam search.
+    length_penalty: Option<f32>,
+    /// Minimum number of tokens to generate before the end token can be sampled.
+    min_length: Option<usize>,
+    /// Id of the token ending the generation, overriding the one of the tokenizer.
+    end_id: Option<u32>,
+    /// Id of the token used for padding, overriding the one of the tokenizer.
+    pad_id: Option<u32>,
+
+    /// The `logit_bias` as words and weights, the form taken by TensorRT-LLM.
+    #[serde(skip)]
+    embedding_bias: Option<(Vec<String>, Vec<f32>)>,
+}
+
+impl SamplingParams {
+    /// Validate the parameters which are not checked when deserializing the request.
+    pub(super) fn validate(&self) -> Result<(), AppError> {
+        check_range("max_tokens", self.max_tokens, 1..=i32::MAX as usize)?;
+        check_range("temperature", self.temperature, 0.0..=2.0)?;
+        check_range("top_p", self.top_p, 0.0..=1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0..=2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0..=2.0)?;
+        for (token_id, bias) in self.logit_bias.iter().flatten() {
+            if token_id.parse::<u32>().is_err() {
+                return Err(AppError::invalid_request(
+                    format!("logit_bias keys must be token ids, got {}", token_id),
+                    Some("logit_bias"),
+                ));
+            }
+            check_range("logit_bias", *bias, -100.0..=100.0)?;
+        }
+        check_range("beam_width", self.beam_width, 1..=i32::MAX as usize)?;
+        if let Some(top_k) = self.top_k {
+            check_range("top_k", top_k, 0..=MAX_TOP_K)?;
+        }
+        if let Some(min_p) = self.min_p {
+            check_range("min_p", min_p, 0.0..=1.0)?;
+        }
+        if let Some(repetition_penalty) = self.repetition_penalty {
+            if repetition_penalty <= 0.0 {
+                return Err(AppError::invalid_request(
+                    "repetition_penalty must be greater than 0",
+                    Some("repetition_penalty"),
+                ));
+            }
+        }
+        if let Some(min_length) = self.min_length {
+            check_range("min_length", min_length, 0..=self.max_tokens)?;
+        }
+        for (param, token_id) in [("end_id", self.end_id), ("pad_id", self.pad_id)] {
+            if let Some(token_id) = token_id {
+                check_range(param, token_id, 0..=i32::MAX as u32)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert the `logit_bias` to the `embedding_bias_words` and `embedding_bias_weights` of
+    /// TensorRT-LLM, whose preprocessing tokenizes the words again. The token ids are decoded with
+    /// the tokenizer of the model, without which the bias is ignored. Models which do not declare
+    /// these inputs ignore the bias as well.
+    pub(super) fn resolve_logit_bias(
+        &mut self,
+        tokenizer: Option<&Tokenizer>,
+    ) -> Result<(), AppError> {
+        let Some(logit_bias) = self.logit_bias.as_ref().filter(|bias| !bias.is_empty()) else {
+            return Ok(());
+        };
+        let Some(tokenizer) = tokenizer else {
+            tracing::debug!("ignoring logit_bias of a model without tokenizer");
+            return Ok(());
+        };
+        let mut words = Vec::with_capacity(logit_bias.len());
+        let mut weights = Vec::with_capacity(logit_bias.len());
+        for (token_id, bias) in logit_bias {
+            // The ids were validated already.
+            let token_id = token_id.parse().unwrap_or_default();
+            let word = tokenizer.token(token_id).ok_or_else(|| {
+                AppError::invalid_request(
+                    format!("logit_bias refers to unknown token id {}", token_id),
+                    Some("logit_bias"),
+                )
+            })?;
+            words.push(word);
+            weights.push(*bias);
+        }
+        self.embedding_bias = Some((words, weights));
+        Ok(())
+    }
+
+    /// The stop sequences set by the client, which are not returned in the output.
+    pub(super) fn stop_sequences(&self) -> &[String] {
+        self.stop.as_deref().unwrap_or_default()
+    }
+
+    /// The stop words sent to triton, defaulting to the end of sequence of Llama models.
+    pub(super) fn stop_words(&self) -> Vec<String> {
+        self.stop
+            .clone()
+            .unwrap_or_else(|| vec!["</s>".to_string()])
+    }
+
+    /// Add the sampling inputs to the triton request.
+    pub(super) fn inputs(&self, mut builder: Builder) -> Builder {
+        if let Some((words, weights)) = &self.embedding_bias {
+            let len = words.len() as i64;
+            builder = builder
+                .input(
+                    "embedding_bias_words",
+                    [1, len],
+                    InferTensorData::Bytes(
+                        words.iter().map(|word| word.clone().into_bytes()).collect(),
+                    ),
+                )
+                .input(
+                    "embedding_bias_weights",
+                    [1, len],
+                    InferTensorData::FP32(weights.clone()),
+                );
+        }
+        let stop_words = self.stop_words();
+        builder
+            .input(
+                "max_tokens",
+                [1, 1],
+                InferTensorData::Int32(vec![self.max_tokens as i32]),
+            )
+            .input(
+                "bad_words",
+                [1, 1],
+                InferTensorData::Bytes(vec!["".as_bytes().to_vec()]),
+            )
+            .input(
+                "stop_words",
+                [1, stop_words.len() as i64],
+                InferTensorData::Bytes(stop_words.into_iter().map(String::into_bytes).collect()),
+            )
+            .input("top_p", [1, 1], InferTensorData::FP32(vec![self.top_p]))
+            .input(
+                "temperature",
+                [1, 1],
+                InferTensorData::FP32(vec![self.temperature]),
+            )
+            .input(
+                "presence_penalty",
+                [1, 1],
+                InferTensorData::FP32(vec![self.presence_penalty]),
+            )
+            .input(
+                "frequency_penalty",
+                [1, 1],
+                InferTensorData::FP32(vec![self.frequency_penalty]),
+            )
+            .input(
+                "beam_width",
+                [1, 1],
+                InferTensorData::Int32(vec![self.beam_width as i32]),
+            )
+            .optional_input(
+                "top_k",
+                [1, 1],
+                self.top_k
+                    .map(|top_k| InferTensorData::Int32(vec![top_k as i32])),
+            )
+            .optional_input(
+                "min_p",
+                [1, 1],
+                self.min_p.map(|min_p| InferTensorData::FP32(vec![min_p])),
+            )
+            .optional_input(
+                "repetition_penalty",
+                [1, 1],
+                self.repetition_penalty
+                    .map(|penalty| InferTensorData::FP32(vec![penalty])),
+            )
+            .optional_input(
+                "length_penalty",
+                [1, 1],
+                self.length_penalty
+                    .map(|penalty| InferTensorData::FP32(vec![penalty])),
+            )
+            .optional_input(
+                "min_length",
+                [1, 1],
+                self.min_length
+                    .map(|min_length| InferTensorData::Int32(vec![min_length as i32])),
+            )
+            .optional_input(
+                "end_id",
+                [1, 1],
+                self.end_id
+                    .map(|end_id| InferTensorData::Int32(vec![end_id as i32])),
+            )
+            .optional_input(
+                "pad_id",
+                [1, 1],
+                self.pad_id
+                    .map(|pad_id| InferTensorData::Int32(vec![pad_id as i32])),
+            )
+    }
+}
+
+fn default_frequency_penalty() -> f32 {
+    0.0
+}
+
+fn default_max_tokens() -> usize {
+    16
+}
+
+fn default_beam_width() -> usize {
+    1
+}
+
+fn default_presence_penalty() -> f32 {
+    0.0
+}
+
+fn default_temperature() -> f32 {
+    1.0
+}
+
+fn default_top_p() -> f32 {
+    1.0
+}
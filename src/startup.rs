@@ -1,51 +1,124 @@
-use anyhow::Context;
-use axum::routing::{get, post};
-use axum::Router;
+use std::time::Duration;
+
+use anyhow::bail;
+use axum::body::Body;
+use axum::extract::DefaultBodyLimit;
+use axum::http::header::AUTHORIZATION;
+use axum::http::Request;
 use axum::middleware::{self, Next};
-use axum::http::{Request, StatusCode};
 use axum::response::Response;
-use axum::body::Body;
+use axum::routing::{get, post};
+use axum::Router;
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
+use tracing::Instrument;
 
+use crate::admission::{Admission, AdmissionOptions};
+use crate::auth::ApiKeys;
 use crate::config::Config;
-use crate::history::HistoryBuilder;
+use crate::error::AppError;
+use crate::history::HistoryBuilders;
+use crate::model_routes::{ModelRouteConfig, ModelRoutes};
+use crate::rate_limit::{rate_limit_middleware, RateLimiter, RateLimits};
 use crate::routes;
 use crate::state::AppState;
-use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::telemetry;
+use crate::tokenizer::Tokenizers;
+use crate::triton::pool::{PoolOptions, TritonPool};
 
+/// Authenticate the request with its bearer token, and attach its API key to the request, for the
+/// routes to check the models it may use, and to the span of the request.
 async fn auth_middleware(
-    req: Request<Body>,
+    mut req: Request<Body>,
     next: Next,
-    api_key: Option<String>,
-) -> Result<Response, StatusCode> {
-    if let Some(ref key) = api_key {
-        if let Some(auth_header) = req.headers().get("Authorization") {
-            if let Ok(auth_str) = auth_header.to_str() {
-                if auth_str == format!("Bearer {}", key) {
-                    return Ok(next.run(req).await);
-                }
-            }
-        }
-        Err(StatusCode::UNAUTHORIZED)
+    api_keys: ApiKeys,
+) -> Result<Response, AppError> {
+    let api_key = if api_keys.required() {
+        let secret = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or_else(|| AppError::Unauthorized("Incorrect API key provided.".to_string()))?;
+        api_keys.authenticate(secret)?
     } else {
-        Ok(next.run(req).await)
-    }
+        api_keys.anonymous()
+    };
+    let span = tracing::info_span!("api_key", api_key = %api_key.name);
+    req.extensions_mut().insert(api_key);
+    Ok(next.run(req).instrument(span).await)
 }
 
 pub async fn run_server(config: Config) -> anyhow::Result<()> {
-    tracing::info!("Connecting to triton endpoint: {}", config.triton_endpoint);
-    let grpc_client = GrpcInferenceServiceClient::connect(config.triton_endpoint)
-        .await
-        .context("failed to connect triton endpoint")?;
+    let pool_options = PoolOptions::new(&config.triton);
+    let triton = TritonPool::new(&config.triton.endpoints, pool_options)?;
+
+    let mut route_configs = config.models.routes.clone();
+    if let Some(path) = &config.models.routes_file {
+        route_configs.extend(ModelRouteConfig::load(path)?);
+    }
+    let model_routes = ModelRoutes::new(&route_configs, config.model_aliases()?, |endpoints| {
+        TritonPool::new(endpoints, pool_options)
+    })?;
+    model_routes.watch_models(&triton);
+    triton.start_health_checks();
+    for pool in model_routes.pools() {
+        pool.start_health_checks();
+    }
 
-    let history_builder =
-        HistoryBuilder::new(&config.history_template, &config.history_template_file)?;
+    let mut history_builders = HistoryBuilders::new(
+        &config.models.history_template,
+        &config.models.history_template_file,
+        &config.models.history_templates,
+    )?;
+    for route in &route_configs {
+        if let Some(template_file) = &route.history_template_file {
+            history_builders.insert_model_template(&route.id, template_file)?;
+        }
+    }
+    let tokenizers = Tokenizers::new(&config.models.tokenizers)?;
+    let telemetry = &config.telemetry;
+    let otlp_metrics_endpoint = match (telemetry.otlp_metrics, telemetry.otlp_endpoint.as_deref()) {
+        (true, None) => bail!("--otlp-metrics requires --otlp-endpoint"),
+        (otlp_metrics, otlp_endpoint) => otlp_endpoint.filter(|_| otlp_metrics),
+    };
+    let prometheus = telemetry::init_metrics("openai_trtllm", otlp_metrics_endpoint)?;
+    let admission = Admission::new(
+        AdmissionOptions {
+            max_concurrency: config.models.max_concurrency,
+            max_queue: config.models.max_queue,
+            max_queue_wait: Duration::from_secs(config.models.max_queue_wait),
+        },
+        model_routes.clone(),
+        triton.clone(),
+    );
     let state = AppState {
-        grpc_client,
-        history_builder,
+        triton,
+        history_builders,
+        model_routes,
+        tokenizers,
+        prometheus,
+        response_format_retries: config.models.response_format_retries,
+        admission,
     };
 
-    let api_key = config.api_key.clone();
+    let api_keys = ApiKeys::new(
+        config.auth.api_key.clone(),
+        config.auth.api_keys_file.clone(),
+        RateLimits::new(
+            config.auth.requests_per_minute,
+            config.auth.tokens_per_minute,
+        )?,
+    )?;
+    api_keys.reload_on_hangup()?;
+    let rate_limiter = RateLimiter::new(state.model_routes.clone(), config.server.max_body_size);
+
+    // The probes and the metrics are scraped without API key, and are never rate limited.
+    let probes = Router::new()
+        .route("/health_check", get(routes::health_check))
+        .route("/health/live", get(routes::liveness))
+        .route("/health/ready", get(routes::readiness))
+        .route("/metrics", get(routes::render_metrics))
+        .with_state(state.clone());
 
     let app = Router::new()
         .route("/v1/completions", post(routes::compat_completions))
@@ -53,14 +126,21 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
             "/v1/chat/completions",
             post(routes::compat_chat_completions),
         )
-        .route("/health_check", get(routes::health_check))
+        .route("/v1/embeddings", post(routes::compat_embeddings))
+        .route("/v1/models", get(routes::list_models))
+        .route("/v1/models/:model", get(routes::retrieve_model))
         .with_state(state)
+        .layer(DefaultBodyLimit::max(config.server.max_body_size))
         .layer(OtelAxumLayer::default())
         .layer(middleware::from_fn(move |req, next| {
-            auth_middleware(req, next, api_key.clone())
-        }));
+            rate_limit_middleware(req, next, rate_limiter.clone())
+        }))
+        .layer(middleware::from_fn(move |req, next| {
+            auth_middleware(req, next, api_keys.clone())
+        }))
+        .merge(probes);
 
-    let address = format!("{}:{}", config.host, config.port);
+    let address = format!("{}:{}", config.server.host, config.server.port);
     tracing::info!("Starting server at {}", address);
 
     let listener = tokio::net::TcpListener::bind(address).await.unwrap();
@@ -78,3 +158,61 @@ async fn shutdown_signal() {
 
     opentelemetry::global::shutdown_tracer_provider();
 }
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+    use tracing::field::{Field, Visit};
+    use tracing::span::Attributes;
+    use tracing::Id;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    use super::*;
+
+    /// Records the fields of the spans, as `name=value`.
+    #[derive(Clone, Default)]
+    struct SpanFields(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for SpanFields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            let field = format!("{}={:?}", field.name(), value);
+            self.0.lock().unwrap().push(field);
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for SpanFields {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+            attrs.record(&mut self.clone());
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_api_key_span() {
+        let fields = SpanFields::default();
+        let subscriber = tracing_subscriber::registry().with(fields.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let api_keys =
+            ApiKeys::new(Some("sk-secret".to_string()), None, Default::default()).unwrap();
+        let app = Router::new()
+            .route("/v1/models", get(|| async { "models" }))
+            .layer(middleware::from_fn(move |req, next| {
+                auth_middleware(req, next, api_keys.clone())
+            }));
+        let request = Request::get("/v1/models")
+            .header(AUTHORIZATION, "Bearer sk-secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let fields = fields.0.lock().unwrap();
+        assert_eq!(vec!["api_key=default"], *fields);
+        assert!(!fields.iter().any(|field| field.contains("sk-secret")));
+    }
+}
@@ -1,9 +1,19 @@
-use crate::history::HistoryBuilder;
-use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
-use tonic::transport::Channel;
+use metrics_exporter_prometheus::PrometheusHandle;
+
+use crate::admission::Admission;
+use crate::history::HistoryBuilders;
+use crate::model_routes::ModelRoutes;
+use crate::tokenizer::Tokenizers;
+use crate::triton::pool::TritonPool;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub grpc_client: GrpcInferenceServiceClient<Channel>,
-    pub history_builder: HistoryBuilder,
+    pub triton: TritonPool,
+    pub history_builders: HistoryBuilders,
+    pub model_routes: ModelRoutes,
+    pub tokenizers: Tokenizers,
+    pub prometheus: PrometheusHandle,
+    /// Number of times an output not following the JSON response_format is generated again.
+    pub response_format_retries: usize,
+    pub(crate) admission: Admission,
 }
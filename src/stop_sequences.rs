@@ -0,0 +1,121 @@
+//! Cut the generated text at the first stop sequence. TensorRT-LLM stops generating on the
+//! `stop_words`, but depending on the backend the stop word is kept in its output, while the
+//! OpenAI API never returns it.
+
+/// Receives the text generated for a choice piece by piece, and holds back the text which could
+/// be the start of a stop sequence until the following text tells whether it is one.
+#[derive(Clone, Default)]
+pub(crate) struct StopBuffer {
+    stop: Vec<String>,
+    pending: String,
+    stopped: bool,
+}
+
+impl StopBuffer {
+    pub(crate) fn new(stop: &[String]) -> Self {
+        Self {
+            stop: stop.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// Add generated text, returning the text which can be sent to the client. Text generated
+    /// after a stop sequence is dropped.
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(text);
+
+        let matched = self
+            .stop
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(position) = matched {
+            tracing::debug!("generation stopped on a stop sequence at {}", position);
+            self.pending.truncate(position);
+            self.stopped = true;
+            return std::mem::take(&mut self.pending);
+        }
+
+        let held = self
+            .stop
+            .iter()
+            .map(|stop| partial_match(&self.pending, stop))
+            .max()
+            .unwrap_or(0);
+        let ready = self.pending.len() - held;
+        self.pending.drain(..ready).collect()
+    }
+
+    /// Whether a stop sequence was generated, so the generation can be stopped.
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Return the text held back, once the generation completed without stop sequence.
+    pub(crate) fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// The length of the longest end of `text` which is the start of `stop`.
+fn partial_match(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| {
+            let start = text.len() - len;
+            text.is_char_boundary(start) && stop.starts_with(&text[start..])
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stop(stop: &[&str]) -> StopBuffer {
+        StopBuffer::new(&stop.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    pub fn test_stop_across_chunks() {
+        let mut buffer = stop(&["###", "\n\n"]);
+        assert_eq!("Hello", buffer.push("Hello"));
+        assert_eq!(" world", buffer.push(" world#"));
+        assert_eq!("", buffer.push("#"));
+        assert!(!buffer.is_stopped());
+        assert_eq!("", buffer.push("# Next"));
+        assert!(buffer.is_stopped());
+        assert_eq!("", buffer.push("more"));
+        assert_eq!("", buffer.finish());
+    }
+
+    #[test]
+    pub fn test_no_stop() {
+        let mut buffer = stop(&["###"]);
+        assert_eq!("Hello ", buffer.push("Hello #"));
+        assert_eq!("## world", buffer.push("# world"));
+        assert_eq!("", buffer.push("#"));
+        assert_eq!("#", buffer.finish());
+        assert!(!buffer.is_stopped());
+
+        let mut buffer = stop(&[]);
+        assert_eq!("Hello", buffer.push("Hello"));
+    }
+
+    #[test]
+    pub fn test_first_stop() {
+        let mut buffer = stop(&["world", "lo"]);
+        assert_eq!("Hel", buffer.push("Hello world"));
+        assert!(buffer.is_stopped());
+
+        // Stop sequences spanning multi-byte characters.
+        let mut buffer = stop(&["été"]);
+        assert_eq!("un ", buffer.push("un é"));
+        assert_eq!("", buffer.push("té chaud"));
+        assert!(buffer.is_stopped());
+    }
+}
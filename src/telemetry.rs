@@ -1,12 +1,32 @@
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use opentelemetry::metrics::{MeterProvider as _, MetricsError};
 use opentelemetry::trace::TraceError;
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, MeterProvider};
 use opentelemetry_sdk::propagation::TraceContextPropagator;
 use opentelemetry_sdk::trace as sdktrace;
 use opentelemetry_sdk::{runtime, Resource};
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer};
 
+use crate::telemetry::otlp_metrics::OtlpRecorder;
+
+mod otlp_metrics;
+
+/// Boundaries of the histogram buckets, covering both latencies in seconds and token throughputs.
+const HISTOGRAM_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0,
+    1000.0,
+];
+
+static PROMETHEUS_HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
 fn init_tracer(name: &str, otlp_endpoint: &str) -> Result<sdktrace::Tracer, TraceError> {
     opentelemetry_otlp::new_pipeline()
         .tracing()
@@ -26,6 +46,64 @@ fn init_tracer(name: &str, otlp_endpoint: &str) -> Result<sdktrace::Tracer, Trac
         .install_batch(runtime::Tokio)
 }
 
+fn init_meter_provider(name: &str, otlp_endpoint: &str) -> Result<MeterProvider, MetricsError> {
+    opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(otlp_endpoint),
+        )
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            name.to_owned(),
+        )]))
+        .with_aggregation_selector(|kind| match kind {
+            InstrumentKind::Histogram => Aggregation::ExplicitBucketHistogram {
+                boundaries: HISTOGRAM_BUCKETS.to_vec(),
+                record_min_max: true,
+            },
+            _ => Aggregation::Default,
+        })
+        .build()
+}
+
+/// Install the global metrics recorder, whose metrics are rendered in the Prometheus format by the
+/// returned handle. If an OTLP endpoint is given, the metrics are exported to it as well.
+///
+/// The recorder is installed once, later calls return the handle of the installed recorder.
+pub fn init_metrics(name: &str, otlp_endpoint: Option<&str>) -> anyhow::Result<PrometheusHandle> {
+    if let Some(handle) = PROMETHEUS_HANDLE.get() {
+        return Ok(handle.clone());
+    }
+
+    let recorder = PrometheusBuilder::new()
+        .set_buckets(HISTOGRAM_BUCKETS)?
+        .build_recorder();
+    let handle = recorder.handle();
+    let installed = match otlp_endpoint {
+        Some(otlp_endpoint) => {
+            let meter_provider = init_meter_provider(name, otlp_endpoint)?;
+            let meter = meter_provider.meter(name.to_owned());
+            global::set_meter_provider(meter_provider);
+            metrics::set_global_recorder(OtlpRecorder::new(recorder, meter)).is_ok()
+        }
+        None => metrics::set_global_recorder(recorder).is_ok(),
+    };
+    if !installed {
+        return Err(anyhow!("a metrics recorder is already installed"));
+    }
+
+    // The exporter only drains the histograms when it serves the metrics itself.
+    let upkeep_handle = handle.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(5));
+        upkeep_handle.run_upkeep();
+    });
+
+    Ok(PROMETHEUS_HANDLE.get_or_init(|| handle).clone())
+}
+
 /// Compose multiple layers into a `tracing`'s subscriber.
 ///
 /// # Implementation Notes
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use opentelemetry::metrics::Meter;
+use opentelemetry::KeyValue;
+
+/// Recorder forwarding the metrics to another recorder and to OpenTelemetry, so that the same
+/// instruments are exported over OTLP. Gauges are exported as up-down counters.
+pub(super) struct OtlpRecorder<R> {
+    inner: R,
+    meter: Meter,
+    instruments: Mutex<Instruments>,
+}
+
+#[derive(Default)]
+struct Instruments {
+    counters: HashMap<KeyName, opentelemetry::metrics::Counter<u64>>,
+    gauges: HashMap<KeyName, opentelemetry::metrics::UpDownCounter<f64>>,
+    histograms: HashMap<KeyName, opentelemetry::metrics::Histogram<f64>>,
+    /// Values of the gauges, since up-down counters are only updated by deltas.
+    gauge_values: HashMap<Key, Arc<Mutex<f64>>>,
+}
+
+impl<R> OtlpRecorder<R> {
+    pub(super) fn new(inner: R, meter: Meter) -> Self {
+        Self {
+            inner,
+            meter,
+            instruments: Default::default(),
+        }
+    }
+}
+
+impl<R: Recorder> Recorder for OtlpRecorder<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_counter(key, unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_gauge(key, unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_histogram(key, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        let counter = self
+            .instruments
+            .lock()
+            .unwrap()
+            .counters
+            .entry(key.name().to_string().into())
+            .or_insert_with(|| self.meter.u64_counter(key.name().to_string()).init())
+            .clone();
+        Counter::from_arc(Arc::new(OtlpCounter {
+            inner: self.inner.register_counter(key, metadata),
+            counter,
+            attributes: attributes(key),
+        }))
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        let mut instruments = self.instruments.lock().unwrap();
+        let gauge = instruments
+            .gauges
+            .entry(key.name().to_string().into())
+            .or_insert_with(|| {
+                self.meter
+                    .f64_up_down_counter(key.name().to_string())
+                    .init()
+            })
+            .clone();
+        let value = instruments
+            .gauge_values
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        Gauge::from_arc(Arc::new(OtlpGauge {
+            inner: self.inner.register_gauge(key, metadata),
+            gauge,
+            value,
+            attributes: attributes(key),
+        }))
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        let histogram = self
+            .instruments
+            .lock()
+            .unwrap()
+            .histograms
+            .entry(key.name().to_string().into())
+            .or_insert_with(|| self.meter.f64_histogram(key.name().to_string()).init())
+            .clone();
+        Histogram::from_arc(Arc::new(OtlpHistogram {
+            inner: self.inner.register_histogram(key, metadata),
+            histogram,
+            attributes: attributes(key),
+        }))
+    }
+}
+
+fn attributes(key: &Key) -> Vec<KeyValue> {
+    key.labels()
+        .map(|label| KeyValue::new(label.key().to_string(), label.value().to_string()))
+        .collect()
+}
+
+struct OtlpCounter {
+    inner: Counter,
+    counter: opentelemetry::metrics::Counter<u64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl CounterFn for OtlpCounter {
+    fn increment(&self, value: u64) {
+        self.inner.increment(value);
+        self.counter.add(value, &self.attributes);
+    }
+
+    fn absolute(&self, value: u64) {
+        // Absolute values cannot be expressed with an OpenTelemetry counter.
+        self.inner.absolute(value);
+    }
+}
+
+struct OtlpGauge {
+    inner: Gauge,
+    gauge: opentelemetry::metrics::UpDownCounter<f64>,
+    value: Arc<Mutex<f64>>,
+    attributes: Vec<KeyValue>,
+}
+
+impl OtlpGauge {
+    fn add(&self, delta: f64) {
+        *self.value.lock().unwrap() += delta;
+        self.gauge.add(delta, &self.attributes);
+    }
+}
+
+impl GaugeFn for OtlpGauge {
+    fn increment(&self, value: f64) {
+        self.inner.increment(value);
+        self.add(value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.inner.decrement(value);
+        self.add(-value);
+    }
+
+    fn set(&self, value: f64) {
+        self.inner.set(value);
+        let mut current = self.value.lock().unwrap();
+        self.gauge.add(value - *current, &self.attributes);
+        *current = value;
+    }
+}
+
+struct OtlpHistogram {
+    inner: Histogram,
+    histogram: opentelemetry::metrics::Histogram<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl HistogramFn for OtlpHistogram {
+    fn record(&self, value: f64) {
+        self.inner.record(value);
+        self.histogram.record(value, &self.attributes);
+    }
+}
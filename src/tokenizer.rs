@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+
+/// HuggingFace tokenizers used to count the tokens of prompts and completions.
+#[derive(Clone, Default)]
+pub struct Tokenizers {
+    default: Option<Tokenizer>,
+    models: Arc<HashMap<String, Tokenizer>>,
+}
+
+impl Tokenizers {
+    /// Load the tokenizers from `tokenizer.json` files, given either as `model=path` for a
+    /// specific triton model, or as a plain `path` used for all other models.
+    pub fn new(tokenizers: &[String]) -> anyhow::Result<Self> {
+        let mut default = None;
+        let mut models = HashMap::new();
+        for tokenizer in tokenizers {
+            match tokenizer.split_once('=') {
+                Some((model, path)) => {
+                    models.insert(model.trim().to_string(), Tokenizer::from_file(path.trim())?);
+                }
+                None => {
+                    if default.is_some() {
+                        anyhow::bail!("cannot set more than one tokenizer without model name");
+                    }
+                    default = Some(Tokenizer::from_file(tokenizer.trim())?);
+                }
+            }
+        }
+
+        Ok(Self {
+            default,
+            models: Arc::new(models),
+        })
+    }
+
+    /// Get the tokenizer of the given triton model, if there is one.
+    pub(crate) fn get(&self, model: &str) -> Option<Tokenizer> {
+        self.models.get(model).or(self.default.as_ref()).cloned()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Tokenizer(Arc<tokenizers::Tokenizer>);
+
+impl Tokenizer {
+    fn from_file(path: &str) -> anyhow::Result<Self> {
+        let tokenizer = tokenizers::Tokenizer::from_file(path)
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("failed to load tokenizer from {}", path))?;
+        Ok(Self(Arc::new(tokenizer)))
+    }
+
+    /// Count the tokens of a prompt, including the special tokens added by the tokenizer.
+    pub(crate) fn count_prompt_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        self.count(text, true)
+    }
+
+    /// Count the tokens of a generated completion.
+    pub(crate) fn count_completion_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        self.count(text, false)
+    }
+
+    /// Split a generated text into the text of its tokens.
+    pub(crate) fn split(&self, text: &str) -> anyhow::Result<Vec<String>> {
+        let encoding = self
+            .0
+            .encode(text, false)
+            .map_err(|e| anyhow!(e))
+            .context("failed to tokenize text")?;
+
+        // Each token spans from its start offset to the start of the next one, so that the
+        // whitespace dropped by some pre-tokenizers is kept in the tokens.
+        let mut starts: Vec<_> = encoding
+            .get_offsets()
+            .iter()
+            .map(|(start, _)| *start)
+            .filter(|start| *start > 0 && text.is_char_boundary(*start))
+            .collect();
+        starts.dedup();
+        starts.insert(0, 0);
+        starts.push(text.len());
+        Ok(starts
+            .windows(2)
+            .filter(|range| range[0] < range[1])
+            .map(|range| text[range[0]..range[1]].to_string())
+            .collect())
+    }
+
+    /// The text of the token with the given id, if the id is in the vocabulary.
+    pub(crate) fn token(&self, id: u32) -> Option<String> {
+        self.0.id_to_token(id)?;
+        self.0.decode(&[id], false).ok()
+    }
+
+    fn count(&self, text: &str, add_special_tokens: bool) -> anyhow::Result<usize> {
+        let encoding = self
+            .0
+            .encode(text, add_special_tokens)
+            .map_err(|e| anyhow!(e))
+            .context("failed to tokenize text")?;
+        Ok(encoding.len())
+    }
+}
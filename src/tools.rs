@@ -0,0 +1,238 @@
+//! Detect and parse tool calls emitted by the model.
+//!
+//! Models are prompted (see the history templates) to wrap every call in
+//! `<tool_call>{"name": ..., "arguments": ...}</tool_call>`. Models fine-tuned on the plain JSON
+//! convention (e.g. llama 3.1) that answer with a bare `{"name": ..., "parameters": ...}` object
+//! or a list of such objects are accepted as well.
+use serde_json::Value;
+
+pub(crate) const TOOL_CALL_START: &str = "<tool_call>";
+pub(crate) const TOOL_CALL_END: &str = "</tool_call>";
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParsedToolCall {
+    pub name: String,
+    /// The arguments of the call, encoded as a JSON string.
+    pub arguments: String,
+}
+
+/// Split the model output into its plain text and the tool calls it contains. The text around the
+/// tagged calls is kept, one line per piece of text.
+///
+/// Only calls to functions listed in `tool_names` are recognized. Returns `None` if the output
+/// does not contain any valid tool call, in which case it should be treated as plain content.
+pub(crate) fn parse_tool_calls(
+    text: &str,
+    tool_names: &[String],
+) -> Option<(String, Vec<ParsedToolCall>)> {
+    if let Some(start) = text.find(TOOL_CALL_START) {
+        let mut calls = Vec::new();
+        let mut content = vec![text[..start].trim()];
+        for block in text[start..].split(TOOL_CALL_START).skip(1) {
+            let (body, rest) = block.split_once(TOOL_CALL_END).unwrap_or((block, ""));
+            calls.push(parse_call(
+                serde_json::from_str(body.trim()).ok()?,
+                tool_names,
+            )?);
+            content.push(rest.trim());
+        }
+        content.retain(|text| !text.is_empty());
+        return Some((content.join("\n"), calls));
+    }
+
+    let calls = match serde_json::from_str(text.trim()).ok()? {
+        Value::Array(values) if !values.is_empty() => values
+            .into_iter()
+            .map(|value| parse_call(value, tool_names))
+            .collect::<Option<Vec<_>>>()?,
+        value => vec![parse_call(value, tool_names)?],
+    };
+    Some((String::new(), calls))
+}
+
+fn parse_call(value: Value, tool_names: &[String]) -> Option<ParsedToolCall> {
+    let Value::Object(mut object) = value else {
+        return None;
+    };
+    let name = match object.remove("name")? {
+        Value::String(name) if tool_names.contains(&name) => name,
+        _ => return None,
+    };
+    let arguments = match object
+        .remove("arguments")
+        .or_else(|| object.remove("parameters"))
+    {
+        None => "{}".to_string(),
+        Some(Value::String(arguments)) => arguments,
+        Some(arguments) => arguments.to_string(),
+    };
+    Some(ParsedToolCall { name, arguments })
+}
+
+/// Incrementally separates plain content from tool calls in a streamed model output.
+///
+/// Text is passed through as soon as it can no longer be the beginning of a tool call. Once a
+/// tool call is detected, the rest of the output is held back and parsed by [`Self::finish`].
+pub(crate) struct ToolCallStream {
+    tool_names: Vec<String>,
+    buffer: String,
+    buffering: bool,
+    started: bool,
+}
+
+impl ToolCallStream {
+    /// Create a new stream. If `hold_back` is set, no content is emitted before the output is
+    /// complete, e.g. because a tool call is required.
+    pub(crate) fn new(tool_names: Vec<String>, hold_back: bool) -> Self {
+        Self {
+            tool_names,
+            buffer: String::new(),
+            buffering: hold_back,
+            started: false,
+        }
+    }
+
+    /// Feed the next piece of output and return the content that can be emitted right away.
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        self.buffer.push_str(text);
+        if self.buffering {
+            return String::new();
+        }
+
+        if !self.started {
+            let trimmed = self.buffer.trim_start();
+            if trimmed.is_empty() {
+                return String::new();
+            }
+            self.started = true;
+            if trimmed.starts_with('{') || trimmed.starts_with('[') {
+                self.buffering = true;
+                return String::new();
+            }
+        }
+
+        if let Some(start) = self.buffer.find(TOOL_CALL_START) {
+            self.buffering = true;
+            let rest = self.buffer.split_off(start);
+            return std::mem::replace(&mut self.buffer, rest);
+        }
+
+        // Keep any suffix which may turn out to be the beginning of a tool call marker.
+        let keep = (1..TOOL_CALL_START.len())
+            .rev()
+            .find(|&len| self.buffer.ends_with(&TOOL_CALL_START[..len]))
+            .unwrap_or(0);
+        let rest = self.buffer.split_off(self.buffer.len() - keep);
+        std::mem::replace(&mut self.buffer, rest)
+    }
+
+    /// Parse the held back output. Returns the remaining content and the tool calls, if any.
+    pub(crate) fn finish(self) -> (String, Option<Vec<ParsedToolCall>>) {
+        match parse_tool_calls(&self.buffer, &self.tool_names) {
+            Some((content, calls)) => (content, Some(calls)),
+            None => (self.buffer, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tool_names() -> Vec<String> {
+        vec!["get_weather".into(), "get_time".into()]
+    }
+
+    #[test]
+    pub fn test_parse_tagged_tool_calls() {
+        let text = "Let me check.\n<tool_call>{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}</tool_call>\n\
+<tool_call>{\"name\": \"get_time\", \"arguments\": \"{}\"}</tool_call>";
+
+        let (content, calls) = parse_tool_calls(text, &tool_names()).expect("should parse");
+
+        assert_eq!("Let me check.", content);
+        assert_eq!(
+            vec![
+                ParsedToolCall {
+                    name: "get_weather".into(),
+                    arguments: "{\"city\":\"Paris\"}".into(),
+                },
+                ParsedToolCall {
+                    name: "get_time".into(),
+                    arguments: "{}".into(),
+                },
+            ],
+            calls
+        );
+    }
+
+    #[test]
+    pub fn test_parse_text_around_tool_calls() {
+        let text = "Let me check.\n<tool_call>{\"name\": \"get_weather\"}</tool_call> and \
+<tool_call>{\"name\": \"get_time\"}</tool_call>\nOne moment.";
+
+        let (content, calls) = parse_tool_calls(text, &tool_names()).expect("should parse");
+
+        assert_eq!("Let me check.\nand\nOne moment.", content);
+        assert_eq!(2, calls.len());
+    }
+
+    #[test]
+    pub fn test_parse_json_tool_calls() {
+        let text = " {\"name\": \"get_weather\", \"parameters\": {\"city\": \"Paris\"}}";
+
+        let (content, calls) = parse_tool_calls(text, &tool_names()).expect("should parse");
+
+        assert_eq!("", content);
+        assert_eq!("get_weather", calls[0].name);
+        assert_eq!("{\"city\":\"Paris\"}", calls[0].arguments);
+    }
+
+    #[test]
+    pub fn test_parse_no_tool_calls() {
+        assert_eq!(None, parse_tool_calls("Hello world", &tool_names()));
+        assert_eq!(None, parse_tool_calls("{\"answer\": 42}", &tool_names()));
+        assert_eq!(
+            None,
+            parse_tool_calls("{\"name\": \"unknown\", \"arguments\": {}}", &tool_names())
+        );
+    }
+
+    #[test]
+    pub fn test_stream_tool_calls() {
+        let mut stream = ToolCallStream::new(tool_names(), false);
+
+        let mut content = String::new();
+        for chunk in [
+            "Sure",
+            ", one moment.",
+            " <tool",
+            "_call>{\"name\": ",
+            "\"get_time\"}",
+            "</tool_call>",
+            "\nDone.",
+        ] {
+            content.push_str(&stream.push(chunk));
+        }
+        let (rest, calls) = stream.finish();
+
+        assert_eq!("Sure, one moment. ", content);
+        assert_eq!("Done.", rest);
+        assert_eq!("get_time", calls.expect("should parse")[0].name);
+    }
+
+    #[test]
+    pub fn test_stream_plain_content() {
+        let mut stream = ToolCallStream::new(tool_names(), false);
+
+        let mut content = String::new();
+        for chunk in ["Hello", " <b>world</b>", " <"] {
+            content.push_str(&stream.push(chunk));
+        }
+        let (rest, calls) = stream.finish();
+
+        assert_eq!("Hello <b>world</b> ", content);
+        assert_eq!("<", rest);
+        assert!(calls.is_none());
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+use super::grpc_inference_service_client::GrpcInferenceServiceClient;
+use super::metadata::ModelInputs;
+use super::model_infer_request::InferInputTensor;
+use super::request::InferTensorData;
+use super::ModelInferRequest;
+use crate::request_metrics::record_cancelled;
+
+/// Generate an id for an inference request. TensorRT-LLM requires request ids to be numeric.
+pub(crate) fn new_request_id() -> String {
+    Uuid::new_v4().as_u64_pair().0.to_string()
+}
+
+/// Stops an inference on triton if the guard is dropped before the inference completed, which
+/// happens when the client disconnects and axum drops the response, or when the inference is
+/// stopped on a stop sequence.
+///
+/// Dropping the response stream of `model_stream_infer` already cancels the gRPC call. On top of
+/// that, models declaring the `stop` input (e.g. the tensorrt_llm model of tensorrtllm_backend) get
+/// a request with `stop` set and the same request id, so that TensorRT-LLM releases the slot in the
+/// in-flight batch right away.
+///
+/// Only the inferences cancelled for the client are counted in the cancelled requests, the ones
+/// stopped on a stop sequence are not.
+pub(crate) struct CancellationGuard {
+    client: GrpcInferenceServiceClient<Channel>,
+    model_inputs: ModelInputs,
+    /// The model, version and id of the inference, cleared once it completed.
+    inference: Option<(String, String, String)>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl CancellationGuard {
+    /// `stopped` is set when the inference is stopped on purpose, e.g. on a stop sequence, rather
+    /// than because the client is gone.
+    pub(crate) fn new(
+        client: GrpcInferenceServiceClient<Channel>,
+        model_inputs: ModelInputs,
+        request: &ModelInferRequest,
+        stopped: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            client,
+            model_inputs,
+            inference: Some((
+                request.model_name.clone(),
+                request.model_version.clone(),
+                request.id.clone(),
+            )),
+            stopped,
+        }
+    }
+
+    /// Mark the inference as completed, so nothing is sent on drop.
+    pub(crate) fn complete(&mut self) {
+        self.inference = None;
+    }
+}
+
+/// The request stopping an inference, with nothing but its id and the `stop` flag.
+fn stop_request(model: String, version: String, id: String) -> ModelInferRequest {
+    let inputs = [("stop", true), ("stream", false)]
+        .into_iter()
+        .map(|(name, value)| {
+            let data = InferTensorData::Bool(vec![value]);
+            InferInputTensor {
+                name: name.to_string(),
+                shape: vec![1, 1],
+                datatype: data.as_ref().into(),
+                contents: Some(data.into()),
+                ..Default::default()
+            }
+        })
+        .collect();
+    ModelInferRequest {
+        model_name: model,
+        model_version: version,
+        id,
+        inputs,
+        ..Default::default()
+    }
+}
+
+impl Drop for CancellationGuard {
+    fn drop(&mut self) {
+        let Some((model, version, id)) = self.inference.take() else {
+            return;
+        };
+        if self.stopped.load(Ordering::Relaxed) {
+            tracing::debug!("stopping inference request {} of model {}", id, model);
+        } else {
+            tracing::info!("cancelling inference request {} of model {}", id, model);
+            record_cancelled(&model);
+        }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let mut client = self.client.clone();
+        let model_inputs = self.model_inputs.clone();
+        runtime.spawn(async move {
+            // Other models only rely on the gRPC call being cancelled.
+            match model_inputs.get(&mut client, &model, &version).await {
+                Ok(inputs) if inputs.contains("stop") => {}
+                _ => return,
+            }
+            let request = stop_request(model, version, id.clone());
+            let request = async_stream::stream! { yield request };
+            match client.model_stream_infer(request).await {
+                Ok(response) => {
+                    // Drain the stream so that triton does not see the stop request cancelled.
+                    let mut stream = response.into_inner();
+                    while let Ok(Some(response)) = stream.message().await {
+                        if !response.error_message.is_empty() {
+                            tracing::debug!(
+                                "stop request {} rejected by triton: {}",
+                                id,
+                                response.error_message
+                            );
+                        }
+                    }
+                }
+                Err(status) => tracing::debug!("failed to send stop request {}: {}", id, status),
+            }
+        });
+    }
+}
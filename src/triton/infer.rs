@@ -0,0 +1,345 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::Context;
+use async_stream::{stream, try_stream};
+use axum::http::HeaderMap;
+use tonic::codegen::tokio_stream::{Stream, StreamExt, StreamMap};
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Streaming};
+use uuid::Uuid;
+
+use super::cancellation::CancellationGuard;
+use super::pool::{InFlight, Replica, TritonPool};
+use super::telemetry::propagate_context;
+use super::{ModelInferRequest, ModelInferResponse, ModelStreamInferResponse};
+use crate::error::AppError;
+use crate::request_metrics::record_triton_error;
+use crate::utils::{deserialize_bytes_tensor, deserialize_fp32_tensor};
+
+/// An event of one of the inferences run by [`stream_infer`].
+pub(crate) enum InferEvent {
+    /// A response generated by the inference.
+    Response(ModelInferResponse),
+    /// The inference is completed, no response follows.
+    Completed,
+}
+
+type InferEvents = Pin<Box<dyn Stream<Item = Result<InferEvent, AppError>> + Send>>;
+
+/// The merged events of the inferences run by [`stream_infer`].
+pub(crate) struct InferStream {
+    streams: StreamMap<usize, InferEvents>,
+    /// Inferences stopped with [`InferStream::stop`], whose completion is not yielded yet.
+    stopped: VecDeque<usize>,
+    /// Flags of the inferences telling their cancellation guard they are stopped on purpose.
+    stop_flags: HashMap<usize, Arc<AtomicBool>>,
+    /// The first event, received by [`InferStream::started`] and yielded next.
+    first: Option<(usize, InferEvent)>,
+}
+
+impl InferStream {
+    /// Wait for the first event of the inferences, so that inferences failing to start, e.g. on
+    /// an unknown model or an unavailable triton, fail the request with their status code rather
+    /// than with an error event once streaming started.
+    pub(crate) async fn started(&mut self) -> Result<(), AppError> {
+        self.first = self.next().await.transpose()?;
+        Ok(())
+    }
+
+    /// Stop the inference with the given index before it completes, e.g. when a stop sequence is
+    /// generated. The inference is cancelled on triton and [`InferEvent::Completed`] is yielded
+    /// for it next.
+    pub(crate) fn stop(&mut self, index: usize) {
+        if let Some(stop_flag) = self.stop_flags.get(&index) {
+            stop_flag.store(true, Ordering::Relaxed);
+        }
+        if self.streams.remove(&index).is_some() {
+            self.stopped.push_back(index);
+        }
+    }
+}
+
+impl Stream for InferStream {
+    type Item = Result<(usize, InferEvent), AppError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(first) = self.first.take() {
+            return Poll::Ready(Some(Ok(first)));
+        }
+        if let Some(index) = self.stopped.pop_front() {
+            return Poll::Ready(Some(Ok((index, InferEvent::Completed))));
+        }
+        Pin::new(&mut self.streams)
+            .poll_next(cx)
+            .map(|item| item.map(|(index, event)| event.map(|event| (index, event))))
+    }
+}
+
+/// Run the inference requests concurrently and merge their responses into a single stream. Each
+/// event is tagged with the index of the request it belongs to.
+///
+/// Inferences which did not complete when the stream is dropped are cancelled on triton.
+pub(crate) fn stream_infer(
+    pool: &TritonPool,
+    headers: &HeaderMap,
+    requests: Vec<ModelInferRequest>,
+) -> InferStream {
+    // The context is propagated from the span of the handler, which is not current anymore when
+    // the inferences are started.
+    let mut context = tonic::Request::new(());
+    propagate_context(&mut context, headers);
+    let (metadata, _, _) = context.into_parts();
+
+    let mut streams: StreamMap<usize, InferEvents> = StreamMap::new();
+    let mut stop_flags = HashMap::new();
+    for (index, request) in requests.into_iter().enumerate() {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        streams.insert(
+            index,
+            Box::pin(infer_events(
+                pool.clone(),
+                request,
+                metadata.clone(),
+                stop_flag.clone(),
+            )),
+        );
+        stop_flags.insert(index, stop_flag);
+    }
+
+    InferStream {
+        streams,
+        stopped: VecDeque::new(),
+        stop_flags,
+        first: None,
+    }
+}
+
+fn infer_events(
+    pool: TritonPool,
+    request: ModelInferRequest,
+    metadata: MetadataMap,
+    stop_flag: Arc<AtomicBool>,
+) -> impl Stream<Item = Result<InferEvent, AppError>> {
+    try_stream! {
+        let model = request.model_name.clone();
+        let Started {
+            replica,
+            in_flight: _in_flight,
+            mut cancellation_guard,
+            mut stream,
+            first_response,
+        } = start_inference(&pool, &request, &metadata, &stop_flag).await?;
+
+        let mut next_response = first_response;
+        while let Some(response) = next_response {
+            if !response.error_message.is_empty() {
+                tracing::error!("received error message from triton: {}", response.error_message);
+                record_triton_error(&model, "InferResponse");
+                cancellation_guard.complete();
+                Err(AppError::from_triton_message(&response.error_message))?;
+            }
+            let infer_response = response
+                .infer_response
+                .context("empty infer response received")
+                .inspect_err(|_| cancellation_guard.complete())?;
+            tracing::debug!("triton infer response: {:?}", infer_response);
+
+            yield InferEvent::Response(infer_response);
+
+            // A failed call is over on triton, there is nothing left to cancel.
+            next_response = stream.message().await.inspect_err(|status| {
+                record_triton_error(&model, &format!("{:?}", status.code()));
+                if status.code() == Code::Unavailable {
+                    replica.record_failure();
+                }
+                cancellation_guard.complete();
+            })?;
+        }
+        replica.record_success(&pool, &model);
+        cancellation_guard.complete();
+
+        yield InferEvent::Completed;
+    }
+}
+
+/// An inference started on a replica, which sent its first response.
+struct Started {
+    replica: Arc<Replica>,
+    in_flight: InFlight,
+    cancellation_guard: CancellationGuard,
+    stream: Streaming<ModelStreamInferResponse>,
+    first_response: Option<ModelStreamInferResponse>,
+}
+
+/// Start the inference on a replica chosen by the pool. Until the first response is received,
+/// nothing is sent to the client yet, so the inference is retried on another replica if the
+/// chosen one is unavailable.
+async fn start_inference(
+    pool: &TritonPool,
+    request: &ModelInferRequest,
+    metadata: &MetadataMap,
+    stop_flag: &Arc<AtomicBool>,
+) -> Result<Started, AppError> {
+    let model = &request.model_name;
+    let mut tried = Vec::new();
+    let mut replica = pool.pick(model, &tried).context("no triton endpoint")?;
+    loop {
+        let in_flight = replica.start();
+        let mut cancellation_guard = CancellationGuard::new(
+            replica.client(),
+            pool.model_inputs().clone(),
+            request,
+            stop_flag.clone(),
+        );
+
+        let stream_request = request.clone();
+        let mut grpc_request = tonic::Request::new(stream! { yield stream_request });
+        *grpc_request.metadata_mut() = metadata.clone();
+        let mut client = replica.client();
+        let started = async {
+            let mut stream = client.model_stream_infer(grpc_request).await?.into_inner();
+            let first_response = stream.message().await?;
+            Ok::<_, tonic::Status>((stream, first_response))
+        }
+        .await;
+
+        let status = match started {
+            Ok((stream, first_response)) => {
+                return Ok(Started {
+                    replica,
+                    in_flight,
+                    cancellation_guard,
+                    stream,
+                    first_response,
+                })
+            }
+            Err(status) => status,
+        };
+        record_triton_error(model, &format!("{:?}", status.code()));
+        if status.code() == Code::Unavailable {
+            replica.record_failure();
+            tried.push(replica.index());
+            if let Some(next) = pool.pick(model, &tried) {
+                tracing::warn!(
+                    "triton endpoint {} is unavailable, retrying on {}: {}",
+                    replica.endpoint(),
+                    next.endpoint(),
+                    status
+                );
+                cancellation_guard.complete();
+                replica = next;
+                continue;
+            }
+        }
+        cancellation_guard.complete();
+        return Err(anyhow::Error::from(status)
+            .context("failed to call triton grpc method model_stream_infer")
+            .into());
+    }
+}
+
+/// Seeds for `count` sequences sampled from the same prompt. Without distinct seeds, TensorRT-LLM
+/// samples the same sequence for every request, so random seeds are used if none is given.
+pub(crate) fn sampling_seeds(seed: Option<usize>, count: usize) -> Vec<Option<u64>> {
+    (0..count)
+        .map(|index| match seed {
+            Some(seed) => Some((seed as u64).wrapping_add(index as u64)),
+            None if count > 1 => Some(random_seed()),
+            None => None,
+        })
+        .collect()
+}
+
+pub(crate) fn random_seed() -> u64 {
+    Uuid::new_v4().as_u64_pair().0
+}
+
+impl ModelInferResponse {
+    /// Get the raw contents of the output tensor with the given name.
+    pub(crate) fn raw_output(&self, name: &str) -> Option<&Vec<u8>> {
+        let index = self.outputs.iter().position(|output| output.name == name)?;
+        self.raw_output_contents.get(index)
+    }
+
+    /// Decode the text generated in this response.
+    pub(crate) fn text_output(&self) -> anyhow::Result<String> {
+        let raw_content = self
+            .raw_output("text_output")
+            .context("missing text_output in triton response")?;
+        let content = deserialize_bytes_tensor(raw_content.clone())?
+            .into_iter()
+            .collect::<String>();
+        tracing::debug!("deserialized triton infer response content: {:?}", content);
+        Ok(content)
+    }
+
+    /// Decode the FP32 output tensor with the given name, e.g. the embedding of an embedding
+    /// model.
+    pub(crate) fn fp32_output(&self, name: &str) -> anyhow::Result<Vec<f32>> {
+        let index = self
+            .outputs
+            .iter()
+            .position(|output| output.name == name)
+            .with_context(|| format!("missing {} in triton response", name))?;
+        let datatype = &self.outputs[index].datatype;
+        if datatype != "FP32" {
+            anyhow::bail!("expected {} to be FP32, got {}", name, datatype);
+        }
+        let raw_content = self
+            .raw_output_contents
+            .get(index)
+            .with_context(|| format!("missing contents of {} in triton response", name))?;
+        Ok(deserialize_fp32_tensor(raw_content))
+    }
+
+    /// The cumulative log probability of the sequence generated so far, if requested with
+    /// `return_log_probs`.
+    pub(crate) fn cum_log_prob(&self) -> Option<f32> {
+        let raw_content = self.raw_output("cum_log_probs")?;
+        deserialize_fp32_tensor(raw_content).first().copied()
+    }
+
+    /// The log probabilities of the tokens generated in this response, if requested with
+    /// `return_log_probs`.
+    pub(crate) fn output_log_probs(&self) -> Vec<f32> {
+        let Some(index) = self
+            .outputs
+            .iter()
+            .position(|output| output.name == "output_log_probs")
+        else {
+            return vec![];
+        };
+        let Some(raw_content) = self.raw_output_contents.get(index) else {
+            return vec![];
+        };
+        let mut log_probs = deserialize_fp32_tensor(raw_content);
+        // The tensor is shaped [batch, beam, tokens], only the first beam is kept.
+        if let Some(&tokens) = self.outputs[index].shape.last() {
+            log_probs.truncate(tokens.max(0) as usize);
+        }
+        log_probs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_sampling_seeds() {
+        assert_eq!(vec![None], sampling_seeds(None, 1));
+        assert_eq!(vec![Some(42)], sampling_seeds(Some(42), 1));
+        assert_eq!(
+            vec![Some(42), Some(43), Some(44)],
+            sampling_seeds(Some(42), 3)
+        );
+
+        let seeds = sampling_seeds(None, 2);
+        assert!(seeds.iter().all(Option::is_some));
+        assert_ne!(seeds[0], seeds[1]);
+    }
+}
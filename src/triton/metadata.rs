@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tonic::transport::Channel;
+
+use super::grpc_inference_service_client::GrpcInferenceServiceClient;
+use super::{ModelInferRequest, ModelMetadataRequest};
+use crate::error::AppError;
+
+/// How long the inputs of a model are cached, so that a model reloaded with other inputs is picked
+/// up without restarting.
+const INPUTS_TTL: Duration = Duration::from_secs(60);
+
+/// Inputs declared by the versions of the triton models of a pool, fetched with `model_metadata`
+/// the first time a model is used and again once they expire.
+///
+/// The inputs of the tensorrtllm_backend ensemble differ between versions and deployments, so
+/// optional parameters are only sent to models which declare them.
+#[derive(Clone, Default)]
+pub(crate) struct ModelInputs(Arc<RwLock<HashMap<(String, String), CachedInputs>>>);
+
+struct CachedInputs {
+    inputs: Arc<HashSet<String>>,
+    fetched: Instant,
+}
+
+impl ModelInputs {
+    /// Get the names of the inputs declared by the version of the model. An empty version is the
+    /// version chosen by triton.
+    pub(crate) async fn get(
+        &self,
+        client: &mut GrpcInferenceServiceClient<Channel>,
+        model: &str,
+        version: &str,
+    ) -> Result<Arc<HashSet<String>>, AppError> {
+        let key = (model.to_string(), version.to_string());
+        if let Some(cached) = self.0.read().unwrap().get(&key) {
+            if cached.fetched.elapsed() < INPUTS_TTL {
+                return Ok(cached.inputs.clone());
+            }
+        }
+
+        let metadata = client
+            .model_metadata(ModelMetadataRequest {
+                name: model.to_string(),
+                version: version.to_string(),
+            })
+            .await
+            .context("failed to call triton grpc method model_metadata")?
+            .into_inner();
+        tracing::debug!("triton model metadata: {:?}", metadata);
+
+        let inputs: Arc<HashSet<String>> = Arc::new(
+            metadata
+                .inputs
+                .into_iter()
+                .map(|input| input.name)
+                .collect(),
+        );
+        self.0.write().unwrap().insert(
+            key,
+            CachedInputs {
+                inputs: inputs.clone(),
+                fetched: Instant::now(),
+            },
+        );
+        Ok(inputs)
+    }
+
+    /// Check that the model declares an input the route relies on, such as its image input, which
+    /// the request cannot be served without.
+    pub(crate) async fn require(
+        &self,
+        client: &mut GrpcInferenceServiceClient<Channel>,
+        model: &str,
+        version: &str,
+        input: &str,
+    ) -> Result<(), AppError> {
+        let inputs = self.get(client, model, version).await?;
+        if !inputs.contains(input) {
+            return Err(AppError::invalid_request(
+                format!(
+                    "the model {} does not declare the input {} set by its route",
+                    model, input
+                ),
+                Some("messages"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Remove the inputs of the requests which are not declared by their model.
+    pub(crate) async fn retain_declared(
+        &self,
+        client: &mut GrpcInferenceServiceClient<Channel>,
+        requests: &mut [ModelInferRequest],
+    ) -> Result<(), AppError> {
+        for request in requests {
+            let inputs = self
+                .get(client, &request.model_name, &request.model_version)
+                .await?;
+            request.inputs.retain(|input| {
+                let declared = inputs.contains(&input.name);
+                if !declared {
+                    tracing::debug!(
+                        "omitting input {} not declared by model {}",
+                        input.name,
+                        request.model_name
+                    );
+                }
+                declared
+            });
+        }
+        Ok(())
+    }
+}
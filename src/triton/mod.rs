@@ -1,4 +1,13 @@
-tonic::include_proto!("inference");
+#[allow(clippy::enum_variant_names)]
+mod inference {
+    tonic::include_proto!("inference");
+}
 
+pub use inference::*;
+
+pub(crate) mod cancellation;
+pub(crate) mod infer;
+pub(crate) mod metadata;
+pub(crate) mod pool;
 pub(crate) mod request;
 pub(crate) mod telemetry;
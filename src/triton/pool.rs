@@ -0,0 +1,445 @@
+//! Triton replicas serving the same models. Inferences are balanced between the replicas, which
+//! are health checked with `ServerReady` and `ModelReady` and ejected while they are failing.
+//!
+//! The replicas are connected lazily, so that the server starts even if triton is not up yet. A
+//! replica is only healthy once it passed a health check, and unreachable replicas are checked
+//! again with an exponential backoff until they are back.
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+use tonic::transport::{Channel, Endpoint};
+use uuid::Uuid;
+
+use super::grpc_inference_service_client::GrpcInferenceServiceClient;
+use super::metadata::ModelInputs;
+use super::{ModelReadyRequest, ServerReadyRequest};
+use crate::config::{LoadBalancing, TritonConfig};
+use crate::request_metrics::record_replica_health;
+
+/// Delay before checking an unreachable replica again, doubled after every failed check up to the
+/// health check interval.
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+
+/// How the replicas are connected, checked and balanced.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolOptions {
+    pub load_balancing: LoadBalancing,
+    pub max_failures: usize,
+    pub health_check_interval: Duration,
+    pub connect_timeout: Duration,
+    pub request_timeout: Option<Duration>,
+    pub keepalive_interval: Duration,
+    pub keepalive_timeout: Duration,
+}
+
+impl PoolOptions {
+    pub fn new(config: &TritonConfig) -> Self {
+        Self {
+            load_balancing: config.load_balancing,
+            max_failures: config.max_failures.max(1),
+            health_check_interval: Duration::from_secs(config.health_check_interval.max(1)),
+            connect_timeout: Duration::from_secs(config.connect_timeout),
+            request_timeout: config.request_timeout.map(Duration::from_secs),
+            keepalive_interval: Duration::from_secs(config.keepalive_interval),
+            keepalive_timeout: Duration::from_secs(config.keepalive_timeout),
+        }
+    }
+
+    /// The lazily connected channel to a triton endpoint.
+    fn channel(&self, endpoint: &str) -> anyhow::Result<Channel> {
+        let mut builder = Endpoint::from_shared(endpoint.to_string())
+            .with_context(|| format!("invalid triton endpoint {}", endpoint))?
+            .connect_timeout(self.connect_timeout)
+            .http2_keep_alive_interval(self.keepalive_interval)
+            .keep_alive_timeout(self.keepalive_timeout)
+            .keep_alive_while_idle(true);
+        if let Some(request_timeout) = self.request_timeout {
+            builder = builder.timeout(request_timeout);
+        }
+        Ok(builder.connect_lazy())
+    }
+}
+
+#[derive(Clone)]
+pub struct TritonPool(Arc<Pool>);
+
+struct Pool {
+    replicas: Vec<Arc<Replica>>,
+    load_balancing: LoadBalancing,
+    /// Round-robin counter.
+    next: AtomicUsize,
+    /// Versions of the routed models, whose readiness gates the readiness of the pool.
+    watched: RwLock<BTreeMap<String, BTreeSet<String>>>,
+    /// Models which completed an inference. Their readiness is checked on every replica to route
+    /// around the replicas where they are not ready, without gating the readiness of the pool.
+    models: RwLock<HashSet<String>>,
+    /// Inputs declared by the models, which may differ from the models of other pools.
+    inputs: ModelInputs,
+    health_check_interval: Duration,
+    health_checks_started: AtomicBool,
+}
+
+pub(crate) struct Replica {
+    index: usize,
+    endpoint: String,
+    client: GrpcInferenceServiceClient<Channel>,
+    in_flight: AtomicUsize,
+    /// Cleared when the replica is ejected, until it passes a health check again.
+    healthy: AtomicBool,
+    failures: AtomicUsize,
+    max_failures: usize,
+    /// The result of the last health check.
+    health: RwLock<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Why the server is not ready.
+    error: Option<String>,
+    /// Whether each model is ready.
+    models: BTreeMap<String, bool>,
+}
+
+/// The health of a replica, as reported by the readiness probe.
+#[derive(Serialize, Debug)]
+pub(crate) struct EndpointHealth {
+    endpoint: String,
+    ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    models: BTreeMap<String, bool>,
+}
+
+impl TritonPool {
+    /// Create the pool of the triton endpoints, which are connected on first use.
+    pub fn new(endpoints: &[String], options: PoolOptions) -> anyhow::Result<Self> {
+        if endpoints.is_empty() {
+            anyhow::bail!("at least one triton endpoint is required");
+        }
+
+        let mut replicas = Vec::with_capacity(endpoints.len());
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            let client = GrpcInferenceServiceClient::new(options.channel(endpoint)?);
+            record_replica_health(endpoint, false);
+            replicas.push(Arc::new(Replica {
+                index,
+                endpoint: endpoint.clone(),
+                client,
+                in_flight: AtomicUsize::new(0),
+                healthy: AtomicBool::new(false),
+                failures: AtomicUsize::new(0),
+                max_failures: options.max_failures,
+                health: Default::default(),
+            }));
+        }
+
+        Ok(Self(Arc::new(Pool {
+            replicas,
+            load_balancing: options.load_balancing,
+            next: AtomicUsize::new(0),
+            watched: Default::default(),
+            models: Default::default(),
+            inputs: Default::default(),
+            health_check_interval: options.health_check_interval,
+            health_checks_started: AtomicBool::new(false),
+        })))
+    }
+
+    /// Whether both pools are the same.
+    pub(crate) fn same(&self, other: &TritonPool) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Whether at least one replica is healthy, with every watched model ready.
+    pub(crate) fn is_ready(&self) -> bool {
+        let watched = self.0.watched.read().unwrap();
+        self.0.replicas.iter().any(|replica| {
+            let health = replica.health.read().unwrap();
+            replica.is_healthy()
+                && watched
+                    .keys()
+                    .all(|model| health.models.get(model).copied().unwrap_or(false))
+        })
+    }
+
+    /// The health of the replicas according to their last health check.
+    pub(crate) fn health(&self) -> Vec<EndpointHealth> {
+        self.0
+            .replicas
+            .iter()
+            .map(|replica| {
+                let health = replica.health.read().unwrap();
+                EndpointHealth {
+                    endpoint: replica.endpoint.clone(),
+                    ready: replica.is_healthy(),
+                    error: health.error.clone(),
+                    models: health.models.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Check the readiness of the version of the model on every replica from the next health
+    /// check, the pool is only ready once it is. An empty version checks the model as a whole.
+    pub(crate) fn watch_model(&self, model: &str, version: &str) {
+        self.0
+            .watched
+            .write()
+            .unwrap()
+            .entry(model.to_string())
+            .or_default()
+            .insert(version.to_string());
+    }
+
+    /// Whether the model is watched or completed an inference.
+    pub(crate) fn knows_model(&self, model: &str) -> bool {
+        self.0.watched.read().unwrap().contains_key(model)
+            || self.0.models.read().unwrap().contains(model)
+    }
+
+    /// The inputs declared by the models served by the pool.
+    pub(crate) fn model_inputs(&self) -> &ModelInputs {
+        &self.0.inputs
+    }
+
+    /// A client of the first healthy replica, for requests other than inferences.
+    pub(crate) fn client(&self) -> GrpcInferenceServiceClient<Channel> {
+        let replicas = &self.0.replicas;
+        replicas
+            .iter()
+            .find(|replica| replica.is_healthy())
+            .unwrap_or(&replicas[0])
+            .client()
+    }
+
+    /// Choose the replica running an inference of the model, other than the `excluded` ones.
+    ///
+    /// Healthy replicas on which the model is ready are preferred. If there is none, any replica
+    /// is tried rather than failing right away.
+    pub(crate) fn pick(&self, model: &str, excluded: &[usize]) -> Option<Arc<Replica>> {
+        let available: Vec<_> = self
+            .0
+            .replicas
+            .iter()
+            .filter(|replica| !excluded.contains(&replica.index))
+            .collect();
+        let ready: Vec<_> = available
+            .iter()
+            .copied()
+            .filter(|replica| replica.is_healthy() && replica.is_ready(model))
+            .collect();
+        let candidates = if ready.is_empty() { available } else { ready };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let replica = match self.0.load_balancing {
+            LoadBalancing::RoundRobin => {
+                candidates[self.0.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            LoadBalancing::LeastInFlight => candidates
+                .iter()
+                .copied()
+                .min_by_key(|replica| replica.in_flight())
+                .unwrap(),
+            LoadBalancing::PowerOfTwoChoices => {
+                let (first, second) = two_choices(candidates.len());
+                let (first, second) = (candidates[first], candidates[second]);
+                if second.in_flight() < first.in_flight() {
+                    second
+                } else {
+                    first
+                }
+            }
+        };
+        Some(replica.clone())
+    }
+
+    /// Check the health of each replica periodically in the background, or sooner while it is
+    /// unreachable. The health checks of a pool are only started once.
+    pub(crate) fn start_health_checks(&self) {
+        if self.0.health_checks_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let interval = self.0.health_check_interval;
+        for replica in &self.0.replicas {
+            let pool = self.0.clone();
+            let replica = replica.clone();
+            tokio::spawn(async move {
+                let mut backoff = MIN_BACKOFF;
+                loop {
+                    let models = pool.checked_models();
+                    if replica.check(&models, interval).await {
+                        backoff = MIN_BACKOFF;
+                        tokio::time::sleep(interval).await;
+                    } else {
+                        tokio::time::sleep(backoff.min(interval)).await;
+                        backoff *= 2;
+                    }
+                }
+            });
+        }
+    }
+
+    fn record_model(&self, model: &str) {
+        if !self.0.models.read().unwrap().contains(model) {
+            self.0.models.write().unwrap().insert(model.to_string());
+        }
+    }
+}
+
+impl Pool {
+    /// The versions of the models to check, the watched versions or the model as a whole for the
+    /// models which are not watched.
+    fn checked_models(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let mut models = self.watched.read().unwrap().clone();
+        for model in self.models.read().unwrap().iter() {
+            models
+                .entry(model.clone())
+                .or_insert_with(|| BTreeSet::from([String::new()]));
+        }
+        models
+    }
+}
+
+/// Two distinct indices picked at random among `len`, or twice the only one.
+fn two_choices(len: usize) -> (usize, usize) {
+    if len < 2 {
+        return (0, 0);
+    }
+    let (first, second) = Uuid::new_v4().as_u64_pair();
+    let first = first as usize % len;
+    let second = second as usize % (len - 1);
+    (first, if second >= first { second + 1 } else { second })
+}
+
+impl Replica {
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+
+    pub(crate) fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub(crate) fn client(&self) -> GrpcInferenceServiceClient<Channel> {
+        self.client.clone()
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn is_ready(&self, model: &str) -> bool {
+        let health = self.health.read().unwrap();
+        health.models.get(model).copied().unwrap_or(true)
+    }
+
+    /// Count an inference in flight on this replica until the returned guard is dropped.
+    pub(crate) fn start(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+
+    /// Record an inference of the model completed by this replica.
+    pub(crate) fn record_success(&self, pool: &TritonPool, model: &str) {
+        self.failures.store(0, Ordering::Relaxed);
+        pool.record_model(model);
+    }
+
+    /// Record a failure to reach this replica, which is ejected after too many in a row.
+    pub(crate) fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.max_failures && self.healthy.swap(false, Ordering::Relaxed) {
+            tracing::warn!(
+                "ejecting triton endpoint {} after {} failures",
+                self.endpoint,
+                failures
+            );
+            record_replica_health(&self.endpoint, false);
+        }
+    }
+
+    /// Check that the server and the models are ready, each call timing out after `timeout`, and
+    /// return whether the server is ready.
+    async fn check(&self, models: &BTreeMap<String, BTreeSet<String>>, timeout: Duration) -> bool {
+        let mut client = self.client();
+        let ready = tokio::time::timeout(timeout, client.server_ready(ServerReadyRequest {})).await;
+        let error = match ready {
+            Ok(Ok(response)) if response.get_ref().ready => None,
+            Ok(Ok(_)) => Some("the server is not ready".to_string()),
+            Ok(Err(status)) => {
+                tracing::debug!("health check of {} failed: {}", self.endpoint, status);
+                Some(status.message().to_string())
+            }
+            Err(_) => Some("the health check timed out".to_string()),
+        };
+        let healthy = error.is_none();
+
+        let mut health = Health {
+            error,
+            models: BTreeMap::new(),
+        };
+        if healthy {
+            for (model, versions) in models {
+                let mut model_ready = true;
+                for version in versions {
+                    let request = ModelReadyRequest {
+                        name: model.clone(),
+                        version: version.clone(),
+                    };
+                    let ready = tokio::time::timeout(timeout, client.model_ready(request)).await;
+                    model_ready &= matches!(ready, Ok(Ok(response)) if response.get_ref().ready);
+                }
+                health.models.insert(model.clone(), model_ready);
+            }
+            self.failures.store(0, Ordering::Relaxed);
+        }
+        *self.health.write().unwrap() = health;
+
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                tracing::info!("triton endpoint {} is ready", self.endpoint);
+            } else {
+                tracing::warn!(
+                    "ejecting triton endpoint {}, it is not ready",
+                    self.endpoint
+                );
+            }
+            record_replica_health(&self.endpoint, healthy);
+        }
+        healthy
+    }
+}
+
+/// An inference in flight on a replica.
+pub(crate) struct InFlight(Arc<Replica>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_two_choices() {
+        assert_eq!((0, 0), two_choices(1));
+        for _ in 0..100 {
+            let (first, second) = two_choices(3);
+            assert!(first < 3 && second < 3);
+            assert_ne!(first, second);
+        }
+    }
+}
@@ -27,7 +27,7 @@ impl Builder {
         })
     }
 
-    fn model_version<S>(self, model_version: S) -> Self
+    pub(crate) fn model_version<S>(self, model_version: S) -> Self
     where
         S: Into<String>,
     {
@@ -37,7 +37,7 @@ impl Builder {
         })
     }
 
-    fn id<S>(self, id: S) -> Self
+    pub(crate) fn id<S>(self, id: S) -> Self
     where
         S: Into<String>,
     {
@@ -64,6 +64,23 @@ impl Builder {
         })
     }
 
+    /// Add an input only if there is data for it.
+    pub(crate) fn optional_input<S, V>(
+        self,
+        name: S,
+        shape: V,
+        data: Option<InferTensorData>,
+    ) -> Self
+    where
+        S: Into<String>,
+        V: Into<Vec<i64>>,
+    {
+        match data {
+            Some(data) => self.input(name, shape, data),
+            None => self,
+        }
+    }
+
     pub(crate) fn output<S>(self, name: S) -> Self
     where
         S: Into<String>,
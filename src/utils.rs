@@ -1,11 +1,15 @@
 use std::fmt;
+use std::fmt::Display;
 use std::marker::PhantomData;
+use std::ops::RangeInclusive;
 use std::str;
 use std::str::Utf8Error;
 
 use bytes::{Buf, Bytes};
 use serde::{de, Deserialize, Deserializer};
 
+use crate::error::AppError;
+
 pub(crate) fn string_or_seq_string<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
 where
     D: Deserializer<'de>,
@@ -50,3 +54,34 @@ pub(crate) fn deserialize_bytes_tensor(encoded_tensor: Vec<u8>) -> Result<Vec<St
     }
     Ok(strs)
 }
+
+pub(crate) fn deserialize_fp32_tensor(encoded_tensor: &[u8]) -> Vec<f32> {
+    encoded_tensor
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+/// Check that a request parameter is within its valid range.
+pub(crate) fn check_range<T>(
+    param: &str,
+    value: T,
+    range: RangeInclusive<T>,
+) -> Result<(), AppError>
+where
+    T: PartialOrd + Display,
+{
+    if range.contains(&value) {
+        return Ok(());
+    }
+    Err(AppError::invalid_request(
+        format!(
+            "{} must be between {} and {}, got {}",
+            param,
+            range.start(),
+            range.end(),
+            value
+        ),
+        Some(param),
+    ))
+}
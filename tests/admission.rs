@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use common::mock_triton::Step;
+use common::{text, MockTriton, TestServer};
+
+mod common;
+
+/// Script a model answering after the given delay.
+fn slow(delay: Duration) -> Vec<Step> {
+    let mut steps = vec![Step::Delay(delay)];
+    steps.extend(text(&["Hello"]));
+    steps
+}
+
+async fn complete(server: &TestServer, model: &str) -> reqwest::Response {
+    server
+        .post(
+            "/v1/completions",
+            json!({"model": model, "prompt": "Say hello"}),
+        )
+        .await
+}
+
+#[tokio::test]
+async fn test_queue() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", slow(Duration::from_millis(300)));
+    // Only the routed models, or the ones which completed an inference, have a queue.
+    let server = TestServer::start(
+        &triton,
+        &[
+            "--model-alias",
+            "llm=ensemble",
+            "--max-concurrency",
+            "1",
+            "--max-queue",
+            "1",
+        ],
+    )
+    .await;
+
+    let (first, second, third) =
+        tokio::join!(complete(&server, "llm"), complete(&server, "llm"), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            complete(&server, "llm").await
+        });
+    // The second request waits for the first one, while the third one finds the queue full.
+    assert_eq!(StatusCode::OK, first.status());
+    assert_eq!(StatusCode::OK, second.status());
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, third.status());
+    assert_eq!(2, triton.requests().len());
+
+    let metrics = server.get("/metrics").await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("openai_trtllm_queue_depth{model=\"llm\"} 0"));
+    assert!(metrics.contains("openai_trtllm_queue_wait_seconds_count{model=\"llm\"} 1"));
+    // Unknown models do not get a queue.
+    assert_eq!(
+        StatusCode::NOT_FOUND,
+        complete(&server, "missing").await.status()
+    );
+    let metrics = server.get("/metrics").await.unwrap().text().await.unwrap();
+    assert!(!metrics.contains("missing"));
+}
+
+#[tokio::test]
+async fn test_max_queue_wait() {
+    let triton = MockTriton::default();
+    triton.script("llama", slow(Duration::from_millis(1500)));
+    let server = TestServer::start(
+        &triton,
+        &[
+            "--model-alias",
+            "llama=llama",
+            "--max-concurrency",
+            "1",
+            "--max-queue-wait",
+            "1",
+        ],
+    )
+    .await;
+
+    let (first, second) = tokio::join!(complete(&server, "llama"), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        complete(&server, "llama").await
+    });
+    assert_eq!(StatusCode::OK, first.status());
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, second.status());
+    let error: Value = second.json().await.unwrap();
+    let message = error["error"]["message"].as_str().unwrap();
+    assert!(message.contains("overloaded"), "{}", message);
+}
@@ -0,0 +1,103 @@
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use common::{text, MockTriton, TempFile, TestServer};
+
+mod common;
+
+async fn start(triton: &MockTriton) -> TestServer {
+    let keys = json!([
+        {"name": "team-a", "key": "sk-team-a", "models": ["llama*"]},
+        {"name": "team-b", "key": "sk-team-b", "enabled": false},
+        {"name": "team-c", "key": "sk-team-c", "expires_at": "2020-01-01T00:00:00Z"},
+    ]);
+    // The keys are only read again on SIGHUP.
+    let keys_file = TempFile::json(&keys);
+    TestServer::start(
+        triton,
+        &["--api-key", "sk-admin", "--api-keys-file", keys_file.path()],
+    )
+    .await
+}
+
+async fn complete(server: &TestServer, api_key: Option<&str>, model: &str) -> reqwest::Response {
+    server
+        .post_with_key(
+            api_key,
+            "/v1/completions",
+            json!({"model": model, "prompt": "Say hello"}),
+        )
+        .await
+}
+
+#[tokio::test]
+async fn test_api_keys() {
+    let triton = MockTriton::default();
+    triton
+        .script("llama3", text(&["Hello"]))
+        .script("mistral", text(&["Hello"]));
+    let server = start(&triton).await;
+
+    let response = complete(&server, Some("sk-team-a"), "llama3").await;
+    assert_eq!(StatusCode::OK, response.status());
+    let response = complete(&server, Some("sk-admin"), "mistral").await;
+    assert_eq!(StatusCode::OK, response.status());
+
+    // A valid key asking for a model it is not allowed to use is forbidden.
+    let response = complete(&server, Some("sk-team-a"), "mistral").await;
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("permission_error", error["error"]["type"]);
+    let response = server
+        .post_with_key(
+            Some("sk-team-a"),
+            "/v1/chat/completions",
+            json!({"model": "mistral", "messages": [{"role": "user", "content": "Hi"}]}),
+        )
+        .await;
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+    for api_key in [None, Some("sk-wrong"), Some("sk-team-b"), Some("sk-team-c")] {
+        let response = complete(&server, api_key, "llama3").await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+    assert_eq!(2, triton.requests().len());
+
+    // The probes and the metrics need no key.
+    for path in ["/health/live", "/health/ready", "/health_check", "/metrics"] {
+        let response = server.get_with_key(None, path).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_api_key_models() {
+    let triton = MockTriton::default();
+    triton
+        .script("llama3", text(&["Hello"]))
+        .script("mistral", text(&["Hello"]));
+    let server = start(&triton).await;
+
+    let response = server
+        .get_with_key(Some("sk-team-a"), "/v1/models")
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let models: Value = response.json().await.unwrap();
+    assert_eq!("llama3", models["data"][0]["id"]);
+    assert_eq!(1, models["data"].as_array().unwrap().len());
+    let models: Value = server
+        .get("/v1/models")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(2, models["data"].as_array().unwrap().len());
+
+    let response = server
+        .get_with_key(Some("sk-team-a"), "/v1/models/mistral")
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+}
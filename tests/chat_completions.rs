@@ -0,0 +1,610 @@
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use common::{events, text, MockTriton, TempFile, TestServer};
+
+mod common;
+
+#[tokio::test]
+async fn test_chat_completions() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", " there"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [{"role": "user", "content": "Hi!"}],
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    let choice = &completion["choices"][0];
+    assert_eq!("assistant", choice["message"]["role"]);
+    assert_eq!("Hello there", choice["message"]["content"]);
+    assert_eq!("stop", choice["finish_reason"]);
+
+    let requests = triton.requests();
+    let text_input = &requests[0].input("text_input").unwrap().bytes_contents[0];
+    assert!(String::from_utf8_lossy(text_input).contains("Hi!"));
+}
+
+#[tokio::test]
+async fn test_chat_completions_stream() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", " there"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [{"role": "user", "content": "Hi!"}],
+                "stream": true,
+                "stream_options": {"include_usage": true},
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let events = events(response).await;
+    assert_eq!("[DONE]", events.last().unwrap().data);
+
+    let chunks: Vec<_> = events[..events.len() - 1]
+        .iter()
+        .map(|event| event.json())
+        .collect();
+    let content: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!("Hello there", content);
+    let finish_reasons: Vec<_> = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["finish_reason"].as_str())
+        .collect();
+    assert_eq!(vec!["stop"], finish_reasons);
+
+    let usage_chunk = chunks.last().unwrap();
+    assert!(usage_chunk["choices"].as_array().unwrap().is_empty());
+    assert!(usage_chunk["usage"].is_object());
+}
+
+#[tokio::test]
+async fn test_chat_completions_stop_sequence() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", "###"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    // The stop sequence is stripped, and it is the last token allowed, yet the model did not run
+    // out of tokens.
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [{"role": "user", "content": "Hi!"}],
+                "max_tokens": 2,
+                "stop": ["###"],
+                "stream": true,
+            }),
+        )
+        .await;
+    let events = events(response).await;
+    let chunks: Vec<_> = events[..events.len() - 1]
+        .iter()
+        .map(|event| event.json())
+        .collect();
+    let content: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!("Hello", content);
+    let finish_reasons: Vec<_> = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["finish_reason"].as_str())
+        .collect();
+    assert_eq!(vec!["stop"], finish_reasons);
+}
+
+#[tokio::test]
+async fn test_chat_completions_logprobs() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", " there"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [{"role": "user", "content": "Hi!"}],
+                "logprobs": true,
+                "top_logprobs": 1,
+                "stream": true,
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let events = events(response).await;
+    let tokens: Vec<_> = events[..events.len() - 1]
+        .iter()
+        .map(|event| event.json())
+        .filter(|chunk| chunk["choices"][0]["delta"]["content"].is_string())
+        .map(|chunk| {
+            let logprobs = &chunk["choices"][0]["logprobs"]["content"];
+            assert_eq!(-0.25, logprobs[0]["logprob"]);
+            // Only the sampled token is known.
+            assert_eq!(1, logprobs[0]["top_logprobs"].as_array().unwrap().len());
+            logprobs[0]["token"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(vec!["Hello", " there"], tokens);
+
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [{"role": "user", "content": "Hi!"}],
+                "top_logprobs": 1,
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+    // Only the log probability of the sampled token is known.
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [{"role": "user", "content": "Hi!"}],
+                "logprobs": true,
+                "top_logprobs": 2,
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("top_logprobs", error["error"]["param"]);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let triton = MockTriton::default();
+    triton
+        .script("metrics-model", text(&["Hello"]))
+        .script("unrouted-model", text(&["Hello"]));
+    let server = TestServer::start(&triton, &["--model-alias", "metrics=metrics-model"]).await;
+
+    let chat = |model: &str| {
+        server.post(
+            "/v1/chat/completions",
+            json!({"model": model, "messages": [{"role": "user", "content": "Hi!"}]}),
+        )
+    };
+    assert_eq!(StatusCode::OK, chat("metrics").await.status());
+    // Unrouted models are labelled once they are known to exist.
+    assert_eq!(StatusCode::OK, chat("unrouted-model").await.status());
+    assert_eq!(StatusCode::OK, chat("unrouted-model").await.status());
+    assert_eq!(StatusCode::NOT_FOUND, chat("missing-model").await.status());
+
+    let metrics = server.get("/metrics").await.unwrap().text().await.unwrap();
+    let requests = |model: &str, status: &str| {
+        metrics.lines().find(|line| {
+            line.starts_with("openai_trtllm_requests_total{")
+                && line.contains(&format!("model=\"{}\"", model))
+                && line.contains(&format!("status=\"{}\"", status))
+        })
+    };
+    assert!(requests("metrics", "200").unwrap().ends_with(" 1"));
+    assert!(requests("unrouted-model", "200").unwrap().ends_with(" 1"));
+    assert!(requests("unknown", "404").is_some());
+    assert!(!metrics.contains("missing-model"));
+    // The in flight requests are counted down under the label they were counted up with.
+    assert!(metrics.lines().any(|line| {
+        line.starts_with("openai_trtllm_requests_in_flight{")
+            && line.contains("model=\"unknown\"")
+            && line.ends_with(" 0")
+    }));
+}
+
+fn json_schema_request(model: &str) -> Value {
+    json!({
+        "model": model,
+        "messages": [{"role": "user", "content": "Weather in Paris?"}],
+        "response_format": {
+            "type": "json_schema",
+            "json_schema": {
+                "name": "weather",
+                "schema": {
+                    "type": "object",
+                    "properties": {"city": {"type": "string"}},
+                    "required": ["city"],
+                },
+                "strict": true,
+            },
+        },
+    })
+}
+
+fn text_input(request: &common::mock_triton::inference::ModelInferRequest) -> String {
+    String::from_utf8_lossy(&request.input("text_input").unwrap().bytes_contents[0]).to_string()
+}
+
+#[tokio::test]
+async fn test_json_schema() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["{\"city\": ", "\"Paris\"}"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post("/v1/chat/completions", json_schema_request("ensemble"))
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!(
+        "{\"city\": \"Paris\"}",
+        completion["choices"][0]["message"]["content"]
+    );
+
+    // The format is given in the prompt, since the model has no guided decoding inputs.
+    let request = &triton.requests()[0];
+    assert!(text_input(request).contains("\"required\":[\"city\"]"));
+    assert!(request.input("guided_decoding_guide").is_none());
+}
+
+#[tokio::test]
+async fn test_json_schema_invalid_output() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["{\"town\": \"Paris\"}"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post("/v1/chat/completions", json_schema_request("ensemble"))
+        .await;
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("invalid_model_output", error["error"]["code"]);
+    assert_eq!(1, triton.requests().len());
+}
+
+#[tokio::test]
+async fn test_json_schema_retries() {
+    let triton = MockTriton::default();
+    triton
+        .script("ensemble", text(&["{\"city\": \"Paris\"}"]))
+        .script_next("ensemble", text(&["Sure! It is sunny in Paris."]));
+    let tokenizer = TempFile::json(&json!({
+        "version": "1.0",
+        "model": {"type": "WordLevel", "vocab": {"[UNK]": 0}, "unk_token": "[UNK]"},
+        "pre_tokenizer": {"type": "Whitespace"},
+    }));
+    let server = TestServer::start(
+        &triton,
+        &[
+            "--response-format-retries",
+            "2",
+            "--tokenizer",
+            tokenizer.path(),
+        ],
+    )
+    .await;
+
+    let response = server
+        .post("/v1/chat/completions", json_schema_request("ensemble"))
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!(
+        "{\"city\": \"Paris\"}",
+        completion["choices"][0]["message"]["content"]
+    );
+    // The tokens of the discarded output are counted, 8 tokens and 6 tokens.
+    assert_eq!(14, completion["usage"]["completion_tokens"]);
+
+    let requests = triton.requests();
+    assert_eq!(2, requests.len());
+    assert_ne!(
+        requests[0].input("random_seed"),
+        requests[1].input("random_seed")
+    );
+}
+
+#[tokio::test]
+async fn test_json_schema_guided_decoding() {
+    let triton = MockTriton::default();
+    triton
+        .script("ensemble", text(&["{\"city\": \"Paris\"}"]))
+        .declare_inputs(&["guided_decoding_guide_type", "guided_decoding_guide"]);
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post("/v1/chat/completions", json_schema_request("ensemble"))
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+
+    let request = &triton.requests()[0];
+    assert!(!text_input(request).contains("JSON schema"));
+    let guide_type = request.input("guided_decoding_guide_type").unwrap();
+    assert_eq!(vec![b"json_schema".to_vec()], guide_type.bytes_contents);
+    let guide: Value = serde_json::from_slice(
+        &request
+            .input("guided_decoding_guide")
+            .unwrap()
+            .bytes_contents[0],
+    )
+    .unwrap();
+    assert_eq!(json!(["city"]), guide["required"]);
+}
+
+#[tokio::test]
+async fn test_content_parts() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [
+                    {"role": "system", "content": [{"type": "text", "text": "Be brief."}]},
+                    {"role": "user", "content": [
+                        {"type": "text", "text": "Hi"},
+                        {"type": "text", "text": " there!"},
+                    ]},
+                ],
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let requests = triton.requests();
+    let text_input = &requests[0].input("text_input").unwrap().bytes_contents[0];
+    assert_eq!(
+        "System: Be brief.\nUser: Hi there!\nASSISTANT:",
+        String::from_utf8_lossy(text_input)
+    );
+
+    // Text-only models do not accept images.
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "ensemble",
+                "messages": [{"role": "user", "content": [
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw=="}},
+                ]}],
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!(
+        "the model ensemble does not accept images",
+        error["error"]["message"]
+    );
+}
+
+#[tokio::test]
+async fn test_image_input() {
+    let triton = MockTriton::default();
+    triton
+        .script("llava", text(&["A cat."]))
+        .declare_inputs(&["images"]);
+    let routes = json!([{
+        "id": "llava-1.5-7b",
+        "triton_model": "llava",
+        "image_input": "images",
+    }]);
+    let server = TestServer::with_routes(&triton, routes, &[]).await;
+
+    let image = |data: &str| json!({"type": "image_url", "image_url": {"url": data}});
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "llava-1.5-7b",
+                "messages": [{"role": "user", "content": [
+                    image("data:image/png;base64,iVBORw=="),
+                    {"type": "text", "text": "\nWhat is this?"},
+                    image("data:image/jpeg;base64,/9j/"),
+                ]}],
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let requests = triton.requests();
+    let text_input = &requests[0].input("text_input").unwrap().bytes_contents[0];
+    assert_eq!(
+        "User: <image>\nWhat is this?<image>\nASSISTANT:",
+        String::from_utf8_lossy(text_input)
+    );
+    let images = requests[0].input("images").unwrap();
+    assert_eq!(
+        vec![b"\x89PNG".to_vec(), b"\xff\xd8\xff".to_vec()],
+        images.bytes_contents
+    );
+
+    // Remote images are not fetched.
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "llava-1.5-7b",
+                "messages": [{"role": "user", "content": [image("https://example.com/cat.png")]}],
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("remote images are not fetched"));
+}
+
+#[tokio::test]
+async fn test_image_input_limits() {
+    let triton = MockTriton::default();
+    triton.script("llava-undeclared", text(&["A cat."]));
+    let routes =
+        json!([{"id": "llava", "triton_model": "llava-undeclared", "image_input": "images"}]);
+    let server = TestServer::with_routes(&triton, routes, &["--max-body-size", "1024"]).await;
+
+    let chat = |url: String| {
+        server.post(
+            "/v1/chat/completions",
+            json!({
+                "model": "llava",
+                "messages": [{"role": "user", "content": [
+                    {"type": "image_url", "image_url": {"url": url}},
+                ]}],
+            }),
+        )
+    };
+    // The image input of the route must be declared by the model.
+    let response = chat("data:image/png;base64,iVBORw==".to_string()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("messages", error["error"]["param"]);
+    let message = error["error"]["message"].as_str().unwrap();
+    assert_eq!(
+        "the model llava-undeclared does not declare the input images set by its route",
+        message
+    );
+    assert!(triton.requests().is_empty());
+
+    let response = chat(format!("data:image/png;base64,{}", "A".repeat(2048))).await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: Value = response.json().await.unwrap();
+    let message = error["error"]["message"].as_str().unwrap();
+    assert!(message.contains("length limit exceeded"), "{}", message);
+}
+
+#[tokio::test]
+async fn test_forced_tool_choice() {
+    let triton = MockTriton::default();
+    triton
+        .script_next(
+            "tools-model",
+            text(&["<tool_call>{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}</tool_call>\nOne moment."]),
+        )
+        .script("tools-model", text(&["It is sunny."]));
+    let server = TestServer::start(&triton, &[]).await;
+    let request = json!({
+        "model": "tools-model",
+        "messages": [{"role": "user", "content": "Weather in Paris?"}],
+        "tools": [
+            {"type": "function", "function": {"name": "get_weather", "parameters": {}}},
+            {"type": "function", "function": {"name": "get_time", "parameters": {}}},
+        ],
+        "tool_choice": {"type": "function", "function": {"name": "get_weather"}},
+    });
+
+    let response = server.post("/v1/chat/completions", request.clone()).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    let message = &completion["choices"][0]["message"];
+    assert_eq!("get_weather", message["tool_calls"][0]["function"]["name"]);
+    // The text after the tool call is kept.
+    assert_eq!("One moment.", message["content"]);
+
+    // The forced function must be called.
+    let response = server.post("/v1/chat/completions", request).await;
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("invalid_model_output", error["error"]["code"]);
+    assert_eq!("tool_choice", error["error"]["param"]);
+}
+
+#[tokio::test]
+async fn test_metrics_of_held_back_output() {
+    let triton = MockTriton::default();
+    triton.script(
+        "held-back-model",
+        text(&["<tool_call>{\"name\": \"get_time\"}", "</tool_call>"]),
+    );
+    let server = TestServer::start(&triton, &["--model-alias", "held-back=held-back-model"]).await;
+
+    // The output is held back until the tool calls are complete.
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "held-back",
+                "messages": [{"role": "user", "content": "What time is it?"}],
+                "tools": [{"type": "function", "function": {"name": "get_time"}}],
+                "tool_choice": "required",
+                "stream": true,
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    events(response).await;
+
+    let metrics = server.get("/metrics").await.unwrap().text().await.unwrap();
+    let count = |metric: &str| {
+        metrics.lines().find(|line| {
+            line.starts_with(&format!("{}_count{{", metric)) && line.contains("model=\"held-back\"")
+        })
+    };
+    assert!(count("openai_trtllm_time_to_first_token_seconds")
+        .unwrap()
+        .ends_with(" 1"));
+    assert!(count("openai_trtllm_inter_token_latency_seconds")
+        .unwrap()
+        .ends_with(" 1"));
+}
+
+#[tokio::test]
+async fn test_chat_completions_default_stop_words() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", "</s>", " there"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    // Only the stop sequences of the client are cut from the output, as for the completions.
+    for stream in [false, true] {
+        let response = server
+            .post(
+                "/v1/chat/completions",
+                json!({
+                    "model": "ensemble",
+                    "messages": [{"role": "user", "content": "Hi!"}],
+                    "stream": stream,
+                }),
+            )
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let content = if stream {
+            events(response).await[..]
+                .iter()
+                .filter(|event| event.data != "[DONE]")
+                .filter_map(|event| {
+                    event.json()["choices"][0]["delta"]["content"]
+                        .as_str()
+                        .map(str::to_string)
+                })
+                .collect::<String>()
+        } else {
+            let completion: Value = response.json().await.unwrap();
+            completion["choices"][0]["message"]["content"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!("Hello</s> there", content);
+    }
+
+    // The default stop words are still sent to triton.
+    let requests = triton.requests();
+    assert_eq!(
+        vec![b"</s>".to_vec()],
+        requests[0].input("stop_words").unwrap().bytes_contents
+    );
+}
@@ -0,0 +1,480 @@
+//! An in-process fake of triton's gRPC inference service, replaying scripted responses.
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_stream::{stream, try_stream};
+use tokio::net::TcpListener;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+use inference::grpc_inference_service_server::{GrpcInferenceService, GrpcInferenceServiceServer};
+use inference::model_infer_response::InferOutputTensor;
+use inference::model_metadata_response::TensorMetadata;
+use inference::*;
+
+#[allow(clippy::enum_variant_names)]
+pub mod inference {
+    tonic::include_proto!("inference");
+}
+
+/// Log probability of every token generated for requests with `return_log_probs`, each text
+/// step being a single token.
+pub const TOKEN_LOGPROB: f32 = -0.25;
+
+/// Inputs of the ensemble model of tensorrtllm_backend, declared by every scripted model.
+const ENSEMBLE_INPUTS: &[&str] = &[
+    "text_input",
+    "max_tokens",
+    "bad_words",
+    "stop_words",
+    "end_id",
+    "pad_id",
+    "top_k",
+    "top_p",
+    "temperature",
+    "length_penalty",
+    "repetition_penalty",
+    "min_length",
+    "presence_penalty",
+    "frequency_penalty",
+    "random_seed",
+    "return_log_probs",
+    "beam_width",
+    "stream",
+];
+
+/// A step of the script replayed for each inference request of a model.
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// Send a response whose text_output is the given text.
+    Text(String),
+    /// Send a response with an error message, as triton does when an inference fails.
+    ErrorMessage(String),
+    /// Fail the gRPC call with the given status.
+    Status(Code, String),
+    /// Wait before the next step.
+    Delay(Duration),
+    /// Send a response whose first requested output is the given FP32 embedding, as embedding
+    /// models do.
+    Embedding(Vec<f32>),
+}
+
+/// Script streaming the given chunks of text.
+pub fn text(chunks: &[&str]) -> Vec<Step> {
+    chunks
+        .iter()
+        .map(|chunk| Step::Text(chunk.to_string()))
+        .collect()
+}
+
+/// A fake triton server. Models only exist once they are scripted, other models are reported as
+/// unknown like triton does. Scripted models are served at version 1.
+#[derive(Clone, Default)]
+pub struct MockTriton {
+    scripts: Arc<Mutex<HashMap<String, Vec<Step>>>>,
+    next_scripts: Arc<Mutex<HashMap<String, VecDeque<Vec<Step>>>>>,
+    inputs: Arc<Mutex<Vec<String>>>,
+    requests: Arc<Mutex<Vec<ModelInferRequest>>>,
+    not_ready: Arc<AtomicBool>,
+}
+
+impl MockTriton {
+    /// Set the steps replayed for every inference request of the model.
+    pub fn script(&self, model: &str, steps: Vec<Step>) -> &Self {
+        self.scripts
+            .lock()
+            .unwrap()
+            .insert(model.to_string(), steps);
+        self
+    }
+
+    /// Set the steps replayed for the next inference request of the model only, before falling
+    /// back to the script of the model.
+    pub fn script_next(&self, model: &str, steps: Vec<Step>) -> &Self {
+        self.next_scripts
+            .lock()
+            .unwrap()
+            .entry(model.to_string())
+            .or_default()
+            .push_back(steps);
+        self
+    }
+
+    /// Unload the model, which is then unknown.
+    pub fn unload(&self, model: &str) -> &Self {
+        self.scripts.lock().unwrap().remove(model);
+        self
+    }
+
+    /// Declare inputs in the metadata of every model, besides the ones of the ensemble model.
+    pub fn declare_inputs(&self, inputs: &[&str]) -> &Self {
+        let mut declared = self.inputs.lock().unwrap();
+        declared.extend(inputs.iter().map(|input| input.to_string()));
+        self
+    }
+
+    /// Set whether the server reports itself as ready.
+    pub fn set_ready(&self, ready: bool) -> &Self {
+        self.not_ready.store(!ready, Ordering::Relaxed);
+        self
+    }
+
+    /// The inference requests received so far, including the requests stopping an inference.
+    pub fn requests(&self) -> Vec<ModelInferRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Serve the fake on a random local port and return its endpoint.
+    pub async fn serve(&self) -> String {
+        self.serve_at("127.0.0.1:0").await
+    }
+
+    /// Serve the fake on the given local address and return its endpoint.
+    pub async fn serve_at(&self, address: &str) -> String {
+        let listener = TcpListener::bind(address).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = stream! {
+            loop {
+                yield listener.accept().await.map(|(stream, _)| stream);
+            }
+        };
+
+        let service = GrpcInferenceServiceServer::new(self.clone());
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+        format!("http://{}", address)
+    }
+
+    fn steps(&self, model: &str) -> Option<Vec<Step>> {
+        self.scripts.lock().unwrap().get(model).cloned()
+    }
+
+    fn next_steps(&self, model: &str) -> Option<Vec<Step>> {
+        let next_steps = self
+            .next_scripts
+            .lock()
+            .unwrap()
+            .get_mut(model)
+            .and_then(VecDeque::pop_front);
+        next_steps.or_else(|| self.steps(model))
+    }
+}
+
+fn unknown_model(model: &str) -> String {
+    format!("Request for unknown model: '{}' is not found", model)
+}
+
+/// Respond with the text of the `tokens`-th generated token.
+fn text_response(
+    request: &ModelInferRequest,
+    text: &str,
+    tokens: usize,
+) -> ModelStreamInferResponse {
+    // Serialized BYTES tensor, each element prefixed by its length.
+    let mut raw_content = (text.len() as u32).to_le_bytes().to_vec();
+    raw_content.extend_from_slice(text.as_bytes());
+
+    let mut outputs = vec![InferOutputTensor {
+        name: "text_output".to_string(),
+        datatype: "BYTES".to_string(),
+        shape: vec![1, 1],
+        ..Default::default()
+    }];
+    let mut raw_output_contents = vec![raw_content];
+    if request.returns_log_probs() {
+        let cum_log_prob = TOKEN_LOGPROB * tokens as f32;
+        for (name, shape, value) in [
+            ("cum_log_probs", vec![1, 1], cum_log_prob),
+            ("output_log_probs", vec![1, 1, 1], TOKEN_LOGPROB),
+        ] {
+            outputs.push(InferOutputTensor {
+                name: name.to_string(),
+                datatype: "FP32".to_string(),
+                shape,
+                ..Default::default()
+            });
+            raw_output_contents.push(value.to_le_bytes().to_vec());
+        }
+    }
+
+    ModelStreamInferResponse {
+        error_message: String::new(),
+        infer_response: Some(ModelInferResponse {
+            model_name: request.model_name.clone(),
+            id: request.id.clone(),
+            outputs,
+            raw_output_contents,
+            ..Default::default()
+        }),
+    }
+}
+
+/// Respond with the embedding in the first output requested, `embeddings` by default.
+fn embedding_response(request: &ModelInferRequest, embedding: &[f32]) -> ModelStreamInferResponse {
+    let name = request
+        .outputs
+        .first()
+        .map_or("embeddings", |output| output.name.as_str());
+    ModelStreamInferResponse {
+        error_message: String::new(),
+        infer_response: Some(ModelInferResponse {
+            model_name: request.model_name.clone(),
+            id: request.id.clone(),
+            outputs: vec![InferOutputTensor {
+                name: name.to_string(),
+                datatype: "FP32".to_string(),
+                shape: vec![1, embedding.len() as i64],
+                ..Default::default()
+            }],
+            raw_output_contents: vec![embedding.iter().flat_map(|x| x.to_le_bytes()).collect()],
+            ..Default::default()
+        }),
+    }
+}
+
+fn error_response(message: &str) -> ModelStreamInferResponse {
+    ModelStreamInferResponse {
+        error_message: message.to_string(),
+        infer_response: None,
+    }
+}
+
+impl ModelInferRequest {
+    /// Get the contents of the input with the given name.
+    pub fn input(&self, name: &str) -> Option<&InferTensorContents> {
+        self.inputs
+            .iter()
+            .find(|input| input.name == name)
+            .and_then(|input| input.contents.as_ref())
+    }
+
+    /// Whether the request stops a previous inference rather than starting one.
+    pub fn is_stop(&self) -> bool {
+        self.input("stop")
+            .is_some_and(|contents| contents.bool_contents == [true])
+    }
+
+    /// Whether the request asks for the log probabilities of the generated tokens.
+    pub fn returns_log_probs(&self) -> bool {
+        self.input("return_log_probs")
+            .is_some_and(|contents| contents.bool_contents == [true])
+    }
+}
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<ModelStreamInferResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl GrpcInferenceService for MockTriton {
+    async fn server_live(
+        &self,
+        _: Request<ServerLiveRequest>,
+    ) -> Result<Response<ServerLiveResponse>, Status> {
+        Ok(Response::new(ServerLiveResponse { live: true }))
+    }
+
+    async fn server_ready(
+        &self,
+        _: Request<ServerReadyRequest>,
+    ) -> Result<Response<ServerReadyResponse>, Status> {
+        let ready = !self.not_ready.load(Ordering::Relaxed);
+        Ok(Response::new(ServerReadyResponse { ready }))
+    }
+
+    async fn model_ready(
+        &self,
+        request: Request<ModelReadyRequest>,
+    ) -> Result<Response<ModelReadyResponse>, Status> {
+        let request = request.into_inner();
+        let ready = self.steps(&request.name).is_some()
+            && (request.version.is_empty() || request.version == "1");
+        Ok(Response::new(ModelReadyResponse { ready }))
+    }
+
+    async fn server_metadata(
+        &self,
+        _: Request<ServerMetadataRequest>,
+    ) -> Result<Response<ServerMetadataResponse>, Status> {
+        Err(Status::unimplemented("server_metadata"))
+    }
+
+    async fn model_metadata(
+        &self,
+        request: Request<ModelMetadataRequest>,
+    ) -> Result<Response<ModelMetadataResponse>, Status> {
+        let model = request.into_inner().name;
+        if self.steps(&model).is_none() {
+            return Err(Status::not_found(unknown_model(&model)));
+        }
+
+        Ok(Response::new(ModelMetadataResponse {
+            name: model,
+            platform: "ensemble".to_string(),
+            inputs: ENSEMBLE_INPUTS
+                .iter()
+                .map(|name| name.to_string())
+                .chain(self.inputs.lock().unwrap().iter().cloned())
+                .map(|name| TensorMetadata {
+                    name,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }))
+    }
+
+    async fn model_infer(
+        &self,
+        _: Request<ModelInferRequest>,
+    ) -> Result<Response<ModelInferResponse>, Status> {
+        Err(Status::unimplemented("model_infer"))
+    }
+
+    type ModelStreamInferStream = ResponseStream;
+
+    async fn model_stream_infer(
+        &self,
+        request: Request<Streaming<ModelInferRequest>>,
+    ) -> Result<Response<Self::ModelStreamInferStream>, Status> {
+        let mut requests = request.into_inner();
+        let mock = self.clone();
+
+        let responses = try_stream! {
+            while let Some(request) = requests.next().await {
+                let request = request?;
+                mock.requests.lock().unwrap().push(request.clone());
+                if request.is_stop() {
+                    continue;
+                }
+
+                let Some(steps) = mock.next_steps(&request.model_name) else {
+                    yield error_response(&unknown_model(&request.model_name));
+                    continue;
+                };
+                let mut tokens = 0;
+                for step in steps {
+                    match step {
+                        Step::Text(text) => {
+                            tokens += 1;
+                            yield text_response(&request, &text, tokens);
+                        }
+                        Step::ErrorMessage(message) => yield error_response(&message),
+                        Step::Status(code, message) => Err(Status::new(code, message))?,
+                        Step::Delay(duration) => tokio::time::sleep(duration).await,
+                        Step::Embedding(embedding) => yield embedding_response(&request, &embedding),
+                    }
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(responses)))
+    }
+
+    async fn model_config(
+        &self,
+        _: Request<ModelConfigRequest>,
+    ) -> Result<Response<ModelConfigResponse>, Status> {
+        Err(Status::unimplemented("model_config"))
+    }
+
+    async fn model_statistics(
+        &self,
+        _: Request<ModelStatisticsRequest>,
+    ) -> Result<Response<ModelStatisticsResponse>, Status> {
+        Err(Status::unimplemented("model_statistics"))
+    }
+
+    async fn repository_index(
+        &self,
+        _: Request<RepositoryIndexRequest>,
+    ) -> Result<Response<RepositoryIndexResponse>, Status> {
+        let models = self
+            .scripts
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|name| repository_index_response::ModelIndex {
+                name: name.clone(),
+                version: "1".to_string(),
+                state: "READY".to_string(),
+                reason: String::new(),
+            })
+            .collect();
+        Ok(Response::new(RepositoryIndexResponse { models }))
+    }
+
+    async fn repository_model_load(
+        &self,
+        _: Request<RepositoryModelLoadRequest>,
+    ) -> Result<Response<RepositoryModelLoadResponse>, Status> {
+        Err(Status::unimplemented("repository_model_load"))
+    }
+
+    async fn repository_model_unload(
+        &self,
+        _: Request<RepositoryModelUnloadRequest>,
+    ) -> Result<Response<RepositoryModelUnloadResponse>, Status> {
+        Err(Status::unimplemented("repository_model_unload"))
+    }
+
+    async fn system_shared_memory_status(
+        &self,
+        _: Request<SystemSharedMemoryStatusRequest>,
+    ) -> Result<Response<SystemSharedMemoryStatusResponse>, Status> {
+        Err(Status::unimplemented("system_shared_memory_status"))
+    }
+
+    async fn system_shared_memory_register(
+        &self,
+        _: Request<SystemSharedMemoryRegisterRequest>,
+    ) -> Result<Response<SystemSharedMemoryRegisterResponse>, Status> {
+        Err(Status::unimplemented("system_shared_memory_register"))
+    }
+
+    async fn system_shared_memory_unregister(
+        &self,
+        _: Request<SystemSharedMemoryUnregisterRequest>,
+    ) -> Result<Response<SystemSharedMemoryUnregisterResponse>, Status> {
+        Err(Status::unimplemented("system_shared_memory_unregister"))
+    }
+
+    async fn cuda_shared_memory_status(
+        &self,
+        _: Request<CudaSharedMemoryStatusRequest>,
+    ) -> Result<Response<CudaSharedMemoryStatusResponse>, Status> {
+        Err(Status::unimplemented("cuda_shared_memory_status"))
+    }
+
+    async fn cuda_shared_memory_register(
+        &self,
+        _: Request<CudaSharedMemoryRegisterRequest>,
+    ) -> Result<Response<CudaSharedMemoryRegisterResponse>, Status> {
+        Err(Status::unimplemented("cuda_shared_memory_register"))
+    }
+
+    async fn cuda_shared_memory_unregister(
+        &self,
+        _: Request<CudaSharedMemoryUnregisterRequest>,
+    ) -> Result<Response<CudaSharedMemoryUnregisterResponse>, Status> {
+        Err(Status::unimplemented("cuda_shared_memory_unregister"))
+    }
+
+    async fn trace_setting(
+        &self,
+        _: Request<TraceSettingRequest>,
+    ) -> Result<Response<TraceSettingResponse>, Status> {
+        Err(Status::unimplemented("trace_setting"))
+    }
+
+    async fn log_settings(
+        &self,
+        _: Request<LogSettingsRequest>,
+    ) -> Result<Response<LogSettingsResponse>, Status> {
+        Err(Status::unimplemented("log_settings"))
+    }
+}
@@ -0,0 +1,200 @@
+//! Helpers to run the server against a [`MockTriton`] in integration tests.
+#![allow(dead_code)]
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::Value;
+use uuid::Uuid;
+
+use openai_trtllm::config::Config;
+use openai_trtllm::startup::run_server;
+
+// Not every test uses every helper.
+#[allow(unused_imports)]
+pub use mock_triton::{text, MockTriton};
+
+pub mod mock_triton;
+
+/// A server running against a fake triton.
+pub struct TestServer {
+    address: String,
+    client: reqwest::Client,
+    /// The `--api-key` of the server, sent with every request.
+    api_key: Option<String>,
+}
+
+impl TestServer {
+    /// Start the server with the given additional command line arguments, and wait until it is
+    /// ready.
+    pub async fn start(triton: &MockTriton, args: &[&str]) -> Self {
+        let triton_endpoint = triton.serve().await;
+        let server = Self::start_at(&triton_endpoint, args).await;
+        server.wait_ready().await;
+        server
+    }
+
+    /// Start the server with the given model routes, like [`TestServer::start`].
+    pub async fn with_routes(triton: &MockTriton, routes: Value, args: &[&str]) -> Self {
+        // The routes are only read on startup.
+        let routes_file = TempFile::json(&routes);
+        let mut routes_args = vec!["--model-routes", routes_file.path()];
+        routes_args.extend_from_slice(args);
+        Self::start(triton, &routes_args).await
+    }
+
+    /// Start the server against the triton endpoint, and wait until it answers, ready or not.
+    pub async fn start_at(triton_endpoint: &str, args: &[&str]) -> Self {
+        let port = free_port().to_string();
+        let mut command = vec![
+            "openai_trtllm",
+            "--host",
+            "127.0.0.1",
+            "--port",
+            &port,
+            "--triton-endpoint",
+            triton_endpoint,
+        ];
+        command.extend_from_slice(args);
+        let config = Config::load_from(command).unwrap();
+        tokio::spawn(async move { run_server(config).await.unwrap() });
+
+        let api_key = args
+            .iter()
+            .position(|arg| *arg == "--api-key")
+            .map(|index| args[index + 1].to_string());
+        let server = Self {
+            address: format!("http://127.0.0.1:{}", port),
+            client: reqwest::Client::new(),
+            api_key,
+        };
+        for _ in 0..100 {
+            if server.get("/health_check").await.is_ok() {
+                return server;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server did not start");
+    }
+
+    /// Wait until the health check reports the server as ready.
+    pub async fn wait_ready(&self) {
+        for _ in 0..100 {
+            let response = self.get("/health_check").await.unwrap();
+            if response.status().is_success() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server is not ready");
+    }
+
+    pub async fn get(&self, path: &str) -> reqwest::Result<reqwest::Response> {
+        self.get_with_key(self.api_key.as_deref(), path).await
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> reqwest::Response {
+        self.post_with_key(self.api_key.as_deref(), path, body)
+            .await
+    }
+
+    /// Send a GET request with the given API key, if any.
+    pub async fn get_with_key(
+        &self,
+        api_key: Option<&str>,
+        path: &str,
+    ) -> reqwest::Result<reqwest::Response> {
+        let mut request = self.client.get(format!("{}{}", self.address, path));
+        if let Some(api_key) = api_key {
+            request = request.bearer_auth(api_key);
+        }
+        request.send().await
+    }
+
+    /// Send a POST request with the given API key, if any.
+    pub async fn post_with_key(
+        &self,
+        api_key: Option<&str>,
+        path: &str,
+        body: Value,
+    ) -> reqwest::Response {
+        let mut request = self
+            .client
+            .post(format!("{}{}", self.address, path))
+            .json(&body);
+        if let Some(api_key) = api_key {
+            request = request.bearer_auth(api_key);
+        }
+        request.send().await.unwrap()
+    }
+}
+
+/// A file in the temporary directory, removed when dropped.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Write the content to a new file with the given extension.
+    pub fn new(extension: &str, content: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("openai_trtllm_{}.{}", Uuid::new_v4(), extension));
+        std::fs::write(&path, content).unwrap();
+        Self(path)
+    }
+
+    /// Write the JSON value to a new `.json` file.
+    pub fn json(value: &Value) -> Self {
+        Self::new("json", &value.to_string())
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Reserve a free local port, released right before it is bound.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A server-sent event.
+#[derive(Debug)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl SseEvent {
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.data).unwrap()
+    }
+}
+
+/// Read all the server-sent events of a streaming response.
+pub async fn events(response: reqwest::Response) -> Vec<SseEvent> {
+    let body = response.text().await.unwrap();
+    body.split("\n\n")
+        .filter_map(|block| {
+            let mut event = None;
+            let mut data = None;
+            for line in block.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    event = Some(value.trim().to_string());
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data = Some(value.trim().to_string());
+                }
+            }
+            data.map(|data| SseEvent { event, data })
+        })
+        .collect()
+}
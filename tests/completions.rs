@@ -0,0 +1,480 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tonic::Code;
+
+use common::mock_triton::Step;
+use common::{events, text, MockTriton, TempFile, TestServer};
+
+mod common;
+
+#[tokio::test]
+async fn test_completions() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", ",", " world"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "max_tokens": 16}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!("text_completion", completion["object"]);
+    assert_eq!("ensemble", completion["model"]);
+    assert_eq!("Hello, world", completion["choices"][0]["text"]);
+    assert_eq!("stop", completion["choices"][0]["finish_reason"]);
+
+    let requests = triton.requests();
+    assert_eq!(1, requests.len());
+    let text_input = requests[0].input("text_input").unwrap();
+    assert_eq!(vec![b"Say hello".to_vec()], text_input.bytes_contents);
+    assert_eq!(
+        vec![16],
+        requests[0].input("max_tokens").unwrap().int_contents
+    );
+}
+
+#[tokio::test]
+async fn test_completions_stream() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", ",", " world"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "stream": true}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let events = events(response).await;
+    assert_eq!("[DONE]", events.last().unwrap().data);
+
+    let chunks: Vec<_> = events[..events.len() - 1]
+        .iter()
+        .map(|event| event.json())
+        .collect();
+    let texts: Vec<_> = chunks
+        .iter()
+        .map(|chunk| chunk["choices"][0]["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(vec!["Hello", ",", " world", ""], texts);
+    assert_eq!("stop", chunks[3]["choices"][0]["finish_reason"]);
+}
+
+#[tokio::test]
+async fn test_completions_finish_reason() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", ",", " world"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    for (max_tokens, expected) in [(3, "length"), (16, "stop")] {
+        let response = server
+            .post(
+                "/v1/completions",
+                json!({
+                    "model": "ensemble",
+                    "prompt": "Say hello",
+                    "max_tokens": max_tokens,
+                    "stream": true,
+                }),
+            )
+            .await;
+        let events = events(response).await;
+        let finish_reasons: Vec<_> = events[..events.len() - 1]
+            .iter()
+            .filter_map(|event| {
+                event.json()["choices"][0]["finish_reason"]
+                    .as_str()
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(vec![expected], finish_reasons);
+    }
+}
+
+#[tokio::test]
+async fn test_stop_sequence() {
+    let triton = MockTriton::default();
+    triton.script(
+        "stop-model",
+        vec![
+            Step::Text("Hello".to_string()),
+            Step::Text(" ##".to_string()),
+            Step::Text("# and".to_string()),
+            Step::Delay(Duration::from_secs(30)),
+            Step::Text(" more".to_string()),
+        ],
+    );
+    triton.declare_inputs(&["stop"]);
+    let server = TestServer::start(&triton, &[]).await;
+
+    // The inference is stopped as soon as the stop sequence is generated, across chunks.
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({
+                "model": "stop-model",
+                "prompt": "Say hello",
+                "stop": ["###"],
+                "stream": true,
+            }),
+        )
+        .await;
+    let events = events(response).await;
+    let chunks: Vec<_> = events[..events.len() - 1]
+        .iter()
+        .map(|event| event.json())
+        .collect();
+    let text: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["text"].as_str())
+        .collect();
+    assert_eq!("Hello ", text);
+    assert_eq!(
+        "stop",
+        chunks.last().unwrap()["choices"][0]["finish_reason"]
+    );
+    assert!(triton.requests().iter().any(|request| request.is_stop()));
+    // Stopping on a stop sequence is not a cancellation.
+    let metrics = server.get("/metrics").await.unwrap().text().await.unwrap();
+    assert!(!metrics.contains("openai_trtllm_cancelled_requests_total{model=\"stop-model\"}"));
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "stop-model", "prompt": "Say hello", "stop": ["###"]}),
+        )
+        .await;
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!("Hello ", completion["choices"][0]["text"]);
+}
+
+#[tokio::test]
+async fn test_completions_n() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "n": 2}),
+        )
+        .await;
+    let completion: Value = response.json().await.unwrap();
+    let choices = completion["choices"].as_array().unwrap();
+    assert_eq!(2, choices.len());
+    for (index, choice) in choices.iter().enumerate() {
+        assert_eq!(index, choice["index"]);
+        assert_eq!("Hello", choice["text"]);
+    }
+
+    let seeds: Vec<_> = triton
+        .requests()
+        .iter()
+        .map(|request| {
+            request
+                .input("random_seed")
+                .unwrap()
+                .uint64_contents
+                .clone()
+        })
+        .collect();
+    assert_eq!(2, seeds.len());
+    assert_ne!(seeds[0], seeds[1]);
+}
+
+#[tokio::test]
+async fn test_completions_logprobs() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello", ",", " world"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "logprobs": 1}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    let logprobs = &completion["choices"][0]["logprobs"];
+    assert_eq!(json!(["Hello", ",", " world"]), logprobs["tokens"]);
+    assert_eq!(json!([-0.25, -0.25, -0.25]), logprobs["token_logprobs"]);
+    assert_eq!(
+        json!([{"Hello": -0.25}, {",": -0.25}, {" world": -0.25}]),
+        logprobs["top_logprobs"]
+    );
+    // Offsets start after the prompt.
+    assert_eq!(json!([9, 14, 15]), logprobs["text_offset"]);
+
+    let requests = triton.requests();
+    assert!(requests[0].returns_log_probs());
+    assert!(requests[0]
+        .outputs
+        .iter()
+        .any(|output| output.name == "output_log_probs"));
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "logprobs": 2}),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+
+#[tokio::test]
+async fn test_invalid_request() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello"]));
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "temperature": 3.0}),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("invalid_request_error", error["error"]["type"]);
+    assert!(triton.requests().is_empty());
+}
+
+#[tokio::test]
+async fn test_logit_bias() {
+    let triton = MockTriton::default();
+    triton
+        .script("bias-model", text(&["Hello"]))
+        .script("ensemble", text(&["Hello"]))
+        .declare_inputs(&["embedding_bias_words", "embedding_bias_weights"]);
+    let tokenizer = TempFile::json(&json!({
+        "version": "1.0",
+        "model": {
+            "type": "WordLevel",
+            "vocab": {"[UNK]": 0, "hello": 1, "world": 2},
+            "unk_token": "[UNK]",
+        },
+        "pre_tokenizer": {"type": "Whitespace"},
+    }));
+    let tokenizer = format!("bias-model={}", tokenizer.path());
+    let server = TestServer::start(&triton, &["--tokenizer", &tokenizer]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "bias-model", "prompt": "Say hello", "logit_bias": {"2": -100}}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let request = &triton.requests()[0];
+    assert_eq!(
+        vec![b"world".to_vec()],
+        request
+            .input("embedding_bias_words")
+            .unwrap()
+            .bytes_contents
+    );
+    assert_eq!(
+        vec![-100.0],
+        request
+            .input("embedding_bias_weights")
+            .unwrap()
+            .fp32_contents
+    );
+
+    // Token ids missing from the vocabulary are rejected.
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "bias-model", "prompt": "Say hello", "logit_bias": {"3": 1}}),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+    // Without tokenizer, the bias is ignored.
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "logit_bias": {"2": -100}}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let requests = triton.requests();
+    assert_eq!(2, requests.len());
+    assert!(requests[1].input("embedding_bias_words").is_none());
+}
+
+#[tokio::test]
+async fn test_unknown_model() {
+    let triton = MockTriton::default();
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "missing", "prompt": "Say hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("model_not_found", error["error"]["code"]);
+}
+
+#[tokio::test]
+async fn test_error_message() {
+    let triton = MockTriton::default();
+    triton.script(
+        "ensemble",
+        vec![Step::ErrorMessage("executor failed".to_string())],
+    );
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("server_error", error["error"]["type"]);
+    assert_eq!("executor failed", error["error"]["message"]);
+}
+
+#[tokio::test]
+async fn test_stream_error() {
+    let triton = MockTriton::default();
+    triton.script(
+        "ensemble",
+        vec![
+            Step::Text("Hello".to_string()),
+            // Let the response be sent, tonic drops buffered responses when the call fails.
+            Step::Delay(Duration::from_millis(100)),
+            Step::Status(Code::Unavailable, "triton is shutting down".to_string()),
+        ],
+    );
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "stream": true}),
+        )
+        .await;
+    // The status is sent before the error occurs.
+    assert_eq!(StatusCode::OK, response.status());
+    let events = events(response).await;
+    assert_eq!(2, events.len());
+    assert_eq!("Hello", events[0].json()["choices"][0]["text"]);
+    assert_eq!(Some("error"), events[1].event.as_deref());
+    let message = events[1].json()["error"]["message"].to_string();
+    assert!(message.contains("triton is shutting down"));
+
+    // The failed inference is over, so it is not cancelled.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!triton.requests().iter().any(|request| request.is_stop()));
+}
+
+#[tokio::test]
+async fn test_stream_start_error() {
+    let triton = MockTriton::default();
+    triton.script(
+        "ensemble",
+        vec![Step::Status(
+            Code::Unavailable,
+            "triton is shutting down".to_string(),
+        )],
+    );
+    let server = TestServer::start(&triton, &[]).await;
+
+    // Inferences failing before their first response fail the request with their status code.
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "stream": true}),
+        )
+        .await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "missing",
+                "messages": [{"role": "user", "content": "Hi!"}],
+                "stream": true,
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+}
+
+#[tokio::test]
+async fn test_cancellation() {
+    let triton = MockTriton::default();
+    triton.script(
+        "ensemble",
+        vec![
+            Step::Text("Hello".to_string()),
+            Step::Delay(Duration::from_secs(30)),
+            Step::Text(" world".to_string()),
+        ],
+    );
+    triton.declare_inputs(&["stop"]);
+    let server = TestServer::start(&triton, &[]).await;
+
+    let mut response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "stream": true}),
+        )
+        .await;
+    assert!(response.chunk().await.unwrap().is_some());
+    drop(response);
+
+    for _ in 0..100 {
+        let requests = triton.requests();
+        if let Some(stop_request) = requests.iter().find(|request| request.is_stop()) {
+            assert_eq!(requests[0].id, stop_request.id);
+            // Only the id of the inference is sent.
+            assert!(stop_request.input("text_input").is_none());
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("inference was not stopped");
+}
+
+#[tokio::test]
+async fn test_cancellation_without_stop_input() {
+    let triton = MockTriton::default();
+    triton.script(
+        "ensemble",
+        vec![
+            Step::Text("Hello".to_string()),
+            Step::Delay(Duration::from_secs(30)),
+            Step::Text(" world".to_string()),
+        ],
+    );
+    let server = TestServer::start(&triton, &[]).await;
+
+    let mut response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello", "stream": true}),
+        )
+        .await;
+    assert!(response.chunk().await.unwrap().is_some());
+    drop(response);
+
+    // The model does not declare the stop input, dropping the gRPC call is enough.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!triton.requests().iter().any(|request| request.is_stop()));
+}
@@ -0,0 +1,114 @@
+use openai_trtllm::config::{Config, LoadBalancing};
+
+use common::TempFile;
+
+mod common;
+
+#[test]
+fn test_toml_config() {
+    let file = TempFile::new(
+        "toml",
+        r#"
+            [server]
+            port = 8000
+
+            [triton]
+            endpoints = ["http://triton-a:8001", "http://triton-b:8001"]
+            load_balancing = "least-in-flight"
+
+            [models]
+            aliases = ["gpt-3.5-turbo=ensemble"]
+
+            [auth]
+            api_key = "secret"
+        "#,
+    );
+    let config = Config::load_from(["openai_trtllm", "--config", file.path()]).unwrap();
+    assert_eq!(8000, config.server.port);
+    assert_eq!("0.0.0.0", config.server.host);
+    assert_eq!(
+        vec!["http://triton-a:8001", "http://triton-b:8001"],
+        config.triton.endpoints
+    );
+    assert_eq!(LoadBalancing::LeastInFlight, config.triton.load_balancing);
+    assert_eq!(5, config.triton.health_check_interval);
+    assert_eq!(Some("secret"), config.auth.api_key.as_deref());
+    assert_eq!("ensemble", config.model_aliases().unwrap()["gpt-3.5-turbo"]);
+
+    // The command line overrides the file, but not with the defaults of its options.
+    let config = Config::load_from([
+        "openai_trtllm",
+        "--config",
+        file.path(),
+        "--port",
+        "9000",
+        "--max-failures",
+        "1",
+    ])
+    .unwrap();
+    assert_eq!(9000, config.server.port);
+    assert_eq!(1, config.triton.max_failures);
+    assert_eq!(2, config.triton.endpoints.len());
+}
+
+#[test]
+fn test_yaml_config() {
+    let file = TempFile::new(
+        "yaml",
+        r#"
+            triton:
+              endpoints: http://triton:8001
+            models:
+              routes:
+                - id: llama-3-8b
+                  triton_model: ensemble
+                  defaults:
+                    max_tokens: 64
+            telemetry:
+              otlp_endpoint: http://collector:4317
+        "#,
+    );
+    let config = Config::load_from(["openai_trtllm", "-c", file.path()]).unwrap();
+    assert_eq!(vec!["http://triton:8001"], config.triton.endpoints);
+    assert_eq!(1, config.models.routes.len());
+    assert_eq!("llama-3-8b", config.models.routes[0].id);
+    assert_eq!(
+        Some("ensemble"),
+        config.models.routes[0].triton_model.as_deref()
+    );
+    assert_eq!(
+        Some("http://collector:4317"),
+        config.telemetry.otlp_endpoint.as_deref()
+    );
+}
+
+#[test]
+fn test_comma_separated_endpoints() {
+    // As given by OPENAI_TRTLLM_TRITON_ENDPOINT, which is a single string.
+    let file = TempFile::new(
+        "toml",
+        "[triton]\nendpoints = \"http://triton-a:8001, http://triton-b:8001,\"\n",
+    );
+    let config = Config::load_from(["openai_trtllm", "--config", file.path()]).unwrap();
+    assert_eq!(
+        vec!["http://triton-a:8001", "http://triton-b:8001"],
+        config.triton.endpoints
+    );
+}
+
+#[test]
+fn test_invalid_config() {
+    let file = TempFile::new("toml", "[server]\nport = \"eighty\"\n");
+    let err = Config::load_from(["openai_trtllm", "--config", file.path()]).unwrap_err();
+    assert!(err.to_string().contains("server.port"), "{}", err);
+
+    let file = TempFile::new("toml", "[triton]\nendpiont = \"http://triton\"\n");
+    let err = Config::load_from(["openai_trtllm", "--config", file.path()]).unwrap_err();
+    assert!(err.to_string().contains("endpiont"), "{}", err);
+
+    let file = TempFile::new("json", "{}");
+    let err = Config::load_from(["openai_trtllm", "--config", file.path()]).unwrap_err();
+    assert!(err.to_string().contains("unsupported configuration file"));
+    let err = Config::load_from(["openai_trtllm", "--config", "missing.toml"]).unwrap_err();
+    assert!(err.to_string().contains("does not exist"));
+}
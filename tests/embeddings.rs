@@ -0,0 +1,151 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use common::mock_triton::Step;
+use common::{text, MockTriton, TestServer};
+
+mod common;
+
+#[tokio::test]
+async fn test_embeddings() {
+    let triton = MockTriton::default();
+    triton.script("bge", vec![Step::Embedding(vec![3.0, 4.0, 12.0])]);
+    let server = TestServer::start(&triton, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/embeddings",
+            json!({"model": "bge", "input": ["Hello", "world"]}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let embeddings: Value = response.json().await.unwrap();
+    assert_eq!("list", embeddings["object"]);
+    assert_eq!("bge", embeddings["model"]);
+    assert_eq!(2, embeddings["data"].as_array().unwrap().len());
+    assert_eq!("embedding", embeddings["data"][1]["object"]);
+    assert_eq!(1, embeddings["data"][1]["index"]);
+    assert_eq!(json!([3.0, 4.0, 12.0]), embeddings["data"][1]["embedding"]);
+    assert_eq!(0, embeddings["usage"]["prompt_tokens"]);
+
+    // Each input is embedded by its own inference, run concurrently.
+    let requests = triton.requests();
+    let mut inputs: Vec<_> = requests
+        .iter()
+        .map(|request| request.input("text_input").unwrap().bytes_contents.clone())
+        .collect();
+    inputs.sort();
+    assert_eq!(
+        vec![vec![b"Hello".to_vec()], vec![b"world".to_vec()]],
+        inputs
+    );
+    assert_eq!("embeddings", requests[0].outputs[0].name);
+
+    // The shortened embedding is normalized again.
+    let response = server
+        .post(
+            "/v1/embeddings",
+            json!({"model": "bge", "input": "Hello", "dimensions": 2, "encoding_format": "base64"}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let embeddings: Value = response.json().await.unwrap();
+    let encoded = embeddings["data"][0]["embedding"].as_str().unwrap();
+    let bytes = BASE64.decode(encoded).unwrap();
+    let embedding: Vec<f32> = bytes
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+    assert_eq!(vec![0.6, 0.8], embedding);
+
+    let response = server
+        .post(
+            "/v1/embeddings",
+            json!({"model": "bge", "input": "Hello", "dimensions": 4}),
+        )
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("dimensions", error["error"]["param"]);
+
+    let response = server
+        .post("/v1/embeddings", json!({"model": "bge", "input": []}))
+        .await;
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+    // Generation models have no embedding output.
+    triton.script("ensemble", text(&["Hello"]));
+    let response = server
+        .post(
+            "/v1/embeddings",
+            json!({"model": "ensemble", "input": "Hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+}
+
+#[tokio::test]
+async fn test_embedding_tensors() {
+    let triton = MockTriton::default();
+    triton.script("e5", vec![Step::Embedding(vec![1.0, 0.0])]);
+    let routes = json!([{
+        "id": "e5-large",
+        "triton_model": "e5",
+        "embedding_input": "TEXT",
+        "embedding_output": "sentence_embedding",
+    }]);
+    let server = TestServer::with_routes(&triton, routes, &[]).await;
+
+    let response = server
+        .post(
+            "/v1/embeddings",
+            json!({"model": "e5-large", "input": "query: hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let embeddings: Value = response.json().await.unwrap();
+    assert_eq!("e5-large", embeddings["model"]);
+    assert_eq!(json!([1.0, 0.0]), embeddings["data"][0]["embedding"]);
+
+    let requests = triton.requests();
+    let text_input = requests[0].input("TEXT").unwrap();
+    assert_eq!(vec![b"query: hello".to_vec()], text_input.bytes_contents);
+    assert_eq!("sentence_embedding", requests[0].outputs[0].name);
+}
+
+#[tokio::test]
+async fn test_embedding_batches() {
+    let triton = MockTriton::default();
+    triton
+        .script("bge", vec![Step::Embedding(vec![3.0, 4.0])])
+        .script("empty", vec![]);
+    let server = TestServer::start(&triton, &[]).await;
+
+    // Large requests are embedded in batches.
+    let input: Vec<_> = (0..80).map(|index| format!("input {}", index)).collect();
+    let response = server
+        .post("/v1/embeddings", json!({"model": "bge", "input": input}))
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let embeddings: Value = response.json().await.unwrap();
+    let data = embeddings["data"].as_array().unwrap();
+    assert_eq!(80, data.len());
+    assert!(data
+        .iter()
+        .enumerate()
+        .all(|(index, embedding)| embedding["index"] == index));
+    assert_eq!(80, triton.requests().len());
+
+    // An inference completed without response is not an empty embedding.
+    let response = server
+        .post(
+            "/v1/embeddings",
+            json!({"model": "empty", "input": "Hello", "dimensions": 2}),
+        )
+        .await;
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("server_error", error["error"]["type"]);
+}
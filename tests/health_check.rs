@@ -0,0 +1,164 @@
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use common::{free_port, text, MockTriton, TempFile, TestServer};
+
+mod common;
+
+#[tokio::test]
+async fn test_triton_not_up_yet() {
+    let address = format!("127.0.0.1:{}", free_port());
+    let server = TestServer::start_at(&format!("http://{}", address), &[]).await;
+
+    // The server starts without triton, but is not ready.
+    let response = server.get("/health_check").await.unwrap();
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+    let health: Value = response.json().await.unwrap();
+    assert_eq!(json!({"live": true, "ready": false}), health);
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+    // It connects once triton is up.
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello"]));
+    triton.serve_at(&address).await;
+    server.wait_ready().await;
+    let health: Value = server
+        .get("/health_check")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(json!({"live": true, "ready": true}), health);
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[tokio::test]
+async fn test_probes() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello"]));
+    let endpoint = triton.serve().await;
+    let server = TestServer::start_at(
+        &endpoint,
+        &[
+            "--model-alias",
+            "gpt=ensemble",
+            "--model-alias",
+            "old=unloaded",
+        ],
+    )
+    .await;
+
+    let response = server.get("/health/live").await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let liveness: Value = response.json().await.unwrap();
+    assert_eq!(json!({"live": true}), liveness);
+
+    // The routed models are checked by the health checks, and one of them is not loaded.
+    let mut readiness = Value::Null;
+    for _ in 0..100 {
+        let response = server.get("/health/ready").await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        readiness = response.json().await.unwrap();
+        if !readiness["triton"][0]["models"]
+            .as_object()
+            .unwrap()
+            .is_empty()
+        {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(
+        json!({
+            "ready": false,
+            "triton": [{
+                "endpoint": endpoint,
+                "ready": true,
+                "models": {"ensemble": true, "unloaded": false},
+            }],
+        }),
+        readiness
+    );
+
+    // Without the unloaded model, the server is ready.
+    let server = TestServer::start_at(&endpoint, &["--model-alias", "gpt=ensemble"]).await;
+    server.wait_ready().await;
+    let response = server.get("/health/ready").await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let readiness: Value = response.json().await.unwrap();
+    assert_eq!(json!({"ensemble": true}), readiness["triton"][0]["models"]);
+}
+
+async fn wait_readiness(
+    server: &TestServer,
+    until: impl Fn(&Value) -> bool,
+) -> (StatusCode, Value) {
+    for _ in 0..100 {
+        let response = server.get("/health/ready").await.unwrap();
+        let status = response.status();
+        let readiness: Value = response.json().await.unwrap();
+        if until(&readiness) {
+            return (status, readiness);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("readiness did not change");
+}
+
+#[tokio::test]
+async fn test_readiness_of_watched_models() {
+    let triton = MockTriton::default();
+    triton
+        .script("ensemble", text(&["Hello"]))
+        .script("adhoc", text(&["Hello"]));
+    let server = TestServer::start(
+        &triton,
+        &[
+            "--model-alias",
+            "gpt=ensemble",
+            "--health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    // Models requested without route are checked, but do not gate the readiness.
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "adhoc", "prompt": "Say hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    triton.unload("adhoc");
+    let (status, readiness) = wait_readiness(&server, |readiness| {
+        readiness["triton"][0]["models"]["adhoc"] == json!(false)
+    })
+    .await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(true, readiness["triton"][0]["models"]["ensemble"]);
+
+    // The version of the route is checked.
+    let routes = json!([{"id": "gpt", "triton_model": "ensemble", "model_version": "2"}]);
+    let routes_file = TempFile::json(&routes);
+    let endpoint = triton.serve().await;
+    let server = TestServer::start_at(&endpoint, &["--model-routes", routes_file.path()]).await;
+    let (status, _) = wait_readiness(&server, |readiness| {
+        readiness["triton"][0]["models"]["ensemble"] == json!(false)
+    })
+    .await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+}
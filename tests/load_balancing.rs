@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde_json::json;
+use tonic::Code;
+
+use common::mock_triton::Step;
+use common::{text, MockTriton, TestServer};
+
+mod common;
+
+async fn complete(server: &TestServer) -> StatusCode {
+    server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello"}),
+        )
+        .await
+        .status()
+}
+
+/// Wait until the health gauge of the endpoint has the given value.
+async fn wait_for_health(server: &TestServer, endpoint: &str, healthy: bool) {
+    let gauge = format!(
+        "openai_trtllm_triton_endpoint_healthy{{endpoint=\"{}\"}} {}",
+        endpoint, healthy as u8
+    );
+    for _ in 0..100 {
+        let metrics = server.get("/metrics").await.unwrap().text().await.unwrap();
+        if metrics.lines().any(|line| line == gauge) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} was never reported", gauge);
+}
+
+#[tokio::test]
+async fn test_failover() {
+    let triton_a = MockTriton::default();
+    triton_a.script(
+        "ensemble",
+        vec![Step::Status(Code::Unavailable, "shutting down".to_string())],
+    );
+    let triton_b = MockTriton::default();
+    triton_b.script("ensemble", text(&["Hello"]));
+    let endpoint_b = triton_b.serve().await;
+    let server = TestServer::start(
+        &triton_a,
+        &[
+            "--triton-endpoint",
+            &endpoint_b,
+            "--max-failures",
+            "1",
+            "--health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    for _ in 0..4 {
+        assert_eq!(StatusCode::OK, complete(&server).await);
+    }
+    // The first inference is retried on the other endpoint, and the failing one is ejected.
+    assert_eq!(1, triton_a.requests().len());
+    assert_eq!(4, triton_b.requests().len());
+}
+
+#[tokio::test]
+async fn test_health_check() {
+    let triton_a = MockTriton::default();
+    triton_a
+        .script("ensemble", text(&["Hello"]))
+        .set_ready(false);
+    let endpoint_a = triton_a.serve().await;
+    let triton_b = MockTriton::default();
+    triton_b.script("ensemble", text(&["Hello"]));
+    let server = TestServer::start(
+        &triton_b,
+        &[
+            "--triton-endpoint",
+            &endpoint_a,
+            "--health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    wait_for_health(&server, &endpoint_a, false).await;
+    for _ in 0..4 {
+        assert_eq!(StatusCode::OK, complete(&server).await);
+    }
+    assert!(triton_a.requests().is_empty());
+    assert_eq!(4, triton_b.requests().len());
+
+    // The endpoint gets inferences again once it is ready.
+    triton_a.set_ready(true);
+    wait_for_health(&server, &endpoint_a, true).await;
+    for _ in 0..4 {
+        assert_eq!(StatusCode::OK, complete(&server).await);
+    }
+    assert_eq!(2, triton_a.requests().len());
+}
@@ -0,0 +1,146 @@
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use common::{text, MockTriton, TestServer};
+
+mod common;
+
+/// Start a server with the routing table, whose routes are sent to `routed` while other models
+/// are sent to `triton`.
+async fn start(triton: &MockTriton, routed: &MockTriton) -> TestServer {
+    let endpoint = routed.serve().await;
+    let routes = json!([{
+        "id": "llama-3-8b",
+        "aliases": ["gpt-3.5-turbo"],
+        "triton_model": "ensemble",
+        "model_version": "1",
+        "endpoints": [endpoint],
+        "history_template_file": "templates/history_template_llama3.liquid",
+        "defaults": {"max_tokens": 64, "temperature": 0.5},
+    }]);
+    TestServer::with_routes(triton, routes, &[]).await
+}
+
+#[tokio::test]
+async fn test_routed_completions() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Default"]));
+    let routed = MockTriton::default();
+    routed.script("ensemble", text(&["Routed"]));
+    let server = start(&triton, &routed).await;
+
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "gpt-3.5-turbo", "prompt": "Say hello"}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!("gpt-3.5-turbo", completion["model"]);
+    assert_eq!("Routed", completion["choices"][0]["text"]);
+
+    // The parameters set by the client override the defaults of the route.
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "llama-3-8b", "prompt": "Say hello", "max_tokens": 16}),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+
+    // Models without route are sent as is to the default endpoint.
+    let response = server
+        .post(
+            "/v1/completions",
+            json!({"model": "ensemble", "prompt": "Say hello"}),
+        )
+        .await;
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!("Default", completion["choices"][0]["text"]);
+
+    let requests = routed.requests();
+    assert_eq!(2, requests.len());
+    assert_eq!("ensemble", requests[0].model_name);
+    assert_eq!("1", requests[0].model_version);
+    assert_eq!(
+        vec![64],
+        requests[0].input("max_tokens").unwrap().int_contents
+    );
+    assert_eq!(
+        vec![0.5],
+        requests[0].input("temperature").unwrap().fp32_contents
+    );
+    assert_eq!(
+        vec![16],
+        requests[1].input("max_tokens").unwrap().int_contents
+    );
+    let requests = triton.requests();
+    assert_eq!(1, requests.len());
+    assert_eq!("", requests[0].model_version);
+}
+
+#[tokio::test]
+async fn test_routed_chat_completions() {
+    let triton = MockTriton::default();
+    let routed = MockTriton::default();
+    routed.script("ensemble", text(&["Hello"]));
+    let server = start(&triton, &routed).await;
+
+    let response = server
+        .post(
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-3.5-turbo",
+                "messages": [{"role": "user", "content": "Say hello"}],
+            }),
+        )
+        .await;
+    assert_eq!(StatusCode::OK, response.status());
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!("Hello", completion["choices"][0]["message"]["content"]);
+
+    // The history template of the route is used.
+    let requests = routed.requests();
+    let text_input = &requests[0].input("text_input").unwrap().bytes_contents[0];
+    let prompt = String::from_utf8(text_input.clone()).unwrap();
+    assert!(prompt.contains("<|start_header_id|>User<|end_header_id|>\nSay hello"));
+
+    let response = server.get("/v1/models/gpt-3.5-turbo").await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let model: Value = response.json().await.unwrap();
+    assert_eq!("gpt-3.5-turbo", model["id"]);
+    assert_eq!("ensemble", model["root"]);
+}
+
+#[tokio::test]
+async fn test_routed_model_inputs() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["{\"city\": \"Paris\"}"]));
+    let routed = MockTriton::default();
+    routed
+        .script("ensemble", text(&["{\"city\": \"Paris\"}"]))
+        .declare_inputs(&["guided_decoding_guide_type", "guided_decoding_guide"]);
+    let server = start(&triton, &routed).await;
+
+    // The inputs are cached per pool, the same model is not served the same way by both.
+    for model in ["ensemble", "gpt-3.5-turbo"] {
+        let response = server
+            .post(
+                "/v1/chat/completions",
+                json!({
+                    "model": model,
+                    "messages": [{"role": "user", "content": "Weather in Paris?"}],
+                    "response_format": {"type": "json_object"},
+                }),
+            )
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+    assert!(triton.requests()[0]
+        .input("guided_decoding_guide_type")
+        .is_none());
+    assert!(routed.requests()[0]
+        .input("guided_decoding_guide_type")
+        .is_some());
+}
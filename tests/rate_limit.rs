@@ -0,0 +1,81 @@
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use common::{text, MockTriton, TestServer};
+
+mod common;
+
+async fn complete(server: &TestServer, model: &str) -> reqwest::Response {
+    server
+        .post(
+            "/v1/completions",
+            json!({"model": model, "prompt": "Say hello"}),
+        )
+        .await
+}
+
+#[tokio::test]
+async fn test_requests_per_minute() {
+    let triton = MockTriton::default();
+    triton.script("ensemble", text(&["Hello"]));
+    let server = TestServer::start(&triton, &["--requests-per-minute", "2"]).await;
+
+    for remaining in ["1", "0"] {
+        let response = complete(&server, "ensemble").await;
+        assert_eq!(StatusCode::OK, response.status());
+        let headers = response.headers();
+        assert_eq!("2", headers["x-ratelimit-limit-requests"]);
+        assert_eq!(remaining, headers["x-ratelimit-remaining-requests"]);
+    }
+
+    let response = complete(&server, "ensemble").await;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+    let headers = response.headers();
+    assert_eq!("30", headers["retry-after"]);
+    assert!(headers.contains_key("retry-after-ms"));
+    assert_eq!("0", headers["x-ratelimit-remaining-requests"]);
+    assert_eq!("1m0s", headers["x-ratelimit-reset-requests"]);
+    let error: Value = response.json().await.unwrap();
+    assert_eq!("rate_limit_error", error["error"]["type"]);
+    assert_eq!("rate_limit_exceeded", error["error"]["code"]);
+    assert_eq!(2, triton.requests().len());
+
+    // Listing the models, the probes and the metrics are not limited.
+    for path in ["/v1/models", "/health/ready", "/metrics"] {
+        let response = server.get(path).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status(), "{}", path);
+        assert!(!response
+            .headers()
+            .contains_key("x-ratelimit-remaining-requests"));
+    }
+}
+
+#[tokio::test]
+async fn test_model_tokens_per_minute() {
+    let triton = MockTriton::default();
+    triton
+        .script("ensemble", text(&["Hel", "lo", " there"]))
+        .script("other", text(&["Hello"]));
+    let routes = json!([{"id": "llama", "triton_model": "ensemble", "tokens_per_minute": 2}]);
+    let server = TestServer::with_routes(&triton, routes, &[]).await;
+
+    let response = complete(&server, "llama").await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("2", response.headers()["x-ratelimit-limit-tokens"]);
+
+    // The generated tokens exceed the limit, so the next request waits for the bucket to refill.
+    let response = complete(&server, "llama").await;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+    let error: Value = response.json().await.unwrap();
+    let message = error["error"]["message"].as_str().unwrap();
+    assert!(
+        message.contains("tokens per minute of the model llama"),
+        "{}",
+        message
+    );
+
+    // Other models are not limited.
+    let response = complete(&server, "other").await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert!(!response.headers().contains_key("x-ratelimit-limit-tokens"));
+}